@@ -1,18 +1,19 @@
 #![allow(unused_imports, unused_variables, dead_code)]
 use clap::Parser;
-mod util;
 use iroh::net::ticket::NodeTicket;
-use util::*;
+use workshop_common::*;
 
 #[derive(Debug, Parser)]
 struct Args {
     tickets: Vec<NodeTicket>,
+    #[clap(flatten)]
+    key: KeyArgs,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
-    let secret_key = get_or_create_secret()?;
+    let secret_key = args.key.persistent(env!("CARGO_PKG_NAME"))?.load()?;
     todo!("draw the rest of the owl");
 }
@@ -1,22 +1,30 @@
 use clap::Parser;
-use futures::{SinkExt, StreamExt};
+use futures::SinkExt;
 use iroh::{
     base::node_addr::AddrInfoOptions,
     gossip::net::{Command, Event, GossipEvent},
     net::{
+        discovery::local_swarm_discovery::LocalSwarmDiscovery,
         key::{PublicKey, SecretKey, Signature},
+        relay::RelayMode,
         ticket::NodeTicket,
     },
+    node::DiscoveryConfig,
 };
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use tokio::{io::AsyncBufReadExt, select};
-use util::wait_for_relay;
-mod util;
+use workshop_common::{wait_for_relay, Discovery, DiscoveryArgs, KeyArgs, RoomArgs, Rooms};
 
 #[derive(Debug, Parser)]
 struct Args {
     tickets: Vec<NodeTicket>,
+    #[clap(flatten)]
+    key: KeyArgs,
+    #[clap(flatten)]
+    discovery: DiscoveryArgs,
+    #[clap(flatten)]
+    rooms: RoomArgs,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -58,7 +66,7 @@ impl SignedMessage {
     }
 }
 
-async fn handle_event(event: Event) -> anyhow::Result<()> {
+async fn handle_event(room: &str, event: Event) -> anyhow::Result<()> {
     if let Event::Gossip(GossipEvent::Received(msg)) = event {
         let Ok((from, msg)) = SignedMessage::verify_and_decode(&msg.content) else {
             tracing::warn!("Failed to verify message: {:?}", msg.content);
@@ -66,11 +74,11 @@ async fn handle_event(event: Event) -> anyhow::Result<()> {
         };
         match msg {
             Message::Message { text } => {
-                println!("Received message from node {}: {}", from, text);
+                println!("[{}] Received message from node {}: {}", room, from, text);
             }
         }
     } else {
-        tracing::info!("Got other event: {:?}", event);
+        tracing::info!("Got other event in room {}: {:?}", room, event);
     }
     Ok(())
 }
@@ -89,14 +97,36 @@ async fn main() -> anyhow::Result<()> {
     // parse command line arguments
     let args = Args::parse();
     // get or create the secret key / node identity
-    let secret_key = util::get_or_create_secret()?;
+    let secret_key = args.key.persistent(env!("CARGO_PKG_NAME"))?.load()?;
     // create a new Iroh node, giving it the secret key
-    let iroh = iroh::node::Node::memory()
-        .secret_key(secret_key.clone())
-        .spawn()
-        .await?;
+    let mut builder = iroh::node::Node::memory().secret_key(secret_key.clone());
+    if args.discovery.is_local() {
+        // only use the local network, so neither relays nor internet access are needed
+        let discovery = LocalSwarmDiscovery::new(secret_key.public())?;
+        builder = builder
+            .node_discovery(DiscoveryConfig::Custom(Box::new(discovery)))
+            .relay_mode(RelayMode::Disabled);
+    } else {
+        // use our own relays and discovery servers, if configured
+        let network = args.discovery.network()?;
+        if let Some(relay_mode) = network.relay_mode()? {
+            builder = builder.relay_mode(relay_mode);
+        }
+        if let Some(resolver) = network.dns_resolver() {
+            builder = builder.dns_resolver(resolver);
+        }
+        if network.has_custom_dns() {
+            let discovery = Discovery::Dns { publish: true };
+            if let Some(discovery) = discovery.build(&secret_key, &network)? {
+                builder = builder.node_discovery(DiscoveryConfig::Custom(discovery));
+            }
+        }
+    }
+    let iroh = builder.spawn().await?;
     // wait for the node to figure out its own home relay
-    wait_for_relay(iroh.endpoint()).await?;
+    if !args.discovery.is_local() {
+        wait_for_relay(iroh.endpoint()).await?;
+    }
     // print node addr and ticket, both long and short
     let mut my_addr = iroh.endpoint().node_addr().await?;
     let ticket = NodeTicket::new(my_addr.clone())?;
@@ -113,30 +143,40 @@ async fn main() -> anyhow::Result<()> {
         iroh.endpoint().add_node_addr(addr.clone()).ok();
         bootstrap.push(addr.node_id);
     }
-    // hardcoded topic
-    let topic = [0u8; 32];
-    // subscribe to the topic, giving the bootstrap nodes
+    // join the rooms, giving the bootstrap nodes
     // if the tickets contained additional info, this is available in the address book of the endpoint
-    let (mut sink, mut stream) = iroh.gossip().subscribe(topic, bootstrap).await?;
+    let mut rooms = Rooms::new(args.rooms.room_salt.clone());
+    for name in args.rooms.names() {
+        rooms
+            .join(name, |topic| {
+                iroh.gossip().subscribe(topic, bootstrap.clone())
+            })
+            .await?;
+    }
     let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
     loop {
         select! {
-            message = stream.next() => {
+            (room, event) = rooms.next() => {
                 // got a message from the gossip network
-                if let Some(Ok(event)) = message {
-                    if let Err(cause) = handle_event(event).await {
-                        tracing::warn!("error handling message: {}", cause);
+                match event {
+                    Ok(event) => {
+                        if let Err(cause) = handle_event(&room, event).await {
+                            tracing::warn!("error handling message: {}", cause);
+                        }
                     }
-                } else {
-                    break;
+                    Err(cause) => tracing::warn!("error in room {}: {}", room, cause),
                 }
             }
             line = stdin.next_line() => {
                 if let Ok(Some(line)) = line {
-                    // got a line from stdin
+                    // got a line from stdin, either a room command or something to send
+                    let join = |topic| iroh.gossip().subscribe(topic, bootstrap.clone());
+                    if rooms.handle_line(&line, join).await {
+                        continue;
+                    }
                     match parse_as_command(line, &secret_key).await {
                         Ok(cmd) => {
-                            if let Some(cmd) = cmd {
+                            if let (Some(cmd), Some(sink)) = (cmd, rooms.current()) {
                                 sink.send(cmd).await?;
                             }
                         }
@@ -144,6 +184,8 @@ async fn main() -> anyhow::Result<()> {
                             tracing::warn!("error parsing command: {}", cause);
                         }
                     }
+                } else {
+                    break;
                 }
             }
         }
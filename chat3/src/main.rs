@@ -1,95 +1,629 @@
-use std::str::FromStr;
+use std::{str::FromStr, sync::Arc};
 
 use clap::Parser;
-use futures::{SinkExt, StreamExt};
+use futures::{future::BoxFuture, Sink, SinkExt};
 use iroh::{
-    base::node_addr::AddrInfoOptions,
-    gossip::net::{Command, Event, GossipEvent},
+    gossip::{
+        net::{Command, Event, GossipEvent},
+        proto::TopicId,
+    },
     net::{
-        key::{PublicKey, SecretKey, Signature},
-        ticket::NodeTicket,
+        discovery::local_swarm_discovery::LocalSwarmDiscovery,
+        endpoint::Connecting,
+        key::{PublicKey, SecretKey},
+        relay::RelayMode,
     },
+    node::{DiscoveryConfig, ProtocolHandler},
+};
+use tokio::{io::AsyncBufReadExt, select, sync::mpsc};
+use workshop_common::{
+    chat::{self, Author, Message, ReplayGuard, SignedMessage, VerifiedMessage},
+    direct, dm,
+    dm::{serve_dm, spawn_send_dm, DmEvent, DM_ALPN},
+    edits::{self, EditCommand, Edits},
+    history, names,
+    presence::{self, Change},
+    receipts::Unread,
+    room_key::{RoomKeys, Sent},
+    sync::{serve_sync, spawn_fetch, spawn_sync, SyncAccess, Synced, SYNC_ALPN},
+    threads, wait_for_relay, ChatTicket, Discovery, DiscoveryArgs, History, HistoryArgs, KeyArgs,
+    Names, Profile, ProfileArgs, ReceiptArgs, RoomArgs, Rooms,
 };
-use rand::Rng;
-use serde::{Deserialize, Serialize};
-use tokio::{io::AsyncBufReadExt, select};
-use util::wait_for_relay;
-mod util;
 
 #[derive(Debug, Parser)]
 struct Args {
-    tickets: Vec<NodeTicket>,
+    tickets: Vec<ChatTicket>,
+    #[clap(flatten)]
+    key: KeyArgs,
+    #[clap(flatten)]
+    discovery: DiscoveryArgs,
+    #[clap(flatten)]
+    rooms: RoomArgs,
+    #[clap(flatten)]
+    profile: ProfileArgs,
+    #[clap(flatten)]
+    history: HistoryArgs,
+    #[clap(flatten)]
+    receipts: ReceiptArgs,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum Message {
-    Message { text: String },
-    Direct { to: PublicKey, encrypted: Vec<u8> },
-    // more message types will be added later
+/// Serves the history of our rooms to nodes that connect with [`SYNC_ALPN`].
+#[derive(Debug)]
+struct SyncProtocol {
+    history: Arc<History>,
+    access: SyncAccess,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct SignedMessage {
-    from: PublicKey,
-    data: Vec<u8>,
-    signature: Signature,
-    uid: u128,
+impl ProtocolHandler for SyncProtocol {
+    fn accept(self: Arc<Self>, conn: Connecting) -> BoxFuture<'static, anyhow::Result<()>> {
+        Box::pin(async move {
+            let connection = conn.await?;
+            serve_sync(connection, &self.history, &self.access).await
+        })
+    }
+}
+
+/// Receives private chats from nodes that connect with [`DM_ALPN`].
+#[derive(Debug)]
+struct DmProtocol {
+    access: SyncAccess,
+    tx: mpsc::Sender<DmEvent>,
 }
 
-impl SignedMessage {
-    pub fn verify_and_decode(bytes: &[u8]) -> anyhow::Result<(PublicKey, Message)> {
-        let signed_message: Self = postcard::from_bytes(bytes)?;
-        let key: PublicKey = signed_message.from;
-        key.verify(&signed_message.data, &signed_message.signature)?;
-        let message: Message = postcard::from_bytes(&signed_message.data)?;
-        Ok((signed_message.from, message))
+impl ProtocolHandler for DmProtocol {
+    fn accept(self: Arc<Self>, conn: Connecting) -> BoxFuture<'static, anyhow::Result<()>> {
+        Box::pin(async move {
+            let connection = conn.await?;
+            serve_dm(connection, &self.access, self.tx.clone()).await
+        })
     }
+}
 
-    pub fn sign_and_encode(secret_key: &SecretKey, message: &Message) -> anyhow::Result<Vec<u8>> {
-        let data = postcard::to_stdvec(&message)?;
-        let signature = secret_key.sign(&data);
-        let from: PublicKey = secret_key.public();
-        let uid = rand::thread_rng().gen();
-        let signed_message = Self {
+/// Handle an event of a room.
+///
+/// Returns the author and the parent of a reply whose parent is not in the
+/// history, so the parent can be fetched.
+async fn handle_event<S>(
+    room: &str,
+    event: Event,
+    rooms: &mut Rooms<S>,
+    secret_key: &SecretKey,
+    guard: &mut ReplayGuard,
+    names: &mut Names,
+    history: &History,
+) -> anyhow::Result<Option<(PublicKey, u128)>> {
+    let mut missing = None;
+    if let Event::Gossip(GossipEvent::Received(msg)) = event {
+        let (Some(topic), Some(keys)) = (rooms.joined_topic(room), rooms.keys_mut(room)) else {
+            return Ok(None);
+        };
+        let VerifiedMessage {
             from,
-            data,
-            signature,
             uid,
+            timestamp,
+            max_version,
+            message,
+            ..
+        } = match SignedMessage::verify_and_decode(&msg.content, topic, guard) {
+            Ok(message) => message,
+            Err(cause) => {
+                tracing::warn!("Dropping message: {}", cause);
+                return Ok(None);
+            }
+        };
+        let (from, message, direct) = match open_message(
+            keys,
+            topic,
+            secret_key,
+            from,
+            (timestamp, uid),
+            message,
+            true,
+        ) {
+            Ok(Some(opened)) => opened,
+            Ok(None) => {
+                // sealed for somebody else, but keep it for the history of the room
+                if let Err(cause) = history.insert(topic, &msg.content) {
+                    tracing::warn!("error storing message: {}", cause);
+                }
+                return Ok(None);
+            }
+            Err(cause) => {
+                tracing::warn!("Unable to read message: {}", cause);
+                return Ok(None);
+            }
         };
-        let encoded = postcard::to_stdvec(&signed_message)?;
-        Ok(encoded)
+        // heartbeats and receipts are not worth keeping
+        if !matches!(
+            message,
+            Message::Presence | Message::Ack { .. } | Message::Read { .. }
+        ) {
+            match history.insert(topic, &msg.content) {
+                Ok(true) => {}
+                // already fetched from the history of a neighbor
+                Ok(false) => return Ok(None),
+                Err(cause) => tracing::warn!("error storing message: {}", cause),
+            }
+        }
+        if let Some(roster) = rooms.roster_mut(room) {
+            roster.seen(from, max_version);
+        }
+        show_presence(room, rooms, names);
+        if let Some(receipts) = rooms.receipts_mut(room) {
+            if let Some(sent) = receipts.apply(from, &message) {
+                println!("[{}] {}", room, sent.status(names));
+            }
+            if matches!(message, Message::Message { .. } | Message::Reply { .. }) {
+                receipts.received(from, uid, direct);
+            }
+        }
+        if let (false, Some(keys)) = (direct, rooms.keys_mut(room)) {
+            match edits::apply(history, topic, keys, secret_key, from, &message) {
+                Ok(Some(applied)) => {
+                    println!("[{}] {}", room, applied.describe(&names.display(&from)))
+                }
+                Ok(None) => {}
+                Err(cause) => tracing::warn!("Unable to apply message: {}", cause),
+            }
+            if let Some(parent_uid) = threads::parent(&message) {
+                match threads::quote(history, topic, keys, secret_key, parent_uid, names) {
+                    Ok(Some(quote)) => println!("[{}] {}", room, quote),
+                    Ok(None) => {
+                        println!("[{}] {}", room, threads::missing(parent_uid));
+                        missing = Some((from, parent_uid));
+                    }
+                    Err(cause) => tracing::warn!("Unable to quote message: {}", cause),
+                }
+            }
+        }
+        if let Err(cause) = handle_message(room, from, uid, direct, message, secret_key, names) {
+            tracing::warn!("error handling message: {}", cause);
+        }
+    } else {
+        // neighbor changes are tracked by the roster of the room
+        tracing::debug!("Got other event in room {}: {:?}", room, event);
     }
+    Ok(missing)
 }
 
-async fn handle_event(event: Event, secret_key: &SecretKey) -> anyhow::Result<()> {
-    if let Event::Gossip(GossipEvent::Received(msg)) = event {
-        let Ok((from, msg)) = SignedMessage::verify_and_decode(&msg.content) else {
-            tracing::warn!("Failed to verify message {:?}", msg.content);
-            return Ok(());
-        };
-        match msg {
-            Message::Message { text } => {
-                println!("Received message from node {}: {}", from, text);
-            }
-            Message::Direct { to, encrypted } => {
-                if to != secret_key.public() {
-                    // not for us
-                    return Ok(());
+fn handle_message(
+    room: &str,
+    from: PublicKey,
+    uid: u128,
+    direct: bool,
+    message: Message,
+    secret_key: &SecretKey,
+    names: &mut Names,
+) -> anyhow::Result<()> {
+    match message {
+        Message::Message { text } if direct => {
+            println!(
+                "[{}] got encrypted message from {}: {}",
+                room,
+                names.display(&from),
+                text
+            );
+        }
+        Message::Message { text } => {
+            println!(
+                "[{}] {} Received message from {}: {}",
+                room,
+                edits::short_ref(uid),
+                names.display(&from),
+                text
+            );
+        }
+        Message::Reply { text, .. } => {
+            // below the quote of the parent
+            println!(
+                "[{}] └ {} Received reply from {}: {}",
+                room,
+                edits::short_ref(uid),
+                names.display(&from),
+                text
+            );
+        }
+        Message::Direct { to, encrypted } => {
+            if to != secret_key.public() {
+                // not for us
+                return Ok(());
+            }
+            let mut buffer = encrypted;
+            secret_key.shared(&from).open(&mut buffer)?;
+            let message = std::str::from_utf8(&buffer)?;
+            println!(
+                "[{}] got encrypted message from {}: {}",
+                room,
+                names.display(&from),
+                message
+            );
+        }
+        Message::AboutMe { name, avatar_hash } => {
+            if from == secret_key.public() {
+                // our own announcement from the history, we know better
+                return Ok(());
+            }
+            let name = names::check_name(&name)?;
+            let before = names.display(&from);
+            if names.set(from, Profile { name, avatar_hash }) {
+                println!(
+                    "[{}] {} is now known as {}",
+                    room,
+                    before,
+                    names.display(&from)
+                );
+                if names.is_ambiguous(&from) {
+                    println!("[{}] warning: several nodes use this name", room);
                 }
-                let mut buffer = encrypted;
-                secret_key.shared(&from).open(&mut buffer)?;
-                let message = std::str::from_utf8(&buffer)?;
-                println!("got encrypted message from {}: {}", from, message);
             }
         }
-    } else {
-        tracing::info!("Got other event: {:?}", event);
+        Message::Sealed { .. } => {
+            // opened before, sealed boxes can't be nested
+        }
+        Message::Presence => {
+            // only updates the roster
+        }
+        Message::Ack { .. } | Message::Read { .. } => {
+            // only updates the receipts
+        }
+        Message::Edit { .. } | Message::Delete { .. } | Message::React { .. } => {
+            // applied to the history before
+        }
+        Message::Unknown { kind, .. } => {
+            // added by a newer version, but it stays in the history
+            tracing::debug!(
+                "Ignoring message of unknown kind {} from {}",
+                kind,
+                from.fmt_short()
+            );
+        }
+        Message::Encrypted { .. } => {
+            // opened before, so we do not have the key
+            println!(
+                "[{}] Received encrypted message from {}",
+                room,
+                names.display(&from)
+            );
+        }
+        Message::RoomKey { wrapped, .. } => {
+            let me = secret_key.public();
+            if from == me || wrapped.iter().any(|(member, _)| *member == me) {
+                println!("[{}] {} changed the room key", room, names.display(&from));
+            } else {
+                println!(
+                    "[{}] {} removed you from the room",
+                    room,
+                    names.display(&from)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decrypt a message of a room, and open it if it is a direct message.
+///
+/// Returns the real sender, the message and whether it was a direct message,
+/// or `None` if it is a direct message for somebody else.
+fn open_message(
+    keys: &mut RoomKeys,
+    topic: TopicId,
+    secret_key: &SecretKey,
+    from: PublicKey,
+    sent: Sent,
+    message: Message,
+    live: bool,
+) -> anyhow::Result<Option<(PublicKey, Message, bool)>> {
+    let message = keys.open(topic, secret_key, from, sent, message, live)?;
+    direct::unseal(secret_key, topic, from, message)
+}
+
+/// Print the last `count` messages of a room from the history.
+fn show_history(
+    room: &str,
+    topic: TopicId,
+    count: usize,
+    history: &History,
+    keys: &mut RoomKeys,
+    secret_key: &SecretKey,
+    names: &mut Names,
+) -> anyhow::Result<()> {
+    let messages = history.last(topic, count)?;
+    if messages.is_empty() {
+        println!("[{}] no messages in the history", room);
+        return Ok(());
+    }
+    println!("[{}] --- last {} messages ---", room, messages.len());
+    let mut edits = Edits::default();
+    let mut opened = Vec::new();
+    for bytes in messages {
+        // e.g. written by an older version
+        let VerifiedMessage {
+            from,
+            uid,
+            timestamp,
+            message,
+            ..
+        } = match SignedMessage::verify(&bytes, topic) {
+            Ok(message) => message,
+            Err(cause) => {
+                tracing::warn!("unable to read message from the history: {}", cause);
+                continue;
+            }
+        };
+        let (from, message, direct) = match open_message(
+            keys,
+            topic,
+            secret_key,
+            from,
+            (timestamp, uid),
+            message,
+            false,
+        ) {
+            Ok(Some(opened)) => opened,
+            // a direct message for somebody else
+            Ok(None) => continue,
+            Err(cause) => {
+                tracing::warn!("Unable to read message: {}", cause);
+                continue;
+            }
+        };
+        if !direct {
+            edits.add(from, uid, &message);
+        }
+        opened.push((from, uid, message, direct));
+    }
+    // replies are shown below their parent
+    let parents: Vec<_> = opened
+        .iter()
+        .map(|(_, uid, message, direct)| (*uid, threads::parent(message).filter(|_| !direct)))
+        .collect();
+    let mut opened: Vec<_> = opened.into_iter().map(Some).collect();
+    for (index, grouped) in threads::thread_order(&parents) {
+        let Some((from, uid, message, direct)) = opened[index].take() else {
+            continue;
+        };
+        // show the latest text of edited messages
+        let message = match message {
+            Message::Message { text } if !direct => Message::Message {
+                text: edits.show(uid, text),
+            },
+            Message::Reply { parent_uid, text } if !direct => Message::Reply {
+                parent_uid,
+                text: edits.show(uid, text),
+            },
+            message => message,
+        };
+        if let (Some(parent_uid), false) = (parents[index].1, grouped) {
+            let quote = threads::quote(history, topic, keys, secret_key, parent_uid, names)?;
+            let quote = quote.unwrap_or_else(|| threads::missing(parent_uid));
+            println!("[{}] {}", room, quote);
+        }
+        if let Err(cause) = handle_message(room, from, uid, direct, message, secret_key, names) {
+            tracing::warn!("error handling message: {}", cause);
+        }
     }
+    println!("[{}] --- end of history ---", room);
     Ok(())
 }
 
-async fn parse_as_command(text: String, secret_key: &SecretKey) -> anyhow::Result<Option<Command>> {
-    let msg = if let Some(private) = text.strip_prefix("/for ") {
+/// Command to tell a room our name, if we have one.
+fn about_me(
+    topic: TopicId,
+    version: u8,
+    keys: &RoomKeys,
+    names: &Names,
+    author: &mut Author,
+) -> anyhow::Result<Option<Command>> {
+    let Some(Profile { name, avatar_hash }) = names.get(&author.secret_key().public()).cloned()
+    else {
+        return Ok(None);
+    };
+    let msg = keys.seal(topic, Message::AboutMe { name, avatar_hash })?;
+    let signed = SignedMessage::sign_and_encode(author, topic, version, &msg)?;
+    Ok(Some(Command::Broadcast(signed.into())))
+}
+
+/// Command to tell a room we are still there.
+fn presence_command(
+    topic: TopicId,
+    version: u8,
+    keys: &RoomKeys,
+    author: &mut Author,
+) -> anyhow::Result<Command> {
+    let msg = keys.seal(topic, Message::Presence)?;
+    let signed = SignedMessage::sign_and_encode(author, topic, version, &msg)?;
+    Ok(Command::Broadcast(signed.into()))
+}
+
+/// Command to tell the author of a message that we received or read it.
+///
+/// Receipts for direct messages are sealed as well.
+fn receipt_command(
+    to: PublicKey,
+    receipt: Message,
+    direct: bool,
+    topic: TopicId,
+    version: u8,
+    keys: &RoomKeys,
+    author: &mut Author,
+) -> anyhow::Result<Command> {
+    let signed = if direct {
+        let (mut ephemeral, msg) = direct::seal(author.secret_key(), topic, to, receipt)?;
+        let msg = keys.seal(topic, msg)?;
+        SignedMessage::sign_and_encode(&mut ephemeral, topic, version, &msg)?
+    } else {
+        let msg = keys.seal(topic, receipt)?;
+        SignedMessage::sign_and_encode(author, topic, version, &msg)?
+    };
+    Ok(Command::Broadcast(signed.into()))
+}
+
+/// Commands to acknowledge the messages received in a room, or to mark them as read.
+fn receipt_commands<S>(
+    room: &str,
+    rooms: &mut Rooms<S>,
+    read: bool,
+    author: &mut Author,
+) -> anyhow::Result<Vec<Command>> {
+    let Some(receipts) = rooms.receipts_mut(room) else {
+        return Ok(Vec::new());
+    };
+    let pending = if read {
+        receipts.take_unread()
+    } else {
+        receipts.take_acks()
+    };
+    let version = rooms.version(room);
+    let (Some(topic), Some(keys)) = (rooms.joined_topic(room), rooms.keys_mut(room)) else {
+        return Ok(Vec::new());
+    };
+    let mut commands = Vec::new();
+    for (to, Unread { uid, direct }) in pending {
+        let receipt = if read {
+            Message::Read { uid }
+        } else {
+            Message::Ack { uid }
+        };
+        commands.push(receipt_command(
+            to, receipt, direct, topic, version, keys, author,
+        )?);
+    }
+    Ok(commands)
+}
+
+/// Send a command to a room, if we are still in it.
+///
+/// Errors are only logged, so a room that went away does not end the chat.
+async fn send_command<S>(rooms: &mut Rooms<S>, room: &str, cmd: Command)
+where
+    S: Sink<Command> + Unpin,
+    S::Error: std::fmt::Display,
+{
+    if let Some(sink) = rooms.sender(room) {
+        if let Err(cause) = sink.send(cmd).await {
+            tracing::warn!("error sending to room {}: {}", room, cause);
+        }
+    }
+}
+
+/// Print who joined or left a room since the last call.
+fn show_presence<S>(room: &str, rooms: &mut Rooms<S>, names: &Names) {
+    let Some(roster) = rooms.roster_mut(room) else {
+        return;
+    };
+    for change in roster.take_changes() {
+        match change {
+            Change::Joined(node_id) => println!("[{}] {} joined", room, names.display(&node_id)),
+            Change::Left(node_id) => println!("[{}] {} left", room, names.display(&node_id)),
+        }
+    }
+}
+
+/// Print the members of a room.
+fn show_who<S>(room: &str, rooms: &mut Rooms<S>, names: &Names) {
+    let Some(roster) = rooms.roster_mut(room) else {
+        return;
+    };
+    eprintln!("members of {room} besides you:");
+    for (node_id, member) in roster.members() {
+        if member.neighbor {
+            eprintln!("  {} (neighbor)", names.display(node_id));
+        } else {
+            let ago = member.last_seen.elapsed().as_secs();
+            eprintln!("  {} (seen {}s ago)", names.display(node_id), ago);
+        }
+    }
+}
+
+/// Command to send a reply to a message of a room.
+fn reply_command<S>(
+    target: Option<String>,
+    text: String,
+    room: &str,
+    rooms: &mut Rooms<S>,
+    author: &mut Author,
+    history: &History,
+) -> anyhow::Result<Command> {
+    let version = rooms.version(room);
+    let (Some(topic), Some(keys)) = (rooms.joined_topic(room), rooms.keys_mut(room)) else {
+        anyhow::bail!("not in room {room}");
+    };
+    let secret_key = author.secret_key();
+    let message = threads::prepare(
+        target.as_deref(),
+        text.clone(),
+        history,
+        topic,
+        keys,
+        secret_key,
+    )?;
+    let msg = keys.seal(topic, message)?;
+    let signed = SignedMessage::sign_and_encode(author, topic, version, &msg)?;
+    history.insert(topic, &signed)?;
+    let uid = SignedMessage::verify(&signed, topic)?.uid;
+    if let Some(receipts) = rooms.receipts_mut(room) {
+        receipts.sent(uid, text, None);
+    }
+    Ok(Command::Broadcast(signed.into()))
+}
+
+/// Command to send an edit, delete or reaction to a room, after applying it to our history.
+fn edit_command<S>(
+    command: EditCommand,
+    room: &str,
+    rooms: &mut Rooms<S>,
+    author: &mut Author,
+    history: &History,
+    names: &Names,
+) -> anyhow::Result<Command> {
+    let version = rooms.version(room);
+    let (Some(topic), Some(keys)) = (rooms.joined_topic(room), rooms.keys_mut(room)) else {
+        anyhow::bail!("not in room {room}");
+    };
+    let message = edits::prepare(command, history, topic, keys, author.secret_key())?;
+    let msg = keys.seal(topic, message.clone())?;
+    let signed = SignedMessage::sign_and_encode(author, topic, version, &msg)?;
+    history.insert(topic, &signed)?;
+    let me = author.secret_key().public();
+    if let Some(applied) = edits::apply(history, topic, keys, author.secret_key(), me, &message)? {
+        println!("[{}] {}", room, applied.describe(&names.display(&me)));
+    }
+    Ok(Command::Broadcast(signed.into()))
+}
+
+/// Command to change the key of a room, so `node_id` can no longer read it.
+fn remove_member(
+    node_id: &str,
+    topic: TopicId,
+    version: u8,
+    keys: &mut RoomKeys,
+    author: &mut Author,
+    history: &History,
+) -> anyhow::Result<Command> {
+    let Ok(node_id) = PublicKey::from_str(node_id.trim()) else {
+        anyhow::bail!("usage: /remove <node-id>");
+    };
+    // the new key is sealed with the old one already
+    let (timestamp, uid) = (chat::now(), rand::random());
+    let msg = keys.rotate(topic, author.secret_key(), &node_id, (timestamp, uid))?;
+    let signed = SignedMessage::sign_at(author, topic, version, uid, timestamp, &msg)?;
+    history.insert(topic, &signed)?;
+    eprintln!(
+        "changed the room key, sent it to {} members",
+        keys.members().len()
+    );
+    Ok(Command::Broadcast(signed.into()))
+}
+
+async fn parse_as_command<S>(
+    text: String,
+    room: &str,
+    rooms: &mut Rooms<S>,
+    author: &mut Author,
+    history: &History,
+) -> anyhow::Result<Option<Command>> {
+    if let Some(private) = text.strip_prefix("/for ") {
         // yeah yeah, there are nicer ways to do this, sue me...
         let mut parts = private.splitn(2, ' ');
         let Some(to) = parts.next() else {
@@ -101,18 +635,49 @@ async fn parse_as_command(text: String, secret_key: &SecretKey) -> anyhow::Resul
         let Ok(to) = PublicKey::from_str(to) else {
             anyhow::bail!("invalid recipient");
         };
-        let mut encrypted = msg.as_bytes().to_vec();
-        // encrypt the data in place
-        secret_key.shared(&to).seal(&mut encrypted);
-        Message::Direct { to, encrypted }
-    } else {
-        Message::Message { text }
+        return direct_command(to, msg.to_string(), room, rooms, author, history).map(Some);
+    }
+    let version = rooms.version(room);
+    let (Some(topic), Some(keys)) = (rooms.joined_topic(room), rooms.keys_mut(room)) else {
+        anyhow::bail!("not in room {room}");
     };
-    let signed = SignedMessage::sign_and_encode(secret_key, &msg)?;
+    let msg = keys.seal(topic, Message::Message { text: text.clone() })?;
+    let signed = SignedMessage::sign_and_encode(author, topic, version, &msg)?;
+    history.insert(topic, &signed)?;
+    let uid = SignedMessage::verify(&signed, topic)?.uid;
+    if let Some(receipts) = rooms.receipts_mut(room) {
+        receipts.sent(uid, text, None);
+    }
     let cmd = Command::Broadcast(signed.into());
     Ok(Some(cmd))
 }
 
+/// Command to send a direct message through a room, sealed so only `to` can read it.
+fn direct_command<S>(
+    to: PublicKey,
+    text: String,
+    room: &str,
+    rooms: &mut Rooms<S>,
+    author: &Author,
+    history: &History,
+) -> anyhow::Result<Command> {
+    let version = rooms.version(room);
+    let (Some(topic), Some(keys)) = (rooms.joined_topic(room), rooms.keys_mut(room)) else {
+        anyhow::bail!("not in room {room}");
+    };
+    // signed with a one-time key, so the room can't tell who sent it
+    let message = Message::Message { text: text.clone() };
+    let (mut ephemeral, msg) = direct::seal(author.secret_key(), topic, to, message)?;
+    let msg = keys.seal(topic, msg)?;
+    let signed = SignedMessage::sign_and_encode(&mut ephemeral, topic, version, &msg)?;
+    history.insert(topic, &signed)?;
+    let uid = SignedMessage::verify(&signed, topic)?.uid;
+    if let Some(receipts) = rooms.receipts_mut(room) {
+        receipts.sent(uid, text, Some(to));
+    }
+    Ok(Command::Broadcast(signed.into()))
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // log to console, using the RUST_LOG environment variable
@@ -120,61 +685,368 @@ async fn main() -> anyhow::Result<()> {
     // parse command line arguments
     let args = Args::parse();
     // get or create the secret key / node identity
-    let secret_key = util::get_or_create_secret()?;
+    let secret_key = args.key.persistent(env!("CARGO_PKG_NAME"))?.load()?;
     // create a new Iroh node, giving it the secret key
-    let iroh = iroh::node::Node::memory()
-        .secret_key(secret_key.clone())
+    let mut builder = iroh::node::Node::memory().secret_key(secret_key.clone());
+    if args.discovery.is_local() {
+        // only use the local network, so neither relays nor internet access are needed
+        let discovery = LocalSwarmDiscovery::new(secret_key.public())?;
+        builder = builder
+            .node_discovery(DiscoveryConfig::Custom(Box::new(discovery)))
+            .relay_mode(RelayMode::Disabled);
+    } else {
+        // use our own relays and discovery servers, if configured
+        let network = args.discovery.network()?;
+        if let Some(relay_mode) = network.relay_mode()? {
+            builder = builder.relay_mode(relay_mode);
+        }
+        if let Some(resolver) = network.dns_resolver() {
+            builder = builder.dns_resolver(resolver);
+        }
+        if network.has_custom_dns() {
+            let discovery = Discovery::Dns { publish: true };
+            if let Some(discovery) = discovery.build(&secret_key, &network)? {
+                builder = builder.node_discovery(DiscoveryConfig::Custom(discovery));
+            }
+        }
+    }
+    // serve the history of our rooms next to the gossip protocol
+    let me = secret_key.public();
+    let history = Arc::new(args.history.open(&me, args.key.ephemeral)?);
+    // only members of a room may fetch its history
+    let access = SyncAccess::default();
+    let sync = Arc::new(SyncProtocol {
+        history: history.clone(),
+        access: access.clone(),
+    });
+    // and receive private chats, also only from members of our rooms
+    let (dm_tx, mut dm_rx) = mpsc::channel(16);
+    let dm = Arc::new(DmProtocol {
+        access: access.clone(),
+        tx: dm_tx.clone(),
+    });
+    let iroh = builder
+        .build()
+        .await?
+        .accept(SYNC_ALPN.to_vec(), sync)
+        .accept(DM_ALPN.to_vec(), dm)
         .spawn()
         .await?;
     // wait for the node to figure out its own home relay
-    wait_for_relay(iroh.endpoint()).await?;
-    // print node addr and ticket, both long and short
-    let mut my_addr = iroh.endpoint().node_addr().await?;
-    let ticket = NodeTicket::new(my_addr.clone())?;
-    println!("I am {}", my_addr.node_id);
-    println!("Connect to me using cargo run {}", ticket);
-    my_addr.apply_options(AddrInfoOptions::Id);
-    let short = NodeTicket::new(my_addr.clone())?;
-    println!("..or using          cargo run {}", short);
+    if !args.discovery.is_local() {
+        wait_for_relay(iroh.endpoint()).await?;
+    }
+    println!("I am {}", iroh.node_id());
     // add all the info from the tickets to the endpoint
     // also extract the node IDs to use as bootstrap nodes
     let mut bootstrap = Vec::new();
     for ticket in &args.tickets {
-        let addr = ticket.node_addr();
-        iroh.endpoint().add_node_addr(addr.clone()).ok();
-        bootstrap.push(addr.node_id);
-    }
-    // hardcoded topic
-    let topic = [0u8; 32];
-    // subscribe to the topic, giving the bootstrap nodes
-    // if the tickets contained additional info, this is available in the address book of the endpoint
-    let (mut sink, mut stream) = iroh.gossip().subscribe(topic, bootstrap).await?;
+        for addr in &ticket.nodes {
+            iroh.endpoint().add_node_addr(addr.clone()).ok();
+            bootstrap.push(addr.node_id);
+        }
+    }
+    // join the rooms of the tickets, giving the nodes of the ticket as bootstrap nodes
+    // the info from the tickets is available in the address book of the endpoint
+    let mut rooms = Rooms::new(args.rooms.room_salt.clone())
+        .with_invites(iroh.endpoint().clone())
+        .with_sync_access(access);
+    for ticket in &args.tickets {
+        let nodes: Vec<_> = ticket.nodes.iter().map(|addr| addr.node_id).collect();
+        rooms
+            .join_ticket(ticket, |topic| iroh.gossip().subscribe(topic, nodes))
+            .await?;
+    }
+    // join the rooms given by name, giving all nodes we know as bootstrap nodes
+    let room_names = if args.tickets.is_empty() {
+        args.rooms.names()
+    } else {
+        args.rooms.rooms.clone()
+    };
+    for name in room_names {
+        rooms
+            .join(name, |topic| {
+                iroh.gossip().subscribe(topic, bootstrap.clone())
+            })
+            .await?;
+    }
+    // print a ticket for each room
+    for (name, _, _) in rooms.list() {
+        rooms.invite(Some(&name)).await?;
+    }
+    let mut author = Author::new(secret_key.clone());
+    let mut guard = ReplayGuard::default();
+    let avatar_hash = args.profile.avatar_hash()?;
+    let mut names = Names::default();
+    if let Some(nick) = &args.profile.nick {
+        let name = names::check_name(nick)?;
+        names.set(me, Profile { name, avatar_hash });
+    }
+    for (name, topic, _) in rooms.list() {
+        let Some(keys) = rooms.keys_mut(&name) else {
+            continue;
+        };
+        show_history(
+            &name,
+            topic,
+            history::DEFAULT_COUNT,
+            &history,
+            keys,
+            &secret_key,
+            &mut names,
+        )?;
+    }
+    let (synced_tx, mut synced_rx) = mpsc::channel(16);
+    let mut heartbeat = tokio::time::interval(presence::HEARTBEAT_INTERVAL);
     let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
     loop {
         select! {
-            message = stream.next() => {
+            (room, event) = rooms.next() => {
+                // neighbors coming and going
+                show_presence(&room, &mut rooms, &names);
                 // got a message from the gossip network
-                if let Some(Ok(event)) = message {
-                    if let Err(cause) = handle_event(event, &secret_key).await {
+                let version = rooms.version(&room);
+                let (Some(topic), Some(keys)) = (rooms.joined_topic(&room), rooms.keys_mut(&room)) else {
+                    continue;
+                };
+                // catch up on the messages sent before we joined
+                if let Ok(Event::Gossip(GossipEvent::Joined(neighbors))) = &event {
+                    let endpoint = iroh.endpoint().clone();
+                    spawn_sync(endpoint, history.clone(), room.clone(), topic, neighbors.clone(), synced_tx.clone());
+                }
+                // tell new neighbors who we are
+                if let Ok(Event::Gossip(GossipEvent::Joined(_) | GossipEvent::NeighborUp(_))) = &event {
+                    match about_me(topic, version, keys, &names, &mut author) {
+                        Ok(Some(cmd)) => send_command(&mut rooms, &room, cmd).await,
+                        Ok(None) => {}
+                        Err(cause) => tracing::warn!("error announcing name: {}", cause),
+                    }
+                }
+                match event {
+                    Ok(event) => {
+                        match handle_event(&room, event, &mut rooms, &secret_key, &mut guard, &mut names, &history).await {
+                            Ok(Some((from, parent_uid))) => {
+                                // the author of the reply has it, and maybe our neighbors
+                                let mut nodes = vec![from];
+                                nodes.extend(rooms.roster_mut(&room).into_iter().flat_map(|roster| roster.neighbors()));
+                                let endpoint = iroh.endpoint().clone();
+                                spawn_fetch(endpoint, history.clone(), room.clone(), topic, nodes, vec![parent_uid], synced_tx.clone());
+                            }
+                            Ok(None) => {}
+                            Err(cause) => tracing::warn!("error handling message: {}", cause),
+                        }
+                        // acknowledge what we just received
+                        if args.receipts.enabled {
+                            match receipt_commands(&room, &mut rooms, false, &mut author) {
+                                Ok(acks) => {
+                                    for cmd in acks {
+                                        send_command(&mut rooms, &room, cmd).await;
+                                    }
+                                }
+                                Err(cause) => tracing::warn!("error sending receipt: {}", cause),
+                            }
+                        }
+                    }
+                    Err(cause) => tracing::warn!("error in room {}: {}", room, cause),
+                }
+            }
+            _ = heartbeat.tick() => {
+                for (room, topic, _) in rooms.list() {
+                    let version = rooms.version(&room);
+                    let Some(keys) = rooms.keys_mut(&room) else {
+                        continue;
+                    };
+                    match presence_command(topic, version, keys, &mut author) {
+                        Ok(cmd) => send_command(&mut rooms, &room, cmd).await,
+                        Err(cause) => tracing::warn!("error sending heartbeat: {}", cause),
+                    }
+                    if let Some(roster) = rooms.roster_mut(&room) {
+                        roster.expire();
+                    }
+                    show_presence(&room, &mut rooms, &names);
+                }
+            }
+            Some(Synced { room, from: peer, messages }) = synced_rx.recv() => {
+                println!("[{}] --- {} missed messages from {} ---", room, messages.len(), names.display(&peer));
+                let (Some(topic), Some(keys)) = (rooms.joined_topic(&room), rooms.keys_mut(&room)) else {
+                    continue;
+                };
+                for VerifiedMessage { from, uid, timestamp, message, .. } in messages {
+                    let (from, message, direct) = match open_message(keys, topic, &secret_key, from, (timestamp, uid), message, false) {
+                        Ok(Some(opened)) => opened,
+                        Ok(None) => continue,
+                        Err(cause) => {
+                            tracing::warn!("Unable to read message: {}", cause);
+                            continue;
+                        }
+                    };
+                    if !direct {
+                        match edits::apply(&history, topic, keys, &secret_key, from, &message) {
+                            Ok(Some(applied)) => println!("[{}] {}", room, applied.describe(&names.display(&from))),
+                            Ok(None) => {}
+                            Err(cause) => tracing::warn!("Unable to apply message: {}", cause),
+                        }
+                    }
+                    if let (false, Some(parent_uid)) = (direct, threads::parent(&message)) {
+                        match threads::quote(&history, topic, keys, &secret_key, parent_uid, &names) {
+                            Ok(Some(quote)) => println!("[{}] {}", room, quote),
+                            Ok(None) => {
+                                println!("[{}] {}", room, threads::missing(parent_uid));
+                                let endpoint = iroh.endpoint().clone();
+                                spawn_fetch(endpoint, history.clone(), room.clone(), topic, vec![from, peer], vec![parent_uid], synced_tx.clone());
+                            }
+                            Err(cause) => tracing::warn!("Unable to quote message: {}", cause),
+                        }
+                    }
+                    if let Err(cause) = handle_message(&room, from, uid, direct, message, &secret_key, &mut names) {
                         tracing::warn!("error handling message: {}", cause);
                     }
-                } else {
-                    break;
                 }
+                println!("[{}] --- end of missed messages ---", room);
             }
+            Some(event) = dm_rx.recv() => match event {
+                DmEvent::Received { from, text } => println!("[dm] Received message from {}: {}", names.display(&from), text),
+                DmEvent::Delivered { to } => eprintln!("delivered to {}", names.display(&to)),
+                DmEvent::Failed { to, text, cause } => {
+                    // the room floods it to everyone, but it still gets there
+                    let Some(room) = rooms.room_with_member(&to).map(str::to_string) else {
+                        eprintln!("unable to reach {}: {}, and not in any room with them", names.display(&to), cause);
+                        continue;
+                    };
+                    eprintln!("unable to reach {} directly ({}), sending through room {}", names.display(&to), cause, room);
+                    match direct_command(to, text, &room, &mut rooms, &author, &history) {
+                        Ok(cmd) => {
+                            send_command(&mut rooms, &room, cmd).await;
+                        }
+                        Err(cause) => tracing::warn!("error sending message: {}", cause),
+                    }
+                }
+            },
             line = stdin.next_line() => {
                 if let Ok(Some(line)) = line {
-                    // got a line from stdin
-                    match parse_as_command(line, &secret_key).await {
+                    // got a line from stdin, either a command or something to send
+                    if let Some(nick) = line.strip_prefix("/nick ") {
+                        let name = match names::check_name(nick) {
+                            Ok(name) => name,
+                            Err(cause) => {
+                                eprintln!("{cause}");
+                                continue;
+                            }
+                        };
+                        names.set(me, Profile { name, avatar_hash });
+                        eprintln!("you are now known as {}", names.display(&me));
+                        for (room, topic, _) in rooms.list() {
+                            let version = rooms.version(&room);
+                            let Some(keys) = rooms.keys_mut(&room) else {
+                                continue;
+                            };
+                            match about_me(topic, version, keys, &names, &mut author) {
+                                Ok(Some(cmd)) => send_command(&mut rooms, &room, cmd).await,
+                                Ok(None) => {}
+                                Err(cause) => tracing::warn!("error announcing name: {}", cause),
+                            }
+                        }
+                        continue;
+                    }
+                    if line.trim() == "/who" {
+                        match rooms.current_name().map(str::to_string) {
+                            Some(room) => show_who(&room, &mut rooms, &names),
+                            None => eprintln!("not in any room"),
+                        }
+                        continue;
+                    }
+                    if let Some(dm) = dm::parse_dm_command(&line) {
+                        match dm {
+                            Ok((to, text)) => spawn_send_dm(iroh.endpoint().clone(), to, text, dm_tx.clone()),
+                            Err(cause) => eprintln!("{cause}"),
+                        }
+                        continue;
+                    }
+                    if let Some(node_id) = line.strip_prefix("/remove ") {
+                        let (Some(room), Some(topic)) = (rooms.current_name().map(str::to_string), rooms.current_topic()) else {
+                            eprintln!("not in any room");
+                            continue;
+                        };
+                        let version = rooms.version(&room);
+                        let Some(keys) = rooms.keys_mut(&room) else {
+                            eprintln!("not in any room");
+                            continue;
+                        };
+                        match remove_member(node_id, topic, version, keys, &mut author, &history) {
+                            Ok(cmd) => {
+                                send_command(&mut rooms, &room, cmd).await;
+                            }
+                            Err(cause) => eprintln!("{cause}"),
+                        }
+                        continue;
+                    }
+                    if let Some(command) = edits::parse_edit_command(&line) {
+                        let Some(room) = rooms.current_name().map(str::to_string) else {
+                            eprintln!("not in any room");
+                            continue;
+                        };
+                        match command.and_then(|command| edit_command(command, &room, &mut rooms, &mut author, &history, &names)) {
+                            Ok(cmd) => {
+                                send_command(&mut rooms, &room, cmd).await;
+                            }
+                            Err(cause) => eprintln!("{cause}"),
+                        }
+                        continue;
+                    }
+                    if let Some(count) = history::parse_history_command(&line) {
+                        let current = rooms.current_name().map(str::to_string).zip(rooms.current_topic());
+                        let res = match (count, current) {
+                            (Ok(count), Some((room, topic))) => match rooms.keys_mut(&room) {
+                                Some(keys) => show_history(&room, topic, count, &history, keys, &secret_key, &mut names),
+                                None => Err(anyhow::anyhow!("not in any room")),
+                            },
+                            (Ok(_), None) => Err(anyhow::anyhow!("not in any room")),
+                            (Err(cause), _) => Err(cause),
+                        };
+                        if let Err(cause) = res {
+                            eprintln!("{cause}");
+                        }
+                        continue;
+                    }
+                    let join = |topic| iroh.gossip().subscribe(topic, bootstrap.clone());
+                    if rooms.handle_line(&line, join).await {
+                        continue;
+                    }
+                    let Some(room) = rooms.current_name().map(str::to_string) else {
+                        continue;
+                    };
+                    // whoever writes has read what came before
+                    if args.receipts.enabled {
+                        match receipt_commands(&room, &mut rooms, true, &mut author) {
+                            Ok(read_markers) => {
+                                for cmd in read_markers {
+                                    send_command(&mut rooms, &room, cmd).await;
+                                }
+                            }
+                            Err(cause) => tracing::warn!("error sending read markers: {}", cause),
+                        }
+                    }
+                    if let Some(reply) = threads::parse_reply_command(&line) {
+                        match reply.and_then(|(target, text)| reply_command(target, text, &room, &mut rooms, &mut author, &history)) {
+                            Ok(cmd) => {
+                                send_command(&mut rooms, &room, cmd).await;
+                            }
+                            Err(cause) => eprintln!("{cause}"),
+                        }
+                        continue;
+                    }
+                    match parse_as_command(line, &room, &mut rooms, &mut author, &history).await {
                         Ok(cmd) => {
                             if let Some(cmd) = cmd {
-                                sink.send(cmd).await?;
+                                send_command(&mut rooms, &room, cmd).await;
                             }
                         }
                         Err(cause) => {
                             tracing::warn!("error parsing command: {}", cause);
                         }
                     }
+                } else {
+                    break;
                 }
             }
         }
@@ -7,8 +7,7 @@ use iroh_net::{
     Endpoint,
 };
 use tracing::info;
-mod util;
-use util::*;
+use workshop_common::*;
 
 /// The ALPN we use for this protocol.
 const PIPE_ALPN: &[u8] = b"PIPE";
@@ -17,6 +16,8 @@ const PIPE_ALPN: &[u8] = b"PIPE";
 struct Args {
     /// Ticket to connect to. If not provided, the program will listen for incoming connections.
     ticket: Option<NodeTicket>,
+    #[clap(flatten)]
+    key: KeyArgs,
 }
 
 #[tokio::main]
@@ -1,40 +1,108 @@
+use std::{net::Ipv4Addr, path::PathBuf, sync::Arc};
+
 use clap::Parser;
 use iroh_base::node_addr::AddrInfoOptions;
 use iroh_net::{
-    discovery::pkarr::dht::DhtDiscovery,
-    endpoint,
-    key::{PublicKey, SecretKey},
+    endpoint::{self, Connection},
+    key::PublicKey,
     ticket::NodeTicket,
     Endpoint,
 };
+use tokio::{net::TcpListener, sync::mpsc};
 use tracing::info;
-mod util;
-use util::*;
+use tracing_subscriber::EnvFilter;
+use workshop_common::{forward::*, sessions::*, *};
 
 /// The ALPN we use for this protocol.
 const PIPE_ALPN: &[u8] = b"PIPE";
 
 #[derive(Debug, clap::Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
     /// Ticket to connect to. If not provided, the program will listen for incoming connections.
     ticket: Option<NodeTicket>,
+    /// Send and print line by line, prefixing each line with the node id of the sender.
+    ///
+    /// By default, bytes are copied unchanged, which also works for binary data.
+    #[clap(long)]
+    lines: bool,
+    /// How the listener distributes lines from stdin when several peers are connected.
+    #[clap(long, value_enum, default_value_t)]
+    fan_out: FanOut,
+    #[clap(flatten)]
+    key: KeyArgs,
+    #[clap(flatten)]
+    acl: AclArgs,
+    #[clap(flatten)]
+    discovery: DiscoveryArgs,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-/// Connect to a remote node using a ticket.
-async fn connect(ticket: NodeTicket) -> anyhow::Result<()> {
-    let secret_key = SecretKey::generate();
-    let public_key = secret_key.public();
-    // Use the default PKARR discovery. We just read from the DHT, so we don't need a private key.
-    let discovery = DhtDiscovery::default();
-    // Create a new Endpoint with the secret key.
-    // We bind to port 0 to let the OS choose a random port.
-    let endpoint = Endpoint::builder()
-        .secret_key(secret_key)
-        .discovery(Box::new(discovery))
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Listen for incoming connections and forward them to a TCP service, e.g. localhost:22.
+    ListenTcp {
+        /// Address of the TCP service to forward to.
+        addr: String,
+    },
+    /// Listen on a local TCP port and forward all connections to the remote node.
+    ConnectTcp {
+        /// Local port to listen on.
+        local_port: u16,
+        /// Ticket of the remote node, which must have been started with listen-tcp.
+        ticket: NodeTicket,
+    },
+    /// Send a file to the remote node, checking its hash on arrival.
+    Send {
+        /// File to send.
+        path: PathBuf,
+        /// Ticket of the remote node, which must have been started with receive.
+        ticket: NodeTicket,
+    },
+    /// Listen for incoming connections and store the files sent over them.
+    Receive {
+        /// Directory to store the files in. It is created if it does not exist.
+        dir: PathBuf,
+    },
+}
+
+/// Create an endpoint for connecting to a remote node.
+async fn connect_endpoint(
+    secret: SecretSource,
+    discovery: DiscoveryArgs,
+) -> anyhow::Result<Endpoint> {
+    // Create a new Endpoint. Unless a key file is given, this uses a fresh secret key.
+    // Use the default PKARR discovery. We just read from the DHT, so we don't publish.
+    EndpointBuilder::new()
+        .secret(secret)
+        .discovery(discovery.resolve(Discovery::Dht {
+            publish: false,
+            direct_addresses: false,
+        }))
+        .network(discovery.network()?)
         .bind()
-        .await?;
+        .await
+}
+
+/// Connect to a remote node using a ticket.
+async fn connect(
+    ticket: NodeTicket,
+    secret: SecretSource,
+    discovery: DiscoveryArgs,
+    lines: bool,
+) -> anyhow::Result<()> {
+    let endpoint = connect_endpoint(secret, discovery).await?;
+    let public_key = endpoint.node_id();
     let addr = ticket.node_addr().clone();
     info!("connecting to {:?}", addr);
+    if !lines {
+        // copy raw bytes in both directions until both sides are done,
+        // reconnecting and resuming if the connection is lost.
+        connect_resumable(&endpoint, addr, PIPE_ALPN).await?;
+        endpoint.close(0u32.into(), b"done").await?;
+        return Ok(());
+    }
     let connection = endpoint.connect(addr, PIPE_ALPN).await?;
     let (mut send, recv) = connection.open_bi().await?;
     tracing::info!("opened bidirectional stream");
@@ -48,10 +116,13 @@ async fn connect(ticket: NodeTicket) -> anyhow::Result<()> {
     Ok(())
 }
 
-/// Handle a single incoming connection.
-async fn handle_incoming(my_id: &PublicKey, incoming: endpoint::Incoming) -> anyhow::Result<()> {
+/// Accept an incoming connection, if it uses our ALPN and the remote node is allowed.
+async fn accept_connection(
+    incoming: endpoint::Incoming,
+    acl: &Acl,
+) -> anyhow::Result<Option<Connection>> {
     info!("connection attempt");
-    // accept the connection and get the ALPN and the bidirectional stream.
+    // accept the connection and get the ALPN.
     let mut connecting = incoming.accept()?;
     let alpn = connecting.alpn().await?;
     let connection = connecting.await?;
@@ -60,63 +131,217 @@ async fn handle_incoming(my_id: &PublicKey, incoming: endpoint::Incoming) -> any
         "got connection from {} using ALPN {:?}",
         remote_node_id, alpn
     );
+    // check if the remote node is allowed to connect.
+    if acl.reject_if_denied(&connection, &remote_node_id) {
+        return Ok(None);
+    }
     // check if the ALPN is what we expect.
     if alpn.as_slice() != PIPE_ALPN {
         tracing::warn!("unexpected ALPN: {:?}", alpn);
-        return Ok(());
+        return Ok(None);
     }
+    Ok(Some(connection))
+}
+
+/// Handle a single incoming connection, line by line.
+async fn handle_incoming(
+    my_id: PublicKey,
+    sessions: Sessions,
+    incoming: endpoint::Incoming,
+    acl: &Acl,
+) -> anyhow::Result<()> {
+    let Some(connection) = accept_connection(incoming, acl).await? else {
+        return Ok(());
+    };
+    let remote_node_id = endpoint::get_remote_node_id(&connection)?;
     // we have already accepted the connection, but we need to accept a stream on the connection.
     let (mut send, recv) = connection.accept_bi().await?;
     info!("accepted bidirectional stream");
-    info!("copying from stdin to remote");
-    let author = remote_node_id.to_string();
     // Send a greeting to the remote node.
     send.write_all(format!("hello from {}\n", my_id).as_bytes())
         .await?;
-    // Spawn two tasks to copy data in both directions.
-    tokio::spawn(copy_stdin_to(send));
-    tokio::spawn(copy_to_stdout(author, recv));
-    // this will return immediately, the tasks will keep running in the background.
-    Ok(())
+    // copy lines in both directions until the remote is done.
+    run_session(sessions, remote_node_id, send, recv).await
 }
 
-/// Accept incoming connections.
-async fn accept() -> anyhow::Result<()> {
-    let secret_key = get_or_create_secret()?;
-    let public_key = secret_key.public();
+/// Create an endpoint for accepting connections, and print how to connect to it.
+///
+/// `usage` is the command line to show in front of the tickets.
+/// Warns if `acl` lets everyone connect.
+async fn listen_endpoint(
+    secret: SecretSource,
+    discovery: DiscoveryArgs,
+    usage: &str,
+    acl: &Acl,
+) -> anyhow::Result<Endpoint> {
     // Use the default PKARR discovery. As accepting node, we want to publish
-    // our address to the DHT, so we need to provide the secret key.
+    // our address to the DHT, so the secret key is passed to the discovery.
     // other than that, there is no config. There is only one Mainline DHT globally.
     // (although you could provide other bootstrap nodes to run an internal DHT).
-    let discovery = DhtDiscovery::builder()
-        .secret_key(secret_key.clone())
-        .include_direct_addresses(true)
-        .build()?;
-    let endpoint = Endpoint::builder()
-        .secret_key(secret_key)
-        .discovery(Box::new(discovery))
+    let network = discovery.network()?;
+    let endpoint = EndpointBuilder::new()
+        .secret(secret)
+        .discovery(discovery.resolve(Discovery::Dht {
+            publish: true,
+            direct_addresses: true,
+        }))
+        .network(network.clone())
         .alpns(vec![PIPE_ALPN.to_vec()])
         .bind()
         .await?;
-    wait_for_relay(&endpoint).await?;
+    let public_key = endpoint.node_id();
+    // without relays, there is no home relay to wait for.
+    if !discovery.is_local() {
+        wait_for_relay(&endpoint).await?;
+    }
     let addr = endpoint.node_addr().await?;
-    println!("I am {}", addr.node_id);
-    println!("Listening on {:#?}", addr.info);
-    println!(
-        "Connect to me using\ncargo run {}",
+    eprintln!("I am {}", addr.node_id);
+    eprintln!("Listening on {:#?}", addr.info);
+    eprintln!(
+        "Connect to me using\n{} {}",
+        usage,
         NodeTicket::new(addr.clone())?
     );
     let mut short = addr;
     short.apply_options(AddrInfoOptions::Id);
-    println!("Or using\ncargo run {}\n", NodeTicket::new(short)?);
-    println!("To see the published info, open:");
-    println!("https://app.pkarr.org/?pk={}", z32_node_id(&public_key));
-    println!("To see DHT publishing details, run with");
-    println!("RUST_LOG=mainline::rpc=trace");
+    eprintln!("Or using\n{} {}\n", usage, NodeTicket::new(short)?);
+    // the pkarr explorer only knows about the public DHT.
+    if network.uses_public_dht() {
+        eprintln!("To see the published info, open:");
+        eprintln!("https://app.pkarr.org/?pk={}", z32_node_id(&public_key));
+    }
+    eprintln!("To see DHT publishing details, run with");
+    eprintln!("RUST_LOG=mainline::rpc=trace");
+    if acl.is_open() {
+        eprintln!("Anyone who knows the node id can connect. Use --allow to restrict access.\n");
+    }
+    Ok(endpoint)
+}
+
+/// Accept incoming connections.
+async fn accept(
+    secret: SecretSource,
+    discovery: DiscoveryArgs,
+    lines: bool,
+    fan_out: FanOut,
+    acl: Acl,
+) -> anyhow::Result<()> {
+    let usage = if lines {
+        "cargo run -- --lines"
+    } else {
+        "cargo run"
+    };
+    let endpoint = listen_endpoint(secret, discovery, usage, &acl).await?;
+    let public_key = endpoint.node_id();
+    if lines {
+        let acl = Arc::new(acl);
+        // there is only one stdin, so a single task reads it and hands the lines to the peers.
+        let sessions = Sessions::new(fan_out);
+        tokio::spawn(dispatch_stdin(sessions.clone()));
+        while let Some(incoming) = endpoint.accept().await {
+            // handle each connection concurrently.
+            let sessions = sessions.clone();
+            let acl = acl.clone();
+            tokio::spawn(async move {
+                if let Err(cause) = handle_incoming(public_key, sessions, incoming, &acl).await {
+                    tracing::warn!("error handling connection: {:?}", cause);
+                }
+            });
+        }
+    } else {
+        // keep accepting connections, so the remote can reconnect if the connection is lost.
+        let (connections_tx, connections) = mpsc::channel(1);
+        let accept_endpoint = endpoint.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = accept_endpoint.accept().await {
+                match accept_connection(incoming, &acl).await {
+                    Ok(Some(connection)) => {
+                        if connections_tx.send(connection).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(cause) => tracing::warn!("error accepting connection: {:?}", cause),
+                }
+            }
+        });
+        // serve one transfer after another, like the line based pipe serves its peers.
+        serve_resumable(connections).await?;
+    }
+    endpoint.close(0u32.into(), b"done").await?;
+    Ok(())
+}
+
+/// Accept incoming connections and forward their streams to a TCP service.
+async fn listen_tcp(
+    addr: String,
+    secret: SecretSource,
+    discovery: DiscoveryArgs,
+    acl: Acl,
+) -> anyhow::Result<()> {
+    let endpoint = listen_endpoint(
+        secret,
+        discovery,
+        "cargo run connect-tcp <local-port>",
+        &acl,
+    )
+    .await?;
+    eprintln!("Forwarding incoming connections to {}", addr);
+    forward_to_tcp(endpoint, PIPE_ALPN, &addr, acl).await
+}
+
+/// Forward connections to a local TCP port to a remote node.
+async fn connect_tcp(
+    local_port: u16,
+    ticket: NodeTicket,
+    secret: SecretSource,
+    discovery: DiscoveryArgs,
+) -> anyhow::Result<()> {
+    let endpoint = connect_endpoint(secret, discovery).await?;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, local_port)).await?;
+    let addr = ticket.node_addr().clone();
+    eprintln!("Forwarding {} to {}", listener.local_addr()?, addr.node_id);
+    forward_from_tcp(endpoint, PIPE_ALPN, addr, listener).await
+}
+
+/// Send a file to a remote node.
+async fn send(
+    path: PathBuf,
+    ticket: NodeTicket,
+    secret: SecretSource,
+    discovery: DiscoveryArgs,
+) -> anyhow::Result<()> {
+    let endpoint = connect_endpoint(secret, discovery).await?;
+    let connection = endpoint
+        .connect(ticket.node_addr().clone(), PIPE_ALPN)
+        .await?;
+    send_file(&connection, &path).await?;
+    endpoint.close(0u32.into(), b"done").await?;
+    Ok(())
+}
+
+/// Accept incoming connections and store the files sent over them in `dir`.
+async fn receive(
+    dir: PathBuf,
+    secret: SecretSource,
+    discovery: DiscoveryArgs,
+    acl: Acl,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(&dir).await?;
+    let endpoint = listen_endpoint(secret, discovery, "cargo run send <path>", &acl).await?;
+    eprintln!("Storing received files in {}", dir.display());
+    // receive one file at a time, so the progress output does not get mixed up.
     while let Some(incoming) = endpoint.accept().await {
-        // handle each connection sequentially.
-        if let Err(cause) = handle_incoming(&public_key, incoming).await {
-            tracing::warn!("error handling connection: {:?}", cause);
+        let connection = match accept_connection(incoming, &acl).await {
+            Ok(Some(connection)) => connection,
+            Ok(None) => continue,
+            Err(cause) => {
+                tracing::warn!("error accepting connection: {:?}", cause);
+                continue;
+            }
+        };
+        if let Err(cause) = receive_file(&connection, &dir).await {
+            eprintln!("Failed to receive file: {:#}", cause);
         }
     }
     Ok(())
@@ -125,14 +350,62 @@ async fn accept() -> anyhow::Result<()> {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // init logging. we can now configure the log level with the RUST_LOG environment variable.
-    tracing_subscriber::fmt::init();
+    // we log to stderr, so stdout only contains the data we receive.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
     // Parse the command line arguments.
     let args = Args::parse();
-    // if a ticket is provided, connect to the remote node, otherwise accept incoming connections.
-    if let Some(ticket) = args.ticket {
-        connect(ticket).await?;
-    } else {
-        accept().await?;
+    // the listener keeps its node id across restarts, so the ticket stays valid.
+    let listen_secret = || args.key.persistent(env!("CARGO_PKG_NAME"));
+    match args.command {
+        Some(Command::ListenTcp { addr }) => {
+            listen_tcp(addr, listen_secret()?, args.discovery, args.acl.load()?).await?
+        }
+        Some(Command::ConnectTcp { local_port, ticket }) => {
+            connect_tcp(
+                local_port,
+                ticket,
+                args.key.ephemeral_by_default(),
+                args.discovery,
+            )
+            .await?
+        }
+        Some(Command::Send { path, ticket }) => {
+            send(
+                path,
+                ticket,
+                args.key.ephemeral_by_default(),
+                args.discovery,
+            )
+            .await?
+        }
+        Some(Command::Receive { dir }) => {
+            receive(dir, listen_secret()?, args.discovery, args.acl.load()?).await?
+        }
+        // if a ticket is provided, connect to the remote node, otherwise accept incoming connections.
+        None => match args.ticket {
+            Some(ticket) => {
+                connect(
+                    ticket,
+                    args.key.ephemeral_by_default(),
+                    args.discovery,
+                    args.lines,
+                )
+                .await?
+            }
+            None => {
+                accept(
+                    listen_secret()?,
+                    args.discovery,
+                    args.lines,
+                    args.fan_out,
+                    args.acl.load()?,
+                )
+                .await?
+            }
+        },
     }
     Ok(())
 }
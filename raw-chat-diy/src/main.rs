@@ -5,26 +5,21 @@ use iroh_gossip::{
     net::{Event, Gossip},
     proto::TopicId,
 };
-use iroh_net::{
-    discovery::{dns::DnsDiscovery, pkarr::PkarrPublisher, ConcurrentDiscovery},
-    endpoint,
-    ticket::NodeTicket,
-    Endpoint,
-};
-
-mod util;
+use iroh_net::{endpoint, ticket::NodeTicket, Endpoint};
 use tokio::io::{AsyncBufReadExt, BufReader};
-use util::*;
+use workshop_common::*;
 
 #[derive(Debug, Parser)]
 struct Args {
     tickets: Vec<NodeTicket>,
+    #[clap(flatten)]
+    key: KeyArgs,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
-    let secret_key = get_or_create_secret()?;
+    let secret_key = args.key.persistent(env!("CARGO_PKG_NAME"))?.load()?;
     todo!("draw the rest of the owl");
 }
@@ -1,23 +1,25 @@
 use clap::Parser;
-use futures::StreamExt;
 use iroh_base::node_addr::AddrInfoOptions;
-use iroh_gossip::{net::Gossip, proto::TopicId};
-use iroh_net::{
-    discovery::{dns::DnsDiscovery, pkarr::PkarrPublisher, ConcurrentDiscovery},
-    ticket::NodeTicket,
-    Endpoint,
+use iroh_gossip::{
+    net::{Gossip, GossipReceiver, GossipSender, JoinOptions},
+    proto::TopicId,
 };
-
-mod util;
+use iroh_net::{ticket::NodeTicket, Endpoint, NodeId};
 use tokio::{
     io::{AsyncBufReadExt, BufReader},
     select,
 };
-use util::*;
+use workshop_common::*;
 
 #[derive(Debug, Parser)]
 struct Args {
     tickets: Vec<NodeTicket>,
+    #[clap(flatten)]
+    key: KeyArgs,
+    #[clap(flatten)]
+    discovery: DiscoveryArgs,
+    #[clap(flatten)]
+    rooms: RoomArgs,
 }
 
 /// Handle incoming connections by dispatching them to the right handler.
@@ -28,7 +30,7 @@ async fn handle_connections(endpoint: Endpoint, gossip: Gossip) -> anyhow::Resul
             let mut connecting = incoming.accept()?;
             let alpn = connecting.alpn().await?;
             let connection = connecting.await?;
-            if &alpn == iroh_gossip::net::GOSSIP_ALPN {
+            if alpn == iroh_gossip::net::GOSSIP_ALPN {
                 gossip.handle_connection(connection).await?;
             }
             anyhow::Ok(())
@@ -37,22 +39,29 @@ async fn handle_connections(endpoint: Endpoint, gossip: Gossip) -> anyhow::Resul
     Ok(())
 }
 
+/// Join the gossip topic of a room, bootstrapping from the given nodes.
+///
+/// This does not wait for the first neighbor, messages are queued until then.
+async fn join_room(
+    gossip: &Gossip,
+    topic: TopicId,
+    bootstrap: Vec<NodeId>,
+) -> anyhow::Result<(GossipSender, GossipReceiver)> {
+    let opts = JoinOptions::with_bootstrap(bootstrap);
+    Ok(gossip.join_with_opts(topic, opts).split())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
-    let secret_key = get_or_create_secret()?;
+    let secret_key = args.key.persistent(env!("CARGO_PKG_NAME"))?.load()?;
     let _public_key = secret_key.public();
-    let topic = TopicId::from([0u8; 32]);
-    let discovery = Box::new(ConcurrentDiscovery::from_services(vec![
-        Box::new(DnsDiscovery::n0_dns()),
-        Box::new(PkarrPublisher::n0_dns(secret_key.clone())),
-    ]));
-
-    let endpoint = Endpoint::builder()
-        .secret_key(secret_key.clone())
+    let endpoint = EndpointBuilder::new()
+        .secret(SecretSource::Key(secret_key.clone()))
+        .discovery(args.discovery.resolve(Discovery::Dns { publish: true }))
+        .network(args.discovery.network()?)
         .alpns(vec![iroh_gossip::net::GOSSIP_ALPN.to_vec()])
-        .discovery(discovery)
         .bind()
         .await?;
     let mut my_addr = endpoint.node_addr().await?;
@@ -62,7 +71,10 @@ async fn main() -> anyhow::Result<()> {
     my_addr.apply_options(AddrInfoOptions::Id);
     let short = NodeTicket::new(my_addr.clone())?;
     println!("Connect to me using {}", short);
-    wait_for_relay(&endpoint).await?;
+    // without relays, there is no home relay to wait for.
+    if !args.discovery.is_local() {
+        wait_for_relay(&endpoint).await?;
+    }
     // add all the info from the tickets to the endpoint
     let mut ids = Vec::new();
     for ticket in &args.tickets {
@@ -76,20 +88,30 @@ async fn main() -> anyhow::Result<()> {
         &my_addr.info,
     );
     tokio::spawn(handle_connections(endpoint, gossip.clone()));
-    let mut gossip = gossip.join(topic, ids).await?;
+    let mut rooms = Rooms::new(args.rooms.room_salt.clone());
+    for name in args.rooms.names() {
+        rooms
+            .join(name, |topic| join_room(&gossip, topic, ids.clone()))
+            .await?;
+    }
     let mut stdin = BufReader::new(tokio::io::stdin()).lines();
     loop {
         select! {
-            message = gossip.next() => {
-                if let Some(Ok(event)) = message {
-                    println!("{:?}", event);
-                } else {
-                    break;
+            (room, event) = rooms.next() => {
+                match event {
+                    Ok(event) => println!("[{}] {:?}", room, event),
+                    Err(cause) => tracing::warn!("error in room {}: {}", room, cause),
                 }
             }
             line = stdin.next_line() => {
                 if let Ok(Some(line)) = line {
-                    gossip.broadcast(line.into_bytes().into()).await?;
+                    let join = |topic| join_room(&gossip, topic, ids.clone());
+                    if rooms.handle_line(&line, join).await {
+                        continue;
+                    }
+                    if let Some(sender) = rooms.current() {
+                        sender.broadcast(line.into_bytes().into()).await?;
+                    }
                 } else {
                     break;
                 }
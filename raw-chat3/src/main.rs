@@ -1,30 +1,32 @@
 use std::str::FromStr;
 
 use clap::Parser;
-use futures::StreamExt;
 use iroh_base::node_addr::AddrInfoOptions;
 use iroh_gossip::{
-    net::{Event, Gossip, GossipEvent, GossipTopic},
+    net::{Event, Gossip, GossipEvent, GossipReceiver, GossipSender, JoinOptions},
     proto::TopicId,
 };
 use iroh_net::{
-    discovery::{dns::DnsDiscovery, pkarr::PkarrPublisher, ConcurrentDiscovery},
     key::{PublicKey, SecretKey, Signature},
     ticket::NodeTicket,
-    Endpoint,
+    Endpoint, NodeId,
 };
-
-mod util;
 use serde::{Deserialize, Serialize};
 use tokio::{
     io::{AsyncBufReadExt, BufReader},
     select,
 };
-use util::*;
+use workshop_common::*;
 
 #[derive(Debug, Parser)]
 struct Args {
     tickets: Vec<NodeTicket>,
+    #[clap(flatten)]
+    key: KeyArgs,
+    #[clap(flatten)]
+    discovery: DiscoveryArgs,
+    #[clap(flatten)]
+    rooms: RoomArgs,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -39,7 +41,7 @@ impl SignedMessage {
         let signed_message: Self = postcard::from_bytes(bytes)?;
         let key: PublicKey = signed_message.from;
         key.verify(&signed_message.data, &signed_message.signature)?;
-        let message: Message = postcard::from_bytes(&signed_message.data)?;
+        let message = Message::decode(&signed_message.data)?;
         Ok((signed_message.from, message))
     }
 
@@ -58,10 +60,42 @@ impl SignedMessage {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
 enum Message {
-    Message { text: String },
-    Direct { to: PublicKey, encrypted: Vec<u8> },
+    Message {
+        text: String,
+    },
+    Direct {
+        to: PublicKey,
+        encrypted: Vec<u8>,
+    },
     // more message types will be added later
+    /// A message of a kind added by a newer version.
+    #[serde(skip)]
+    Unknown {
+        kind: u32,
+        bytes: Vec<u8>,
+    },
+}
+
+impl Message {
+    /// Number of kinds we know, the unknown ones come after them.
+    const KNOWN_KINDS: u32 = 2;
+
+    /// Decode a message, keeping kinds added by newer versions as [`Message::Unknown`].
+    ///
+    /// The message is all of the signed data, so the fields of a kind we don't
+    /// know are the rest of the bytes.
+    fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let (kind, fields) = postcard::take_from_bytes::<u32>(bytes)?;
+        if kind < Self::KNOWN_KINDS {
+            return Ok(postcard::from_bytes(bytes)?);
+        }
+        Ok(Message::Unknown {
+            kind,
+            bytes: fields.to_vec(),
+        })
+    }
 }
 
 /// Handle incoming connections by dispatching them to the right handler.
@@ -72,7 +106,7 @@ async fn handle_connections(endpoint: Endpoint, gossip: Gossip) -> anyhow::Resul
             let mut connecting = incoming.accept()?;
             let alpn = connecting.alpn().await?;
             let connection = connecting.await?;
-            if &alpn == iroh_gossip::net::GOSSIP_ALPN {
+            if alpn == iroh_gossip::net::GOSSIP_ALPN {
                 gossip.handle_connection(connection).await?;
             }
             anyhow::Ok(())
@@ -81,10 +115,15 @@ async fn handle_connections(endpoint: Endpoint, gossip: Gossip) -> anyhow::Resul
     Ok(())
 }
 
-async fn handle_event(from: PublicKey, secret_key: SecretKey, msg: Message) -> anyhow::Result<()> {
+async fn handle_event(
+    room: &str,
+    from: PublicKey,
+    secret_key: SecretKey,
+    msg: Message,
+) -> anyhow::Result<()> {
     match msg {
         Message::Message { text } => {
-            println!("{}> {}", from, text);
+            println!("[{}] {}> {}", room, from, text);
         }
         Message::Direct { to, encrypted } => {
             if to != secret_key.public() {
@@ -94,28 +133,47 @@ async fn handle_event(from: PublicKey, secret_key: SecretKey, msg: Message) -> a
             let mut buffer = encrypted;
             secret_key.shared(&from).open(&mut buffer)?;
             let message = std::str::from_utf8(&buffer)?;
-            println!("got encrypted message from {}: {}", from, message);
-        } // more message types will be added later
+            println!(
+                "[{}] got encrypted message from {}: {}",
+                room, from, message
+            );
+        }
+        Message::Unknown { kind, bytes } => {
+            // more message types will be added later
+            tracing::debug!(
+                "Ignoring message of unknown kind {} ({} bytes) from {}",
+                kind,
+                bytes.len(),
+                from
+            );
+        }
     }
     Ok(())
 }
 
+/// Join the gossip topic of a room, bootstrapping from the given nodes.
+///
+/// This does not wait for the first neighbor, messages are queued until then.
+async fn join_room(
+    gossip: &Gossip,
+    topic: TopicId,
+    bootstrap: Vec<NodeId>,
+) -> anyhow::Result<(GossipSender, GossipReceiver)> {
+    let opts = JoinOptions::with_bootstrap(bootstrap);
+    Ok(gossip.join_with_opts(topic, opts).split())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
-    let secret_key = get_or_create_secret()?;
+    let secret_key = args.key.persistent(env!("CARGO_PKG_NAME"))?.load()?;
     let _public_key = secret_key.public();
-    let topic = TopicId::from([0u8; 32]);
-    let discovery = Box::new(ConcurrentDiscovery::from_services(vec![
-        Box::new(DnsDiscovery::n0_dns()),
-        Box::new(PkarrPublisher::n0_dns(secret_key.clone())),
-    ]));
-
-    let endpoint = Endpoint::builder()
-        .secret_key(secret_key.clone())
+    let endpoint = EndpointBuilder::new()
+        .secret(SecretSource::Key(secret_key.clone()))
+        .discovery(args.discovery.resolve(Discovery::Dns { publish: true }))
+        .network(args.discovery.network()?)
         .alpns(vec![iroh_gossip::net::GOSSIP_ALPN.to_vec()])
-        .discovery(discovery)
         .bind()
         .await?;
     let mut my_addr = endpoint.node_addr().await?;
@@ -125,7 +183,10 @@ async fn main() -> anyhow::Result<()> {
     my_addr.apply_options(AddrInfoOptions::Id);
     let short = NodeTicket::new(my_addr.clone())?;
     println!("Connect to me using {}", short);
-    wait_for_relay(&endpoint).await?;
+    // without relays, there is no home relay to wait for.
+    if !args.discovery.is_local() {
+        wait_for_relay(&endpoint).await?;
+    }
     // add all the info from the tickets to the endpoint
     let mut ids = Vec::new();
     for ticket in &args.tickets {
@@ -140,26 +201,40 @@ async fn main() -> anyhow::Result<()> {
     );
 
     tokio::spawn(handle_connections(endpoint, gossip.clone()));
-    let mut gossip = gossip.join(topic, ids).await?;
+    let mut rooms = Rooms::new(args.rooms.room_salt.clone());
+    for name in args.rooms.names() {
+        rooms
+            .join(name, |topic| join_room(&gossip, topic, ids.clone()))
+            .await?;
+    }
     let mut stdin = BufReader::new(tokio::io::stdin()).lines();
     loop {
         select! {
-            message = gossip.next() => {
-                if let Some(Ok(event)) = message {
-                    if let Event::Gossip(GossipEvent::Received(message)) = event {
-                        let (from, msg) = SignedMessage::verify_and_decode(&message.content)?;
-                        if let Err(cause) = handle_event(from, secret_key.clone(), msg).await {
-                            tracing::warn!("error handling message: {}", cause);
+            (room, event) = rooms.next() => {
+                if let Ok(Event::Gossip(GossipEvent::Received(message))) = event {
+                    // e.g. a message from another application, don't exit over it
+                    let (from, msg) = match SignedMessage::verify_and_decode(&message.content) {
+                        Ok(decoded) => decoded,
+                        Err(cause) => {
+                            tracing::warn!("Dropping message: {}", cause);
+                            continue;
                         }
+                    };
+                    if let Err(cause) = handle_event(&room, from, secret_key.clone(), msg).await {
+                        tracing::warn!("error handling message: {}", cause);
                     }
-                } else {
-                    break;
                 }
             }
             line = stdin.next_line() => {
                 if let Ok(Some(line)) = line {
-                    if let Err(cause) = send_message(&mut gossip, line, &secret_key).await {
-                        tracing::warn!("error sending message: {}", cause);
+                    let join = |topic| join_room(&gossip, topic, ids.clone());
+                    if rooms.handle_line(&line, join).await {
+                        continue;
+                    }
+                    if let Some(sender) = rooms.current() {
+                        if let Err(cause) = send_message(sender, line, &secret_key).await {
+                            tracing::warn!("error sending message: {}", cause);
+                        }
                     }
                 } else {
                     break;
@@ -171,7 +246,7 @@ async fn main() -> anyhow::Result<()> {
 }
 
 async fn send_message(
-    gossip: &mut GossipTopic,
+    sender: &GossipSender,
     line: String,
     secret_key: &SecretKey,
 ) -> anyhow::Result<()> {
@@ -195,6 +270,6 @@ async fn send_message(
         Message::Message { text: line }
     };
     let msg = SignedMessage::sign_and_encode(secret_key, &msg)?;
-    gossip.broadcast(msg.into()).await?;
+    sender.broadcast(msg.into()).await?;
     Ok(())
 }
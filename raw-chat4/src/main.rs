@@ -1,83 +1,70 @@
-use std::str::FromStr;
+use std::{str::FromStr, sync::Arc};
 
 use clap::Parser;
-use futures::StreamExt;
-use iroh_base::node_addr::AddrInfoOptions;
 use iroh_gossip::{
-    net::{Event, Gossip, GossipEvent, GossipTopic},
+    net::{Event, Gossip, GossipEvent, GossipReceiver, GossipSender, JoinOptions},
     proto::TopicId,
 };
 use iroh_net::{
-    discovery::{dns::DnsDiscovery, pkarr::PkarrPublisher, ConcurrentDiscovery},
-    key::{PublicKey, SecretKey, Signature},
-    ticket::NodeTicket,
-    Endpoint,
+    key::{PublicKey, SecretKey},
+    Endpoint, NodeId,
 };
-
-mod util;
-use rand::Rng;
-use serde::{Deserialize, Serialize};
 use tokio::{
     io::{AsyncBufReadExt, BufReader},
     select,
+    sync::mpsc,
+};
+use workshop_common::{
+    chat::{self, Author, Message, ReplayGuard, SignedMessage, VerifiedMessage},
+    dm::{serve_dm, spawn_send_dm, DmEvent, DM_ALPN},
+    edits::{EditCommand, Edits},
+    presence::Change,
+    receipts::Unread,
+    room_key::{RoomKeys, Sent},
+    sync::{serve_sync, spawn_fetch, spawn_sync, SyncAccess, Synced, SYNC_ALPN},
+    *,
 };
-use util::*;
 
 #[derive(Debug, Parser)]
 struct Args {
-    tickets: Vec<NodeTicket>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct SignedMessage {
-    from: PublicKey,
-    data: Vec<u8>,
-    signature: Signature,
-    uid: u128,
-}
-
-impl SignedMessage {
-    pub fn verify_and_decode(bytes: &[u8]) -> anyhow::Result<(PublicKey, Message)> {
-        let signed_message: Self = postcard::from_bytes(bytes)?;
-        let key: PublicKey = signed_message.from;
-        key.verify(&signed_message.data, &signed_message.signature)?;
-        let message: Message = postcard::from_bytes(&signed_message.data)?;
-        Ok((signed_message.from, message))
-    }
-
-    pub fn sign_and_encode(secret_key: &SecretKey, message: &Message) -> anyhow::Result<Vec<u8>> {
-        let data = postcard::to_stdvec(&message)?;
-        let signature = secret_key.sign(&data);
-        let from: PublicKey = secret_key.public();
-        let uid = rand::thread_rng().gen();
-        let signed_message = Self {
-            from,
-            data,
-            signature,
-            uid,
-        };
-        let encoded = postcard::to_stdvec(&signed_message)?;
-        Ok(encoded)
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-enum Message {
-    Message { text: String },
-    Direct { to: PublicKey, encrypted: Vec<u8> },
-    // more message types will be added later
+    tickets: Vec<ChatTicket>,
+    #[clap(flatten)]
+    key: KeyArgs,
+    #[clap(flatten)]
+    discovery: DiscoveryArgs,
+    #[clap(flatten)]
+    rooms: RoomArgs,
+    #[clap(flatten)]
+    profile: ProfileArgs,
+    #[clap(flatten)]
+    history: HistoryArgs,
+    #[clap(flatten)]
+    receipts: ReceiptArgs,
 }
 
 /// Handle incoming connections by dispatching them to the right handler.
-async fn handle_connections(endpoint: Endpoint, gossip: Gossip) -> anyhow::Result<()> {
+async fn handle_connections(
+    endpoint: Endpoint,
+    gossip: Gossip,
+    history: Arc<History>,
+    access: SyncAccess,
+    dm_tx: mpsc::Sender<DmEvent>,
+) -> anyhow::Result<()> {
     while let Some(incoming) = endpoint.accept().await {
         let gossip = gossip.clone();
+        let history = history.clone();
+        let access = access.clone();
+        let dm_tx = dm_tx.clone();
         tokio::spawn(async move {
             let mut connecting = incoming.accept()?;
             let alpn = connecting.alpn().await?;
             let connection = connecting.await?;
-            if &alpn == iroh_gossip::net::GOSSIP_ALPN {
+            if alpn == iroh_gossip::net::GOSSIP_ALPN {
                 gossip.handle_connection(connection).await?;
+            } else if alpn == SYNC_ALPN {
+                serve_sync(connection, &history, &access).await?;
+            } else if alpn == DM_ALPN {
+                serve_dm(connection, &access, dm_tx).await?;
             }
             anyhow::Ok(())
         });
@@ -85,10 +72,32 @@ async fn handle_connections(endpoint: Endpoint, gossip: Gossip) -> anyhow::Resul
     Ok(())
 }
 
-async fn handle_event(from: PublicKey, secret_key: SecretKey, msg: Message) -> anyhow::Result<()> {
+async fn handle_event(
+    room: &str,
+    from: PublicKey,
+    uid: u128,
+    direct: bool,
+    secret_key: SecretKey,
+    msg: Message,
+    names: &mut Names,
+) -> anyhow::Result<()> {
     match msg {
+        Message::Message { text } if direct => {
+            println!(
+                "[{}] got encrypted message from {}: {}",
+                room,
+                names.display(&from),
+                text
+            );
+        }
         Message::Message { text } => {
-            println!("{}> {}", from, text);
+            let uid = edits::short_ref(uid);
+            println!("[{}] {} {}> {}", room, uid, names.display(&from), text);
+        }
+        Message::Reply { text, .. } => {
+            // below the quote of the parent
+            let uid = edits::short_ref(uid);
+            println!("[{}] └ {} {}> {}", room, uid, names.display(&from), text);
         }
         Message::Direct { to, encrypted } => {
             if to != secret_key.public() {
@@ -98,44 +107,451 @@ async fn handle_event(from: PublicKey, secret_key: SecretKey, msg: Message) -> a
             let mut buffer = encrypted;
             secret_key.shared(&from).open(&mut buffer)?;
             let message = std::str::from_utf8(&buffer)?;
-            println!("got encrypted message from {}: {}", from, message);
+            println!(
+                "[{}] got encrypted message from {}: {}",
+                room,
+                names.display(&from),
+                message
+            );
+        }
+        Message::AboutMe { name, avatar_hash } => {
+            if from == secret_key.public() {
+                // our own announcement from the history, we know better
+                return Ok(());
+            }
+            let name = names::check_name(&name)?;
+            let before = names.display(&from);
+            if names.set(from, Profile { name, avatar_hash }) {
+                println!(
+                    "[{}] {} is now known as {}",
+                    room,
+                    before,
+                    names.display(&from)
+                );
+                if names.is_ambiguous(&from) {
+                    println!("[{}] warning: several nodes use this name", room);
+                }
+            }
+        }
+        Message::Sealed { .. } => {
+            // opened before, sealed boxes can't be nested
+        }
+        Message::Presence => {
+            // only updates the roster
+        }
+        Message::Ack { .. } | Message::Read { .. } => {
+            // only updates the receipts
+        }
+        Message::Edit { .. } | Message::Delete { .. } | Message::React { .. } => {
+            // applied to the history before
+        }
+        Message::Unknown { kind, .. } => {
+            // added by a newer version, but it stays in the history
+            tracing::debug!(
+                "ignoring message of unknown kind {} from {}",
+                kind,
+                from.fmt_short()
+            );
+        }
+        Message::Encrypted { .. } => {
+            // opened before, so we do not have the key
+            println!("[{}] {}> (encrypted)", room, names.display(&from));
+        }
+        Message::RoomKey { wrapped, .. } => {
+            let me = secret_key.public();
+            if from == me || wrapped.iter().any(|(member, _)| *member == me) {
+                println!("[{}] {} changed the room key", room, names.display(&from));
+            } else {
+                println!(
+                    "[{}] {} removed you from the room",
+                    room,
+                    names.display(&from)
+                );
+            }
         } // more message types will be added later
     }
     Ok(())
 }
 
+/// Decrypt a message of a room, and open it if it is a direct message.
+///
+/// Returns the real sender, the message and whether it was a direct message,
+/// or `None` if it is a direct message for somebody else.
+fn open_message(
+    keys: &mut RoomKeys,
+    topic: TopicId,
+    secret_key: &SecretKey,
+    from: PublicKey,
+    sent: Sent,
+    message: Message,
+    live: bool,
+) -> anyhow::Result<Option<(PublicKey, Message, bool)>> {
+    let message = keys.open(topic, secret_key, from, sent, message, live)?;
+    direct::unseal(secret_key, topic, from, message)
+}
+
+/// Print the last `count` messages of a room from the history.
+async fn show_history(
+    room: &str,
+    topic: TopicId,
+    count: usize,
+    history: &History,
+    keys: &mut RoomKeys,
+    secret_key: &SecretKey,
+    names: &mut Names,
+) -> anyhow::Result<()> {
+    let messages = history.last(topic, count)?;
+    if messages.is_empty() {
+        println!("[{}] no messages in the history", room);
+        return Ok(());
+    }
+    println!("[{}] --- last {} messages ---", room, messages.len());
+    let mut edits = Edits::default();
+    let mut opened = Vec::new();
+    for bytes in messages {
+        // e.g. written by an older version
+        let VerifiedMessage {
+            from,
+            uid,
+            timestamp,
+            message,
+            ..
+        } = match SignedMessage::verify(&bytes, topic) {
+            Ok(message) => message,
+            Err(cause) => {
+                tracing::warn!("unable to read message from the history: {}", cause);
+                continue;
+            }
+        };
+        let (from, message, direct) = match open_message(
+            keys,
+            topic,
+            secret_key,
+            from,
+            (timestamp, uid),
+            message,
+            false,
+        ) {
+            Ok(Some(opened)) => opened,
+            // a direct message for somebody else
+            Ok(None) => continue,
+            Err(cause) => {
+                tracing::warn!("unable to read message: {}", cause);
+                continue;
+            }
+        };
+        if !direct {
+            edits.add(from, uid, &message);
+        }
+        opened.push((from, uid, message, direct));
+    }
+    // replies are shown below their parent
+    let parents: Vec<_> = opened
+        .iter()
+        .map(|(_, uid, message, direct)| (*uid, threads::parent(message).filter(|_| !direct)))
+        .collect();
+    let mut opened: Vec<_> = opened.into_iter().map(Some).collect();
+    for (index, grouped) in threads::thread_order(&parents) {
+        let Some((from, uid, message, direct)) = opened[index].take() else {
+            continue;
+        };
+        // show the latest text of edited messages
+        let message = match message {
+            Message::Message { text } if !direct => Message::Message {
+                text: edits.show(uid, text),
+            },
+            Message::Reply { parent_uid, text } if !direct => Message::Reply {
+                parent_uid,
+                text: edits.show(uid, text),
+            },
+            message => message,
+        };
+        if let (Some(parent_uid), false) = (parents[index].1, grouped) {
+            let quote = threads::quote(history, topic, keys, secret_key, parent_uid, names)?;
+            let quote = quote.unwrap_or_else(|| threads::missing(parent_uid));
+            println!("[{}] {}", room, quote);
+        }
+        if let Err(cause) =
+            handle_event(room, from, uid, direct, secret_key.clone(), message, names).await
+        {
+            tracing::warn!("error handling message: {}", cause);
+        }
+    }
+    println!("[{}] --- end of history ---", room);
+    Ok(())
+}
+
+/// Tell a room our name, if we have one.
+async fn announce(
+    rooms: &mut Rooms<GossipSender>,
+    room: &str,
+    names: &Names,
+    author: &mut Author,
+) -> anyhow::Result<()> {
+    let Some(Profile { name, avatar_hash }) = names.get(&author.secret_key().public()).cloned()
+    else {
+        return Ok(());
+    };
+    send_to_room(rooms, room, Message::AboutMe { name, avatar_hash }, author).await?;
+    Ok(())
+}
+
+/// Seal a message if the room is encrypted, sign it and send it to the room.
+///
+/// Returns the signed message, so it can be stored in the history.
+async fn send_to_room(
+    rooms: &mut Rooms<GossipSender>,
+    room: &str,
+    msg: Message,
+    author: &mut Author,
+) -> anyhow::Result<Vec<u8>> {
+    let sent = (chat::now(), rand::random());
+    send_to_room_at(rooms, room, msg, author, sent).await
+}
+
+/// Like [`send_to_room`], but signed with the timestamp and uid in `sent`.
+async fn send_to_room_at(
+    rooms: &mut Rooms<GossipSender>,
+    room: &str,
+    msg: Message,
+    author: &mut Author,
+    (timestamp, uid): Sent,
+) -> anyhow::Result<Vec<u8>> {
+    let topic = rooms
+        .joined_topic(room)
+        .ok_or_else(|| anyhow::anyhow!("not in room {room}"))?;
+    let msg = match msg {
+        // a new room key, already sealed with the old one
+        msg @ Message::Encrypted { .. } => msg,
+        msg => rooms
+            .keys_mut(room)
+            .ok_or_else(|| anyhow::anyhow!("not in room {room}"))?
+            .seal(topic, msg)?,
+    };
+    let version = rooms.version(room);
+    let msg = SignedMessage::sign_at(author, topic, version, uid, timestamp, &msg)?;
+    let sender = rooms
+        .sender(room)
+        .ok_or_else(|| anyhow::anyhow!("not in room {room}"))?;
+    sender.broadcast(msg.clone().into()).await?;
+    Ok(msg)
+}
+
+/// Tell the author of a message that we received or read it.
+///
+/// Receipts for direct messages are sealed as well.
+async fn send_receipt(
+    rooms: &mut Rooms<GossipSender>,
+    room: &str,
+    to: PublicKey,
+    receipt: Message,
+    direct: bool,
+    author: &mut Author,
+) -> anyhow::Result<()> {
+    if !direct {
+        send_to_room(rooms, room, receipt, author).await?;
+        return Ok(());
+    }
+    let topic = rooms
+        .joined_topic(room)
+        .ok_or_else(|| anyhow::anyhow!("not in room {room}"))?;
+    let (mut ephemeral, msg) = direct::seal(author.secret_key(), topic, to, receipt)?;
+    send_to_room(rooms, room, msg, &mut ephemeral).await?;
+    Ok(())
+}
+
+/// Acknowledge the messages received in a room.
+async fn send_acks(
+    rooms: &mut Rooms<GossipSender>,
+    room: &str,
+    author: &mut Author,
+) -> anyhow::Result<()> {
+    let acks = rooms
+        .receipts_mut(room)
+        .map(|receipts| receipts.take_acks())
+        .unwrap_or_default();
+    for (to, Unread { uid, direct }) in acks {
+        send_receipt(rooms, room, to, Message::Ack { uid }, direct, author).await?;
+    }
+    Ok(())
+}
+
+/// Mark everything received in a room as read.
+async fn send_read_markers(
+    rooms: &mut Rooms<GossipSender>,
+    room: &str,
+    author: &mut Author,
+) -> anyhow::Result<()> {
+    let unread = rooms
+        .receipts_mut(room)
+        .map(|receipts| receipts.take_unread())
+        .unwrap_or_default();
+    for (to, Unread { uid, direct }) in unread {
+        send_receipt(rooms, room, to, Message::Read { uid }, direct, author).await?;
+    }
+    Ok(())
+}
+
+/// Send an edit, delete or reaction to the current room, and apply it to our history.
+async fn send_edit(
+    rooms: &mut Rooms<GossipSender>,
+    command: EditCommand,
+    author: &mut Author,
+    history: &History,
+    names: &Names,
+) -> anyhow::Result<()> {
+    let (Some(room), Some(topic)) = (
+        rooms.current_name().map(str::to_string),
+        rooms.current_topic(),
+    ) else {
+        anyhow::bail!("not in any room");
+    };
+    let keys = rooms
+        .keys_mut(&room)
+        .ok_or_else(|| anyhow::anyhow!("not in room {room}"))?;
+    let message = edits::prepare(command, history, topic, keys, author.secret_key())?;
+    let msg = send_to_room(rooms, &room, message.clone(), author).await?;
+    history.insert(topic, &msg)?;
+    let me = author.secret_key().public();
+    let keys = rooms
+        .keys_mut(&room)
+        .ok_or_else(|| anyhow::anyhow!("not in room {room}"))?;
+    if let Some(applied) = edits::apply(history, topic, keys, author.secret_key(), me, &message)? {
+        println!("[{}] {}", room, applied.describe(&names.display(&me)));
+    }
+    Ok(())
+}
+
+/// Send a reply to a message of a room.
+async fn send_reply(
+    rooms: &mut Rooms<GossipSender>,
+    room: &str,
+    target: Option<String>,
+    text: String,
+    author: &mut Author,
+    history: &History,
+) -> anyhow::Result<()> {
+    let (Some(topic), Some(keys)) = (rooms.joined_topic(room), rooms.keys_mut(room)) else {
+        anyhow::bail!("not in room {room}");
+    };
+    let secret_key = author.secret_key();
+    let message = threads::prepare(
+        target.as_deref(),
+        text.clone(),
+        history,
+        topic,
+        keys,
+        secret_key,
+    )?;
+    let msg = send_to_room(rooms, room, message, author).await?;
+    history.insert(topic, &msg)?;
+    let uid = SignedMessage::verify(&msg, topic)?.uid;
+    if let Some(receipts) = rooms.receipts_mut(room) {
+        receipts.sent(uid, text, None);
+    }
+    Ok(())
+}
+
+/// Change the key of the current room, so `node_id` can no longer read it.
+async fn remove_member(
+    rooms: &mut Rooms<GossipSender>,
+    node_id: &str,
+    author: &mut Author,
+    history: &History,
+) -> anyhow::Result<()> {
+    let Ok(node_id) = PublicKey::from_str(node_id.trim()) else {
+        anyhow::bail!("usage: /remove <node-id>");
+    };
+    let (Some(room), Some(topic)) = (
+        rooms.current_name().map(str::to_string),
+        rooms.current_topic(),
+    ) else {
+        anyhow::bail!("not in any room");
+    };
+    let keys = rooms
+        .keys_mut(&room)
+        .ok_or_else(|| anyhow::anyhow!("not in room {room}"))?;
+    let sent = (chat::now(), rand::random());
+    let msg = keys.rotate(topic, author.secret_key(), &node_id, sent)?;
+    let members = keys.members().len();
+    let msg = send_to_room_at(rooms, &room, msg, author, sent).await?;
+    history.insert(topic, &msg)?;
+    eprintln!("changed the key of room {room}, sent it to {members} members");
+    Ok(())
+}
+
+/// Print who joined or left a room since the last call.
+fn show_presence(room: &str, rooms: &mut Rooms<GossipSender>, names: &Names) {
+    let Some(roster) = rooms.roster_mut(room) else {
+        return;
+    };
+    for change in roster.take_changes() {
+        match change {
+            Change::Joined(node_id) => println!("[{}] {} joined", room, names.display(&node_id)),
+            Change::Left(node_id) => println!("[{}] {} left", room, names.display(&node_id)),
+        }
+    }
+}
+
+/// Print the members of a room.
+fn show_who(room: &str, rooms: &mut Rooms<GossipSender>, names: &Names) {
+    let Some(roster) = rooms.roster_mut(room) else {
+        return;
+    };
+    eprintln!("members of {room} besides you:");
+    for (node_id, member) in roster.members() {
+        if member.neighbor {
+            eprintln!("  {} (neighbor)", names.display(node_id));
+        } else {
+            let ago = member.last_seen.elapsed().as_secs();
+            eprintln!("  {} (seen {}s ago)", names.display(node_id), ago);
+        }
+    }
+}
+
+/// Join the gossip topic of a room, bootstrapping from the given nodes.
+///
+/// This does not wait for the first neighbor, messages are queued until then.
+async fn join_room(
+    gossip: &Gossip,
+    topic: TopicId,
+    bootstrap: Vec<NodeId>,
+) -> anyhow::Result<(GossipSender, GossipReceiver)> {
+    let opts = JoinOptions::with_bootstrap(bootstrap);
+    Ok(gossip.join_with_opts(topic, opts).split())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
-    let secret_key = get_or_create_secret()?;
-    let _public_key = secret_key.public();
-    let topic = TopicId::from([0u8; 32]);
-    let discovery = Box::new(ConcurrentDiscovery::from_services(vec![
-        Box::new(DnsDiscovery::n0_dns()),
-        Box::new(PkarrPublisher::n0_dns(secret_key.clone())),
-    ]));
-
-    let endpoint = Endpoint::builder()
-        .secret_key(secret_key.clone())
-        .alpns(vec![iroh_gossip::net::GOSSIP_ALPN.to_vec()])
-        .discovery(discovery)
+    let secret_key = args.key.persistent(env!("CARGO_PKG_NAME"))?.load()?;
+    let me = secret_key.public();
+    let history = Arc::new(args.history.open(&me, args.key.ephemeral)?);
+    let endpoint = EndpointBuilder::new()
+        .secret(SecretSource::Key(secret_key.clone()))
+        .discovery(args.discovery.resolve(Discovery::Dns { publish: true }))
+        .network(args.discovery.network()?)
+        .alpns(vec![
+            iroh_gossip::net::GOSSIP_ALPN.to_vec(),
+            SYNC_ALPN.to_vec(),
+            DM_ALPN.to_vec(),
+        ])
         .bind()
         .await?;
-    let mut my_addr = endpoint.node_addr().await?;
-    let ticket = NodeTicket::new(my_addr.clone())?;
+    let my_addr = endpoint.node_addr().await?;
     println!("I am {}", my_addr.node_id);
-    println!("Connect to me using {}", ticket);
-    my_addr.apply_options(AddrInfoOptions::Id);
-    let short = NodeTicket::new(my_addr.clone())?;
-    println!("Connect to me using {}", short);
-    wait_for_relay(&endpoint).await?;
+    // without relays, there is no home relay to wait for.
+    if !args.discovery.is_local() {
+        wait_for_relay(&endpoint).await?;
+    }
     // add all the info from the tickets to the endpoint
     let mut ids = Vec::new();
     for ticket in &args.tickets {
-        let addr = ticket.node_addr();
-        endpoint.add_node_addr(addr.clone()).ok();
-        ids.push(addr.node_id);
+        for addr in &ticket.nodes {
+            endpoint.add_node_addr(addr.clone()).ok();
+            ids.push(addr.node_id);
+        }
     }
     let gossip = Gossip::from_endpoint(
         endpoint.clone(),
@@ -143,26 +559,314 @@ async fn main() -> anyhow::Result<()> {
         &my_addr.info,
     );
 
-    tokio::spawn(handle_connections(endpoint, gossip.clone()));
-    let mut gossip = gossip.join(topic, ids).await?;
+    let (dm_tx, mut dm_rx) = mpsc::channel(16);
+    // only members of a room may fetch its history
+    let access = SyncAccess::default();
+    tokio::spawn(handle_connections(
+        endpoint.clone(),
+        gossip.clone(),
+        history.clone(),
+        access.clone(),
+        dm_tx.clone(),
+    ));
+    let mut rooms = Rooms::new(args.rooms.room_salt.clone())
+        .with_invites(endpoint.clone())
+        .with_sync_access(access);
+    // join the rooms of the tickets, bootstrapping from the nodes of the ticket
+    for ticket in &args.tickets {
+        let nodes = ticket.nodes.iter().map(|addr| addr.node_id).collect();
+        rooms
+            .join_ticket(ticket, |topic| join_room(&gossip, topic, nodes))
+            .await?;
+    }
+    // join the rooms given by name, bootstrapping from all nodes we know
+    let room_names = if args.tickets.is_empty() {
+        args.rooms.names()
+    } else {
+        args.rooms.rooms.clone()
+    };
+    for name in room_names {
+        rooms
+            .join(name, |topic| join_room(&gossip, topic, ids.clone()))
+            .await?;
+    }
+    for (name, _, _) in rooms.list() {
+        rooms.invite(Some(&name)).await?;
+    }
+    let mut author = Author::new(secret_key.clone());
+    let mut guard = ReplayGuard::default();
+    let avatar_hash = args.profile.avatar_hash()?;
+    let mut names = Names::default();
+    if let Some(nick) = &args.profile.nick {
+        let name = names::check_name(nick)?;
+        names.set(me, Profile { name, avatar_hash });
+    }
+    for (name, topic, _) in rooms.list() {
+        let Some(keys) = rooms.keys_mut(&name) else {
+            continue;
+        };
+        show_history(
+            &name,
+            topic,
+            history::DEFAULT_COUNT,
+            &history,
+            keys,
+            &secret_key,
+            &mut names,
+        )
+        .await?;
+    }
+    let (synced_tx, mut synced_rx) = mpsc::channel(16);
+    let mut heartbeat = tokio::time::interval(presence::HEARTBEAT_INTERVAL);
     let mut stdin = BufReader::new(tokio::io::stdin()).lines();
     loop {
         select! {
-            message = gossip.next() => {
-                if let Some(Ok(event)) = message {
-                    if let Event::Gossip(GossipEvent::Received(message)) = event {
-                        let (from, msg) = SignedMessage::verify_and_decode(&message.content)?;
-                        if let Err(cause) = handle_event(from, secret_key.clone(), msg).await {
-                            tracing::warn!("error handling message: {}", cause);
+            (room, event) = rooms.next() => {
+                let Some(topic) = rooms.joined_topic(&room) else {
+                    continue;
+                };
+                show_presence(&room, &mut rooms, &names);
+                // catch up on the messages sent before we joined
+                if let Ok(Event::Gossip(GossipEvent::Joined(neighbors))) = &event {
+                    let history = history.clone();
+                    spawn_sync(endpoint.clone(), history, room.clone(), topic, neighbors.clone(), synced_tx.clone());
+                }
+                // tell new neighbors who we are
+                if let Ok(Event::Gossip(GossipEvent::Joined(_) | GossipEvent::NeighborUp(_))) = event {
+                    if let Err(cause) = announce(&mut rooms, &room, &names, &mut author).await {
+                        tracing::warn!("error announcing name: {}", cause);
+                    }
+                } else if let Ok(Event::Gossip(GossipEvent::Received(message))) = event {
+                    let bytes = message.content;
+                    // drop messages that are forged, stale or replayed, instead of exiting
+                    let VerifiedMessage { from, uid, timestamp, max_version, message, .. } =
+                        match SignedMessage::verify_and_decode(&bytes, topic, &mut guard) {
+                            Ok(message) => message,
+                            Err(cause) => {
+                                tracing::warn!("Dropping message: {}", cause);
+                                continue;
+                            }
+                        };
+                    let Some(keys) = rooms.keys_mut(&room) else {
+                        continue;
+                    };
+                    let (from, message, direct) = match open_message(keys, topic, &secret_key, from, (timestamp, uid), message, true) {
+                        Ok(Some(opened)) => opened,
+                        Ok(None) => {
+                            // sealed for somebody else, but keep it for the history of the room
+                            if let Err(cause) = history.insert(topic, &bytes) {
+                                tracing::warn!("error storing message: {}", cause);
+                            }
+                            continue;
+                        }
+                        Err(cause) => {
+                            tracing::warn!("unable to read message from {}: {}", from.fmt_short(), cause);
+                            continue;
+                        }
+                    };
+                    // heartbeats and receipts are not worth keeping
+                    if !matches!(message, Message::Presence | Message::Ack { .. } | Message::Read { .. }) {
+                        match history.insert(topic, &bytes) {
+                            Ok(true) => {}
+                            // already fetched from the history of a neighbor
+                            Ok(false) => continue,
+                            Err(cause) => tracing::warn!("error storing message: {}", cause),
                         }
                     }
-                } else {
-                    break;
+                    if let Some(roster) = rooms.roster_mut(&room) {
+                        roster.seen(from, max_version);
+                    }
+                    show_presence(&room, &mut rooms, &names);
+                    if let Some(receipts) = rooms.receipts_mut(&room) {
+                        if let Some(sent) = receipts.apply(from, &message) {
+                            println!("[{}] {}", room, sent.status(&names));
+                        }
+                        if matches!(message, Message::Message { .. } | Message::Reply { .. }) {
+                            receipts.received(from, uid, direct);
+                        }
+                    }
+                    if args.receipts.enabled {
+                        if let Err(cause) = send_acks(&mut rooms, &room, &mut author).await {
+                            tracing::warn!("error sending receipt: {}", cause);
+                        }
+                    }
+                    if let (false, Some(keys)) = (direct, rooms.keys_mut(&room)) {
+                        match edits::apply(&history, topic, keys, &secret_key, from, &message) {
+                            Ok(Some(applied)) => println!("[{}] {}", room, applied.describe(&names.display(&from))),
+                            Ok(None) => {}
+                            Err(cause) => tracing::warn!("unable to apply message from {}: {}", from.fmt_short(), cause),
+                        }
+                    }
+                    if let (false, Some(parent_uid), Some(keys)) = (direct, threads::parent(&message), rooms.keys_mut(&room)) {
+                        match threads::quote(&history, topic, keys, &secret_key, parent_uid, &names) {
+                            Ok(Some(quote)) => println!("[{}] {}", room, quote),
+                            Ok(None) => {
+                                println!("[{}] {}", room, threads::missing(parent_uid));
+                                // the author of the reply has it, and maybe our neighbors
+                                let mut nodes = vec![from];
+                                nodes.extend(rooms.roster_mut(&room).into_iter().flat_map(|roster| roster.neighbors()));
+                                spawn_fetch(endpoint.clone(), history.clone(), room.clone(), topic, nodes, vec![parent_uid], synced_tx.clone());
+                            }
+                            Err(cause) => tracing::warn!("unable to quote message: {}", cause),
+                        }
+                    }
+                    if let Err(cause) = handle_event(&room, from, uid, direct, secret_key.clone(), message, &mut names).await {
+                        tracing::warn!("error handling message: {}", cause);
+                    }
+                }
+            }
+            _ = heartbeat.tick() => {
+                for (room, _, _) in rooms.list() {
+                    if let Err(cause) = send_to_room(&mut rooms, &room, Message::Presence, &mut author).await {
+                        tracing::warn!("error sending heartbeat: {}", cause);
+                    }
+                    if let Some(roster) = rooms.roster_mut(&room) {
+                        roster.expire();
+                    }
+                    show_presence(&room, &mut rooms, &names);
                 }
             }
+            Some(Synced { room, from: peer, messages }) = synced_rx.recv() => {
+                println!("[{}] --- {} missed messages from {} ---", room, messages.len(), names.display(&peer));
+                let (Some(topic), Some(keys)) = (rooms.joined_topic(&room), rooms.keys_mut(&room)) else {
+                    continue;
+                };
+                for VerifiedMessage { from, uid, timestamp, message, .. } in messages {
+                    let (from, message, direct) = match open_message(keys, topic, &secret_key, from, (timestamp, uid), message, false) {
+                        Ok(Some(opened)) => opened,
+                        Ok(None) => continue,
+                        Err(cause) => {
+                            tracing::warn!("unable to read message from {}: {}", from.fmt_short(), cause);
+                            continue;
+                        }
+                    };
+                    if !direct {
+                        match edits::apply(&history, topic, keys, &secret_key, from, &message) {
+                            Ok(Some(applied)) => println!("[{}] {}", room, applied.describe(&names.display(&from))),
+                            Ok(None) => {}
+                            Err(cause) => tracing::warn!("unable to apply message from {}: {}", from.fmt_short(), cause),
+                        }
+                    }
+                    if let (false, Some(parent_uid)) = (direct, threads::parent(&message)) {
+                        match threads::quote(&history, topic, keys, &secret_key, parent_uid, &names) {
+                            Ok(Some(quote)) => println!("[{}] {}", room, quote),
+                            Ok(None) => {
+                                println!("[{}] {}", room, threads::missing(parent_uid));
+                                let nodes = vec![from, peer];
+                                spawn_fetch(endpoint.clone(), history.clone(), room.clone(), topic, nodes, vec![parent_uid], synced_tx.clone());
+                            }
+                            Err(cause) => tracing::warn!("unable to quote message: {}", cause),
+                        }
+                    }
+                    if let Err(cause) = handle_event(&room, from, uid, direct, secret_key.clone(), message, &mut names).await {
+                        tracing::warn!("error handling message: {}", cause);
+                    }
+                }
+                println!("[{}] --- end of missed messages ---", room);
+            }
+            Some(event) = dm_rx.recv() => match event {
+                DmEvent::Received { from, text } => println!("[dm] {}> {}", names.display(&from), text),
+                DmEvent::Delivered { to } => eprintln!("delivered to {}", names.display(&to)),
+                DmEvent::Failed { to, text, cause } => {
+                    // the room floods it to everyone, but it still gets there
+                    let Some(room) = rooms.room_with_member(&to).map(str::to_string) else {
+                        eprintln!("unable to reach {}: {}, and not in any room with them", names.display(&to), cause);
+                        continue;
+                    };
+                    eprintln!("unable to reach {} directly ({}), sending through room {}", names.display(&to), cause, room);
+                    if let Err(cause) = send_direct(&mut rooms, &room, to, text, &author, &history).await {
+                        tracing::warn!("error sending message: {}", cause);
+                    }
+                }
+            },
             line = stdin.next_line() => {
                 if let Ok(Some(line)) = line {
-                    if let Err(cause) = send_message(&mut gossip, line, &secret_key).await {
+                    if let Some(nick) = line.strip_prefix("/nick ") {
+                        let name = match names::check_name(nick) {
+                            Ok(name) => name,
+                            Err(cause) => {
+                                eprintln!("{cause}");
+                                continue;
+                            }
+                        };
+                        names.set(me, Profile { name, avatar_hash });
+                        eprintln!("you are now known as {}", names.display(&me));
+                        for (room, _, _) in rooms.list() {
+                            if let Err(cause) = announce(&mut rooms, &room, &names, &mut author).await {
+                                tracing::warn!("error announcing name: {}", cause);
+                            }
+                        }
+                        continue;
+                    }
+                    if line.trim() == "/who" {
+                        match rooms.current_name().map(str::to_string) {
+                            Some(room) => show_who(&room, &mut rooms, &names),
+                            None => eprintln!("not in any room"),
+                        }
+                        continue;
+                    }
+                    if let Some(dm) = dm::parse_dm_command(&line) {
+                        match dm {
+                            Ok((to, text)) => spawn_send_dm(endpoint.clone(), to, text, dm_tx.clone()),
+                            Err(cause) => eprintln!("{cause}"),
+                        }
+                        continue;
+                    }
+                    if let Some(node_id) = line.strip_prefix("/remove ") {
+                        if let Err(cause) = remove_member(&mut rooms, node_id, &mut author, &history).await {
+                            eprintln!("{cause}");
+                        }
+                        continue;
+                    }
+                    if let Some(command) = edits::parse_edit_command(&line) {
+                        let res = match command {
+                            Ok(command) => send_edit(&mut rooms, command, &mut author, &history, &names).await,
+                            Err(cause) => Err(cause),
+                        };
+                        if let Err(cause) = res {
+                            eprintln!("{cause}");
+                        }
+                        continue;
+                    }
+                    if let Some(count) = history::parse_history_command(&line) {
+                        let current = rooms.current_name().map(str::to_string).zip(rooms.current_topic());
+                        let res = match (count, current) {
+                            (Ok(count), Some((room, topic))) => match rooms.keys_mut(&room) {
+                                Some(keys) => show_history(&room, topic, count, &history, keys, &secret_key, &mut names).await,
+                                None => Err(anyhow::anyhow!("not in any room")),
+                            },
+                            (Ok(_), None) => Err(anyhow::anyhow!("not in any room")),
+                            (Err(cause), _) => Err(cause),
+                        };
+                        if let Err(cause) = res {
+                            eprintln!("{cause}");
+                        }
+                        continue;
+                    }
+                    let join = |topic| join_room(&gossip, topic, ids.clone());
+                    if rooms.handle_line(&line, join).await {
+                        continue;
+                    }
+                    let Some(room) = rooms.current_name().map(str::to_string) else {
+                        continue;
+                    };
+                    // whoever writes has read what came before
+                    if args.receipts.enabled {
+                        if let Err(cause) = send_read_markers(&mut rooms, &room, &mut author).await {
+                            tracing::warn!("error sending read markers: {}", cause);
+                        }
+                    }
+                    if let Some(reply) = threads::parse_reply_command(&line) {
+                        let res = match reply {
+                            Ok((target, text)) => send_reply(&mut rooms, &room, target, text, &mut author, &history).await,
+                            Err(cause) => Err(cause),
+                        };
+                        if let Err(cause) = res {
+                            eprintln!("{cause}");
+                        }
+                        continue;
+                    }
+                    if let Err(cause) = send_message(&mut rooms, &room, line, &mut author, &history).await {
                         tracing::warn!("error sending message: {}", cause);
                     }
                 } else {
@@ -175,11 +879,13 @@ async fn main() -> anyhow::Result<()> {
 }
 
 async fn send_message(
-    gossip: &mut GossipTopic,
+    rooms: &mut Rooms<GossipSender>,
+    room: &str,
     line: String,
-    secret_key: &SecretKey,
+    author: &mut Author,
+    history: &History,
 ) -> anyhow::Result<()> {
-    let msg = if let Some(private) = line.strip_prefix("/for ") {
+    if let Some(private) = line.strip_prefix("/for ") {
         // yeah yeah, there are nicer ways to do this, sue me...
         let mut parts = private.splitn(2, ' ');
         let Some(to) = parts.next() else {
@@ -191,14 +897,40 @@ async fn send_message(
         let Ok(to) = PublicKey::from_str(to) else {
             anyhow::bail!("invalid recipient");
         };
-        let mut encrypted = msg.as_bytes().to_vec();
-        // encrypt the data in place
-        secret_key.shared(&to).seal(&mut encrypted);
-        Message::Direct { to, encrypted }
-    } else {
-        Message::Message { text: line }
-    };
-    let msg = SignedMessage::sign_and_encode(secret_key, &msg)?;
-    gossip.broadcast(msg.into()).await?;
+        return send_direct(rooms, room, to, msg.to_string(), author, history).await;
+    }
+    let message = Message::Message { text: line.clone() };
+    let msg = send_to_room(rooms, room, message, author).await?;
+    if let Some(topic) = rooms.joined_topic(room) {
+        history.insert(topic, &msg)?;
+        let uid = SignedMessage::verify(&msg, topic)?.uid;
+        if let Some(receipts) = rooms.receipts_mut(room) {
+            receipts.sent(uid, line, None);
+        }
+    }
+    Ok(())
+}
+
+/// Send a direct message through a room, sealed so only `to` can read it.
+async fn send_direct(
+    rooms: &mut Rooms<GossipSender>,
+    room: &str,
+    to: PublicKey,
+    text: String,
+    author: &Author,
+    history: &History,
+) -> anyhow::Result<()> {
+    let topic = rooms
+        .joined_topic(room)
+        .ok_or_else(|| anyhow::anyhow!("not in room {room}"))?;
+    // signed with a one-time key, so the room can't tell who sent it
+    let message = Message::Message { text: text.clone() };
+    let (mut ephemeral, msg) = direct::seal(author.secret_key(), topic, to, message)?;
+    let msg = send_to_room(rooms, room, msg, &mut ephemeral).await?;
+    history.insert(topic, &msg)?;
+    let uid = SignedMessage::verify(&msg, topic)?.uid;
+    if let Some(receipts) = rooms.receipts_mut(room) {
+        receipts.sent(uid, text, Some(to));
+    }
     Ok(())
 }
@@ -0,0 +1,164 @@
+//! Deciding which nodes may connect to a listener.
+//!
+//! Knowing the node id is enough to connect to a node that publishes its
+//! address, e.g. to the Mainline DHT. An [`Acl`] restricts incoming
+//! connections to a set of allowed nodes, and/or rejects a set of denied nodes.
+use std::{collections::BTreeSet, path::PathBuf, str::FromStr};
+
+use anyhow::Context;
+use iroh_net::{endpoint::Connection, key::PublicKey};
+use tracing::warn;
+
+/// Application error code used to close connections rejected by the [`Acl`].
+pub const REJECTED_CODE: u32 = 403;
+
+/// Allowlist and denylist of node ids.
+///
+/// A node is rejected if it is on the denylist, or if there is an allowlist
+/// and the node is not on it. Without an allowlist, every other node is accepted.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    allow: BTreeSet<PublicKey>,
+    deny: BTreeSet<PublicKey>,
+}
+
+impl Acl {
+    /// Allow the given node. Once a node is allowed, all other nodes are rejected.
+    pub fn allow(&mut self, node_id: PublicKey) {
+        self.allow.insert(node_id);
+    }
+
+    /// Reject the given node.
+    pub fn deny(&mut self, node_id: PublicKey) {
+        self.deny.insert(node_id);
+    }
+
+    /// True if no node is rejected.
+    pub fn is_open(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Check if the given node may connect.
+    pub fn is_allowed(&self, node_id: &PublicKey) -> bool {
+        !self.deny.contains(node_id) && (self.allow.is_empty() || self.allow.contains(node_id))
+    }
+
+    /// Add the entries of an ACL file.
+    ///
+    /// Each line is `allow <node-id>`, `deny <node-id>` or just a node id,
+    /// which is allowed. Empty lines and lines starting with `#` are ignored.
+    pub fn add_file(&mut self, text: &str) -> anyhow::Result<()> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let res = match line.split_once(char::is_whitespace) {
+                Some(("allow", id)) => PublicKey::from_str(id.trim()).map(|id| self.allow(id)),
+                Some(("deny", id)) => PublicKey::from_str(id.trim()).map(|id| self.deny(id)),
+                _ => PublicKey::from_str(line).map(|id| self.allow(id)),
+            };
+            res.with_context(|| format!("invalid entry in line {}: {}", i + 1, line))?;
+        }
+        Ok(())
+    }
+
+    /// Close the connection if the remote node is not allowed to connect.
+    ///
+    /// Returns true if the connection was rejected.
+    pub fn reject_if_denied(&self, connection: &Connection, node_id: &PublicKey) -> bool {
+        if self.is_allowed(node_id) {
+            return false;
+        }
+        warn!("rejected connection from {node_id}");
+        connection.close(REJECTED_CODE.into(), b"not allowed");
+        true
+    }
+}
+
+/// Command line options to restrict which nodes may connect.
+///
+/// Flatten this into the arguments of a binary with `#[clap(flatten)]`.
+#[derive(Debug, Clone, clap::Args)]
+#[command(about = None, long_about = None)]
+pub struct AclArgs {
+    /// Only accept connections from this node id. Can be given multiple times.
+    #[clap(long = "allow", value_name = "NODE_ID", global = true)]
+    pub allow: Vec<PublicKey>,
+    /// Reject connections from this node id. Can be given multiple times.
+    #[clap(long = "deny", value_name = "NODE_ID", global = true)]
+    pub deny: Vec<PublicKey>,
+    /// File with node ids to allow or deny, one `allow <node-id>` or `deny <node-id>` per line.
+    #[clap(long, global = true)]
+    pub acl_file: Option<PathBuf>,
+}
+
+impl AclArgs {
+    /// Build the [`Acl`] from the command line options, reading the ACL file if given.
+    pub fn load(&self) -> anyhow::Result<Acl> {
+        let mut acl = Acl::default();
+        if let Some(path) = &self.acl_file {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read ACL file {}", path.display()))?;
+            acl.add_file(&text)
+                .with_context(|| format!("invalid ACL file {}", path.display()))?;
+        }
+        for node_id in &self.allow {
+            acl.allow(*node_id);
+        }
+        for node_id in &self.deny {
+            acl.deny(*node_id);
+        }
+        Ok(acl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_net::key::SecretKey;
+
+    use super::*;
+
+    fn node_id() -> PublicKey {
+        SecretKey::generate().public()
+    }
+
+    #[test]
+    fn add_file_parses_entries() {
+        let (allowed, bare, denied, other) = (node_id(), node_id(), node_id(), node_id());
+        let text = format!("# friends\n\nallow {allowed}\n  {bare}  \ndeny\t{denied}\n");
+        let mut acl = Acl::default();
+        acl.add_file(&text).unwrap();
+        assert!(acl.is_allowed(&allowed));
+        assert!(acl.is_allowed(&bare));
+        assert!(!acl.is_allowed(&denied));
+        // there is an allowlist, so everyone else is rejected.
+        assert!(!acl.is_allowed(&other));
+    }
+
+    #[test]
+    fn add_file_with_only_denies_accepts_others() {
+        let (denied, other) = (node_id(), node_id());
+        let mut acl = Acl::default();
+        acl.add_file(&format!("deny {denied}")).unwrap();
+        assert!(!acl.is_open());
+        assert!(!acl.is_allowed(&denied));
+        assert!(acl.is_allowed(&other));
+    }
+
+    #[test]
+    fn add_file_rejects_bad_lines() {
+        let id = node_id();
+        for text in [
+            "allow".to_string(),
+            "allow not-a-node-id".to_string(),
+            format!("block {id}"),
+            format!("allow {id} extra"),
+        ] {
+            let err = Acl::default()
+                .add_file(&format!("# ok\n{text}"))
+                .unwrap_err();
+            assert!(err.to_string().contains("line 2"), "{text}: {err}");
+        }
+    }
+}
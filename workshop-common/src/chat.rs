@@ -0,0 +1,782 @@
+//! The signed message protocol of chat3 and raw-chat4.
+//!
+//! Every message is signed by its author, together with a random uid, the time
+//! it was sent and a per-author sequence number. Gossip delivers each message
+//! once, but anyone in the room can capture a signed message and broadcast it
+//! again later. A [`ReplayGuard`] rejects messages that are too old, that were
+//! seen before, or that are far behind the sequence of their author.
+//!
+//! The signature covers an [`Envelope`] with a protocol tag, the version of
+//! the format and the topic id of the room, so a signature is only valid for
+//! this application and for the room it was sent to. The version is also sent
+//! in front of every message, so messages of a newer format are detected
+//! before decoding them.
+//!
+//! Every message also carries the highest version its author understands. The
+//! members of a room send with the highest version all of them understand, see
+//! [`Roster::version`](crate::presence::Roster::version), so a room keeps
+//! working while its members update one after the other.
+//!
+//! A [`Message`] is encoded as its kind, followed by its length prefixed
+//! fields. Kinds added by newer versions are decoded as [`Message::Unknown`]
+//! instead of failing, so older nodes can skip them.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use iroh_gossip::proto::TopicId;
+use iroh_net::key::{PublicKey, SecretKey, Signature};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Tag to make signatures of chat messages distinct from other signatures by the same key.
+const PROTOCOL_TAG: &[u8] = b"iroh-workshop/chat";
+/// Highest version of the message format we understand, and the one we prefer.
+///
+/// Bump this with every change of the wire format, including new kinds of
+/// messages. So far:
+///
+/// 1. signed [`Message::Message`] and [`Message::Direct`]
+/// 2. [`Message::AboutMe`]
+/// 3. [`Message::Encrypted`] and [`Message::RoomKey`]
+/// 4. [`Message::Sealed`]
+/// 5. [`Message::Presence`]
+/// 6. [`Message::Ack`] and [`Message::Read`]
+/// 7. [`Message::Edit`], [`Message::Delete`] and [`Message::React`]
+/// 8. [`Message::Reply`]
+/// 9. length prefixed fields and the highest version of the author
+pub const VERSION: u8 = 9;
+/// Lowest version of the message format we understand.
+///
+/// Older versions can't skip kinds they don't know.
+pub const MIN_VERSION: u8 = 9;
+/// How old a message may be when it arrives.
+const MAX_AGE: Duration = Duration::from_secs(10 * 60);
+/// How far the clock of an author may be ahead of ours.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+/// Number of `(author, uid)` pairs remembered to detect duplicates.
+const SEEN_CAPACITY: usize = 4096;
+/// How far a message may lag behind the newest message of its author.
+///
+/// Gossip does not guarantee ordering, so messages of an author can overtake
+/// each other a bit.
+const SEQ_WINDOW: u64 = 256;
+
+/// A chat message.
+///
+/// The kind of each message is part of the protocol, see [`Message::kind`].
+/// Fields are never added to an existing kind, a new kind is added instead.
+#[derive(Debug, Clone)]
+pub enum Message {
+    Message {
+        text: String,
+    },
+    Direct {
+        to: PublicKey,
+        encrypted: Vec<u8>,
+    },
+    /// Announce the name and avatar of the author.
+    AboutMe {
+        name: String,
+        avatar_hash: Option<[u8; 32]>,
+    },
+    /// A message sealed with the key of the room, see [`crate::room_key`].
+    Encrypted {
+        key_id: [u8; 8],
+        nonce: [u8; 24],
+        ciphertext: Vec<u8>,
+    },
+    /// A new key for the room, wrapped for each remaining member.
+    RoomKey {
+        key_id: [u8; 8],
+        wrapped: Vec<(PublicKey, Vec<u8>)>,
+    },
+    /// A direct message that hides sender and recipient, see [`crate::direct`].
+    Sealed {
+        ciphertext: Vec<u8>,
+    },
+    /// Tell the room the author is still there, see [`crate::presence`].
+    Presence,
+    /// The author received the message with this uid, see [`crate::receipts`].
+    Ack {
+        uid: u128,
+    },
+    /// The author read the message with this uid, and the earlier ones.
+    Read {
+        uid: u128,
+    },
+    /// Replace the text of an earlier message of the author, see [`crate::edits`].
+    Edit {
+        target_uid: u128,
+        text: String,
+    },
+    /// Delete an earlier message of the author.
+    Delete {
+        target_uid: u128,
+    },
+    /// React to an earlier message of anyone.
+    React {
+        target_uid: u128,
+        emoji: String,
+    },
+    /// A chat message in the thread of an earlier message, see [`crate::threads`].
+    Reply {
+        parent_uid: u128,
+        text: String,
+    },
+    /// A message of a kind added by a newer version, kept as it was received.
+    Unknown {
+        kind: u32,
+        bytes: Vec<u8>,
+    },
+}
+
+impl Message {
+    /// The kind of the message, as sent on the wire.
+    pub fn kind(&self) -> u32 {
+        match self {
+            Message::Message { .. } => 0,
+            Message::Direct { .. } => 1,
+            Message::AboutMe { .. } => 2,
+            Message::Encrypted { .. } => 3,
+            Message::RoomKey { .. } => 4,
+            Message::Sealed { .. } => 5,
+            Message::Presence => 6,
+            Message::Ack { .. } => 7,
+            Message::Read { .. } => 8,
+            Message::Edit { .. } => 9,
+            Message::Delete { .. } => 10,
+            Message::React { .. } => 11,
+            Message::Reply { .. } => 12,
+            Message::Unknown { kind, .. } => *kind,
+        }
+    }
+
+    /// Encode the fields of the message.
+    fn encode_fields(&self) -> postcard::Result<Vec<u8>> {
+        use postcard::to_stdvec as encode;
+        match self {
+            Message::Message { text } => encode(text),
+            Message::Direct { to, encrypted } => encode(&(to, encrypted)),
+            Message::AboutMe { name, avatar_hash } => encode(&(name, avatar_hash)),
+            Message::Encrypted {
+                key_id,
+                nonce,
+                ciphertext,
+            } => encode(&(key_id, nonce, ciphertext)),
+            Message::RoomKey { key_id, wrapped } => encode(&(key_id, wrapped)),
+            Message::Sealed { ciphertext } => encode(ciphertext),
+            Message::Presence => Ok(Vec::new()),
+            Message::Ack { uid } | Message::Read { uid } => encode(uid),
+            Message::Edit { target_uid, text } => encode(&(target_uid, text)),
+            Message::Delete { target_uid } => encode(target_uid),
+            Message::React { target_uid, emoji } => encode(&(target_uid, emoji)),
+            Message::Reply { parent_uid, text } => encode(&(parent_uid, text)),
+            Message::Unknown { bytes, .. } => Ok(bytes.clone()),
+        }
+    }
+
+    /// Decode the fields of a message of the given kind.
+    fn decode_fields(kind: u32, bytes: Vec<u8>) -> postcard::Result<Self> {
+        let message = match kind {
+            0 => Message::Message {
+                text: decode(&bytes)?,
+            },
+            1 => {
+                let (to, encrypted) = decode(&bytes)?;
+                Message::Direct { to, encrypted }
+            }
+            2 => {
+                let (name, avatar_hash) = decode(&bytes)?;
+                Message::AboutMe { name, avatar_hash }
+            }
+            3 => {
+                let (key_id, nonce, ciphertext) = decode(&bytes)?;
+                Message::Encrypted {
+                    key_id,
+                    nonce,
+                    ciphertext,
+                }
+            }
+            4 => {
+                let (key_id, wrapped) = decode(&bytes)?;
+                Message::RoomKey { key_id, wrapped }
+            }
+            5 => Message::Sealed {
+                ciphertext: decode(&bytes)?,
+            },
+            6 => {
+                decode::<()>(&bytes)?;
+                Message::Presence
+            }
+            7 => Message::Ack {
+                uid: decode(&bytes)?,
+            },
+            8 => Message::Read {
+                uid: decode(&bytes)?,
+            },
+            9 => {
+                let (target_uid, text) = decode(&bytes)?;
+                Message::Edit { target_uid, text }
+            }
+            10 => Message::Delete {
+                target_uid: decode(&bytes)?,
+            },
+            11 => {
+                let (target_uid, emoji) = decode(&bytes)?;
+                Message::React { target_uid, emoji }
+            }
+            12 => {
+                let (parent_uid, text) = decode(&bytes)?;
+                Message::Reply { parent_uid, text }
+            }
+            kind => Message::Unknown { kind, bytes },
+        };
+        Ok(message)
+    }
+}
+
+/// Decode the fields of a message, which must use all of the bytes.
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> postcard::Result<T> {
+    match postcard::take_from_bytes(bytes)? {
+        (value, []) => Ok(value),
+        _ => Err(postcard::Error::DeserializeBadEncoding),
+    }
+}
+
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let fields = self.encode_fields().map_err(serde::ser::Error::custom)?;
+        (self.kind(), fields).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (kind, fields) = <(u32, Vec<u8>)>::deserialize(deserializer)?;
+        Message::decode_fields(kind, fields).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A message with the signature of its author, as sent over gossip.
+///
+/// On the wire it is preceded by the version byte of its format.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedMessage {
+    from: PublicKey,
+    /// Postcard encoded [`SignedData`].
+    data: Vec<u8>,
+    signature: Signature,
+}
+
+/// The bytes covered by the signature.
+///
+/// Only `data` is sent, the rest is known to the receiver.
+#[derive(Debug, Serialize)]
+struct Envelope<'a> {
+    tag: &'a [u8],
+    version: u8,
+    /// The topic of the room the message is sent to.
+    topic: TopicId,
+    /// Postcard encoded [`SignedData`].
+    data: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    fn new(version: u8, topic: TopicId, data: &'a [u8]) -> Self {
+        Self {
+            tag: PROTOCOL_TAG,
+            version,
+            topic,
+            data,
+        }
+    }
+
+    fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(postcard::to_stdvec(self)?)
+    }
+}
+
+/// The part of a [`SignedMessage`] that is sent along with the signature.
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedData {
+    /// Random id, so repeated texts are distinct messages.
+    uid: u128,
+    /// Microseconds since the unix epoch, when the message was sent.
+    timestamp: u64,
+    /// Sequence number of the message, increasing per author.
+    seq: u64,
+    /// The highest version of the format the author understands.
+    max_version: u8,
+    message: Message,
+}
+
+/// A message whose signature was verified.
+#[derive(Debug, Clone)]
+pub struct VerifiedMessage {
+    pub from: PublicKey,
+    pub uid: u128,
+    /// Microseconds since the unix epoch, when the message was sent.
+    pub timestamp: u64,
+    /// Sequence number of the message, increasing per author.
+    pub seq: u64,
+    /// The highest version of the format the author understands.
+    pub max_version: u8,
+    pub message: Message,
+}
+
+impl SignedMessage {
+    /// Decode a message received on `topic`, verify its signature and check it against `guard`.
+    pub fn verify_and_decode(
+        bytes: &[u8],
+        topic: TopicId,
+        guard: &mut ReplayGuard,
+    ) -> anyhow::Result<VerifiedMessage> {
+        let message = Self::verify(bytes, topic)?;
+        guard.check(&message.from, message.uid, message.timestamp, message.seq)?;
+        Ok(message)
+    }
+
+    /// Decode a message sent to `topic` and verify its signature.
+    ///
+    /// Unlike [`SignedMessage::verify_and_decode`], this accepts old messages,
+    /// e.g. from the history.
+    pub fn verify(bytes: &[u8], topic: TopicId) -> anyhow::Result<VerifiedMessage> {
+        let Some((&version, bytes)) = bytes.split_first() else {
+            anyhow::bail!("empty message");
+        };
+        anyhow::ensure!(
+            (MIN_VERSION..=VERSION).contains(&version),
+            "unsupported message version {version}"
+        );
+        let signed_message: Self = postcard::from_bytes(bytes)?;
+        let key: PublicKey = signed_message.from;
+        let envelope = Envelope::new(version, topic, &signed_message.data).to_bytes()?;
+        key.verify(&envelope, &signed_message.signature)
+            .map_err(|_| {
+                anyhow::anyhow!("invalid signature from {key}, or message for another room")
+            })?;
+        let data: SignedData = postcard::from_bytes(&signed_message.data)?;
+        Ok(VerifiedMessage {
+            from: signed_message.from,
+            uid: data.uid,
+            timestamp: data.timestamp,
+            seq: data.seq,
+            max_version: data.max_version,
+            message: data.message,
+        })
+    }
+
+    /// Sign a message as `author` and encode it for sending to `topic`.
+    ///
+    /// `version` is the version of the format negotiated for the room.
+    pub fn sign_and_encode(
+        author: &mut Author,
+        topic: TopicId,
+        version: u8,
+        message: &Message,
+    ) -> anyhow::Result<Vec<u8>> {
+        let uid = rand::thread_rng().gen();
+        Self::sign_at(author, topic, version, uid, now(), message)
+    }
+
+    /// Sign a message with a given uid and timestamp, instead of a random one and the current time.
+    pub fn sign_at(
+        author: &mut Author,
+        topic: TopicId,
+        version: u8,
+        uid: u128,
+        timestamp: u64,
+        message: &Message,
+    ) -> anyhow::Result<Vec<u8>> {
+        anyhow::ensure!(
+            (MIN_VERSION..=VERSION).contains(&version),
+            "unsupported message version {version}"
+        );
+        let data = SignedData {
+            uid,
+            timestamp,
+            seq: author.next_seq(),
+            max_version: VERSION,
+            message: message.clone(),
+        };
+        let data = postcard::to_stdvec(&data)?;
+        let signature = author
+            .secret_key
+            .sign(&Envelope::new(version, topic, &data).to_bytes()?);
+        let signed_message = Self {
+            from: author.secret_key.public(),
+            data,
+            signature,
+        };
+        let mut encoded = vec![version];
+        postcard::to_io(&signed_message, &mut encoded)?;
+        Ok(encoded)
+    }
+}
+
+/// Our own identity as the author of messages.
+#[derive(Debug, Clone)]
+pub struct Author {
+    secret_key: SecretKey,
+    seq: u64,
+}
+
+impl Author {
+    /// Create an author with the given secret key.
+    ///
+    /// Sequence numbers are not stored, so they start at the current time in
+    /// microseconds. That way they keep increasing across restarts.
+    pub fn new(secret_key: SecretKey) -> Self {
+        Self {
+            secret_key,
+            seq: now(),
+        }
+    }
+
+    /// The secret key of the author.
+    pub fn secret_key(&self) -> &SecretKey {
+        &self.secret_key
+    }
+
+    /// The next sequence number.
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+}
+
+/// Remembers recent messages to reject stale and replayed ones.
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    /// Recently seen messages, bounded by [`SEEN_CAPACITY`].
+    seen: HashSet<(PublicKey, u128)>,
+    /// The entries of `seen`, oldest first.
+    order: VecDeque<(PublicKey, u128)>,
+    /// Highest sequence number and timestamp seen per author.
+    ///
+    /// Sealed messages are signed with one-time keys, so authors that have
+    /// been quiet for [`MAX_AGE`] are forgotten once there are too many.
+    highest_seq: HashMap<PublicKey, (u64, u64)>,
+}
+
+impl ReplayGuard {
+    /// Check a message, and remember it if it is accepted.
+    pub fn check(
+        &mut self,
+        from: &PublicKey,
+        uid: u128,
+        timestamp: u64,
+        seq: u64,
+    ) -> anyhow::Result<()> {
+        let now = now();
+        anyhow::ensure!(
+            timestamp.saturating_add(MAX_AGE.as_micros() as u64) >= now,
+            "stale message from {from}"
+        );
+        check_not_future(from, timestamp)?;
+        let key = (*from, uid);
+        anyhow::ensure!(!self.seen.contains(&key), "replayed message from {from}");
+        if !self.highest_seq.contains_key(from) && self.highest_seq.len() >= SEEN_CAPACITY {
+            // all their messages would be rejected as stale anyway
+            let max_age = MAX_AGE.as_micros() as u64;
+            self.highest_seq
+                .retain(|_, (_, last)| last.saturating_add(max_age) >= now);
+        }
+        let (highest, last) = self.highest_seq.entry(*from).or_default();
+        anyhow::ensure!(
+            seq.saturating_add(SEQ_WINDOW) > *highest,
+            "replayed message from {from}, sequence number {seq} is too old"
+        );
+        *highest = (*highest).max(seq);
+        *last = (*last).max(timestamp);
+        if self.order.len() >= SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(key);
+        self.order.push_back(key);
+        Ok(())
+    }
+}
+
+/// Refuse a message whose timestamp is more than [`MAX_CLOCK_SKEW`] ahead of our clock.
+pub(crate) fn check_not_future(from: &PublicKey, timestamp: u64) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        timestamp <= now().saturating_add(MAX_CLOCK_SKEW.as_micros() as u64),
+        "message from {from} is from the future"
+    );
+    Ok(())
+}
+
+/// The current time in microseconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before unix epoch")
+        .as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topic(name: &str) -> TopicId {
+        TopicId::from_bytes(*blake3::hash(name.as_bytes()).as_bytes())
+    }
+
+    fn author() -> Author {
+        Author::new(SecretKey::generate())
+    }
+
+    fn text(text: &str) -> Message {
+        Message::Message { text: text.into() }
+    }
+
+    #[test]
+    fn verify_and_decode_accepts_fresh_message() {
+        let mut alice = author();
+        let bytes =
+            SignedMessage::sign_and_encode(&mut alice, topic("a"), VERSION, &text("hi")).unwrap();
+        let mut guard = ReplayGuard::default();
+        let message = SignedMessage::verify_and_decode(&bytes, topic("a"), &mut guard).unwrap();
+        assert_eq!(message.from, alice.secret_key().public());
+        assert_eq!(message.max_version, VERSION);
+        assert!(matches!(message.message, Message::Message { text } if text == "hi"));
+    }
+
+    #[test]
+    fn verify_and_decode_rejects_replay() {
+        let mut alice = author();
+        let bytes =
+            SignedMessage::sign_and_encode(&mut alice, topic("a"), VERSION, &text("hi")).unwrap();
+        let mut guard = ReplayGuard::default();
+        SignedMessage::verify_and_decode(&bytes, topic("a"), &mut guard).unwrap();
+        let err = SignedMessage::verify_and_decode(&bytes, topic("a"), &mut guard).unwrap_err();
+        assert!(err.to_string().contains("replayed"), "{err}");
+    }
+
+    #[test]
+    fn verify_rejects_wrong_topic() {
+        let mut alice = author();
+        let bytes =
+            SignedMessage::sign_and_encode(&mut alice, topic("a"), VERSION, &text("hi")).unwrap();
+        let err = SignedMessage::verify(&bytes, topic("b")).unwrap_err();
+        assert!(err.to_string().contains("invalid signature"), "{err}");
+    }
+
+    #[test]
+    fn verify_rejects_wrong_version() {
+        let mut alice = author();
+        let mut bytes =
+            SignedMessage::sign_and_encode(&mut alice, topic("a"), VERSION, &text("hi")).unwrap();
+        for version in [0, 1, MIN_VERSION - 1, VERSION + 1] {
+            bytes[0] = version;
+            let err = SignedMessage::verify(&bytes, topic("a")).unwrap_err();
+            assert!(err.to_string().contains("unsupported"), "{err}");
+        }
+        assert!(SignedMessage::verify(&[], topic("a")).is_err());
+        assert!(
+            SignedMessage::sign_and_encode(&mut alice, topic("a"), VERSION + 1, &text("hi"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn verify_rejects_tampered_data() {
+        let mut alice = author();
+        let mut bytes =
+            SignedMessage::sign_and_encode(&mut alice, topic("a"), VERSION, &text("hi")).unwrap();
+        // the signed data is followed by the signature, flip its last byte.
+        let signed: SignedMessage = postcard::from_bytes(&bytes[1..]).unwrap();
+        let signature = postcard::to_stdvec(&signed.signature).unwrap();
+        let last = bytes.len() - signature.len() - 1;
+        assert_eq!(bytes[last], *signed.data.last().unwrap());
+        bytes[last] ^= 1;
+        assert!(SignedMessage::verify(&bytes, topic("a")).is_err());
+    }
+
+    #[test]
+    fn replay_guard_rejects_stale_and_future_messages() {
+        let from = SecretKey::generate().public();
+        let mut guard = ReplayGuard::default();
+        let max_age = MAX_AGE.as_micros() as u64;
+        let err = guard
+            .check(&from, 1, now() - max_age - 1_000_000, 1)
+            .unwrap_err();
+        assert!(err.to_string().contains("stale"), "{err}");
+        // just inside the window is fine.
+        guard
+            .check(&from, 2, now() - max_age + 1_000_000, 2)
+            .unwrap();
+        let skew = MAX_CLOCK_SKEW.as_micros() as u64;
+        let err = guard
+            .check(&from, 3, now() + skew + 1_000_000, 3)
+            .unwrap_err();
+        assert!(err.to_string().contains("future"), "{err}");
+    }
+
+    #[test]
+    fn replay_guard_rejects_duplicate_uid() {
+        let from = SecretKey::generate().public();
+        let other = SecretKey::generate().public();
+        let mut guard = ReplayGuard::default();
+        guard.check(&from, 7, now(), 1).unwrap();
+        assert!(guard.check(&from, 7, now(), 2).is_err());
+        // the same uid from another author is a different message.
+        guard.check(&other, 7, now(), 1).unwrap();
+    }
+
+    #[test]
+    fn replay_guard_seq_window() {
+        let from = SecretKey::generate().public();
+        let mut guard = ReplayGuard::default();
+        guard.check(&from, 1, now(), 1000).unwrap();
+        // messages may overtake each other within the window.
+        guard.check(&from, 2, now(), 1000 - SEQ_WINDOW + 1).unwrap();
+        let err = guard.check(&from, 3, now(), 1000 - SEQ_WINDOW).unwrap_err();
+        assert!(err.to_string().contains("too old"), "{err}");
+        // the window follows the highest sequence number.
+        guard.check(&from, 4, now(), 2000).unwrap();
+        assert!(guard.check(&from, 5, now(), 1500).is_err());
+    }
+
+    #[test]
+    fn replay_guard_forgets_oldest_uids() {
+        let from = SecretKey::generate().public();
+        let mut guard = ReplayGuard::default();
+        let seq = 1_000_000;
+        for uid in 0..SEEN_CAPACITY as u128 + 1 {
+            guard.check(&from, uid, now(), seq).unwrap();
+        }
+        assert_eq!(guard.seen.len(), SEEN_CAPACITY);
+        assert!(!guard.seen.contains(&(from, 0)));
+        assert!(guard.check(&from, 1, now(), seq).is_err());
+    }
+
+    #[test]
+    fn replay_guard_forgets_quiet_authors() {
+        let mut guard = ReplayGuard::default();
+        // e.g. the one-time keys of sealed messages.
+        let long_ago = now() - MAX_AGE.as_micros() as u64 - 1_000_000;
+        for _ in 0..SEEN_CAPACITY - 1 {
+            let from = SecretKey::generate().public();
+            guard.highest_seq.insert(from, (1, long_ago));
+        }
+        let recent = SecretKey::generate().public();
+        guard.check(&recent, 1, now(), 1000).unwrap();
+        assert_eq!(guard.highest_seq.len(), SEEN_CAPACITY);
+        guard
+            .check(&SecretKey::generate().public(), 2, now(), 1)
+            .unwrap();
+        assert_eq!(guard.highest_seq.len(), 2);
+        // authors that are still active keep their window.
+        assert!(guard.check(&recent, 3, now(), 1000 - SEQ_WINDOW).is_err());
+    }
+
+    fn all_kinds() -> Vec<Message> {
+        let node_id = SecretKey::generate().public();
+        vec![
+            text("hello"),
+            Message::Direct {
+                to: node_id,
+                encrypted: vec![1, 2, 3],
+            },
+            Message::AboutMe {
+                name: "alice".into(),
+                avatar_hash: Some([7; 32]),
+            },
+            Message::Encrypted {
+                key_id: [1; 8],
+                nonce: [2; 24],
+                ciphertext: vec![3; 40],
+            },
+            Message::RoomKey {
+                key_id: [1; 8],
+                wrapped: vec![(node_id, vec![4; 72])],
+            },
+            Message::Sealed {
+                ciphertext: vec![5; 100],
+            },
+            Message::Presence,
+            Message::Ack { uid: 1 },
+            Message::Read { uid: u128::MAX },
+            Message::Edit {
+                target_uid: 2,
+                text: "fixed".into(),
+            },
+            Message::Delete { target_uid: 3 },
+            Message::React {
+                target_uid: 4,
+                emoji: "👍".into(),
+            },
+            Message::Reply {
+                parent_uid: 5,
+                text: "indeed".into(),
+            },
+            Message::Unknown {
+                kind: 1000,
+                bytes: vec![9, 9],
+            },
+        ]
+    }
+
+    #[test]
+    fn message_round_trip() {
+        for (i, message) in all_kinds().into_iter().enumerate() {
+            let bytes = postcard::to_stdvec(&message).unwrap();
+            // the kind comes first, followed by the length prefixed fields.
+            let (kind, fields): (u32, Vec<u8>) = postcard::from_bytes(&bytes).unwrap();
+            assert_eq!(kind, message.kind());
+            assert_eq!(fields, message.encode_fields().unwrap());
+            let decoded: Message = postcard::from_bytes(&bytes).unwrap();
+            assert_eq!(
+                format!("{decoded:?}"),
+                format!("{message:?}"),
+                "message {i}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_kind_decodes_to_unknown() {
+        let fields = postcard::to_stdvec(&("some", 42u64)).unwrap();
+        let bytes = postcard::to_stdvec(&(4711u32, &fields)).unwrap();
+        let decoded: Message = postcard::from_bytes(&bytes).unwrap();
+        match &decoded {
+            Message::Unknown { kind, bytes } => {
+                assert_eq!(*kind, 4711);
+                assert_eq!(bytes, &fields);
+            }
+            other => panic!("expected an unknown message, got {other:?}"),
+        }
+        // unknown messages are sent on unchanged.
+        assert_eq!(postcard::to_stdvec(&decoded).unwrap(), bytes);
+    }
+
+    #[test]
+    fn unknown_kind_in_signed_message() {
+        let mut alice = author();
+        let unknown = Message::Unknown {
+            kind: 99,
+            bytes: vec![1, 2, 3],
+        };
+        let bytes =
+            SignedMessage::sign_and_encode(&mut alice, topic("a"), VERSION, &unknown).unwrap();
+        let message = SignedMessage::verify(&bytes, topic("a")).unwrap();
+        assert!(matches!(message.message, Message::Unknown { kind: 99, .. }));
+    }
+
+    #[test]
+    fn known_kind_with_bad_fields_is_rejected() {
+        // trailing bytes after the fields of a known kind are an error, not an unknown message.
+        let mut fields = postcard::to_stdvec(&"hello").unwrap();
+        fields.push(0);
+        let bytes = postcard::to_stdvec(&(0u32, fields)).unwrap();
+        assert!(postcard::from_bytes::<Message>(&bytes).is_err());
+        let bytes = postcard::to_stdvec(&(7u32, vec![0xffu8])).unwrap();
+        assert!(postcard::from_bytes::<Message>(&bytes).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+//! Direct messages that hide who is talking to whom.
+//!
+//! A [`Message::Direct`] names its recipient in the clear, and is signed by
+//! the node id of its sender, so everyone in the room can see who writes to
+//! whom. A [`Message::Sealed`] instead is signed with a fresh, one-time key,
+//! and encrypted with the shared secret of that key and the node id of the
+//! recipient. Every node tries to open it, and only the recipient succeeds.
+//!
+//! The real sender is inside the sealed box, together with a signature of its
+//! node id over the message, the recipient and the one-time key. So the
+//! recipient knows who wrote, but can not pass the message off as sent to
+//! somebody else. Besides text, a sealed box can carry receipts, so even
+//! those don't reveal who talks to whom.
+use iroh_gossip::proto::TopicId;
+use iroh_net::key::{PublicKey, SecretKey, Signature};
+use serde::{Deserialize, Serialize};
+
+use crate::chat::{Author, Message};
+
+/// Tag for the signature of the real sender.
+const DIRECT_TAG: &[u8] = b"iroh-workshop/chat/direct";
+
+/// The content of a sealed direct message.
+#[derive(Debug, Serialize, Deserialize)]
+struct SealedContent {
+    /// The real sender.
+    from: PublicKey,
+    message: Message,
+    /// Signature of `from` over [`SignedContent`].
+    signature: Signature,
+}
+
+/// What the real sender signs.
+#[derive(Debug, Serialize)]
+struct SignedContent<'a> {
+    tag: &'a [u8],
+    topic: TopicId,
+    /// The one-time key the message is signed with.
+    ephemeral: PublicKey,
+    to: PublicKey,
+    message: &'a Message,
+}
+
+impl SignedContent<'_> {
+    fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(postcard::to_stdvec(self)?)
+    }
+}
+
+/// A direct message from us, to `to`, that only the recipient can open.
+///
+/// Returns the sealed message, and the one-time author it must be signed with.
+pub fn seal(
+    secret_key: &SecretKey,
+    topic: TopicId,
+    to: PublicKey,
+    message: Message,
+) -> anyhow::Result<(Author, Message)> {
+    let ephemeral = SecretKey::generate();
+    let signed = SignedContent {
+        tag: DIRECT_TAG,
+        topic,
+        ephemeral: ephemeral.public(),
+        to,
+        message: &message,
+    };
+    let signature = secret_key.sign(&signed.to_bytes()?);
+    let content = SealedContent {
+        from: secret_key.public(),
+        message,
+        signature,
+    };
+    let mut ciphertext = postcard::to_stdvec(&content)?;
+    ephemeral.shared(&to).seal(&mut ciphertext);
+    Ok((Author::new(ephemeral), Message::Sealed { ciphertext }))
+}
+
+/// Try to open a sealed direct message signed by the one-time key `ephemeral`.
+///
+/// Returns the real sender and the message, or `None` if the message is not for us.
+fn open(
+    secret_key: &SecretKey,
+    topic: TopicId,
+    ephemeral: PublicKey,
+    ciphertext: &[u8],
+) -> anyhow::Result<Option<(PublicKey, Message)>> {
+    let mut buffer = ciphertext.to_vec();
+    if secret_key.shared(&ephemeral).open(&mut buffer).is_err() {
+        // sealed for somebody else
+        return Ok(None);
+    }
+    let SealedContent {
+        from,
+        message,
+        signature,
+    } = postcard::from_bytes(&buffer)?;
+    anyhow::ensure!(
+        !matches!(message, Message::Sealed { .. } | Message::Encrypted { .. }),
+        "nested message in direct message"
+    );
+    let signed = SignedContent {
+        tag: DIRECT_TAG,
+        topic,
+        ephemeral,
+        to: secret_key.public(),
+        message: &message,
+    };
+    from.verify(&signed.to_bytes()?, &signature)
+        .map_err(|_| anyhow::anyhow!("invalid signature of direct message"))?;
+    Ok(Some((from, message)))
+}
+
+/// Open a message if it is a sealed direct message.
+///
+/// Returns the real sender, the message and whether it was sealed, or `None`
+/// if it is sealed for somebody else.
+pub fn unseal(
+    secret_key: &SecretKey,
+    topic: TopicId,
+    from: PublicKey,
+    message: Message,
+) -> anyhow::Result<Option<(PublicKey, Message, bool)>> {
+    match message {
+        Message::Sealed { ciphertext } => Ok(open(secret_key, topic, from, &ciphertext)?
+            .map(|(from, message)| (from, message, true))),
+        message => Ok(Some((from, message, false))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topic(byte: u8) -> TopicId {
+        TopicId::from_bytes([byte; 32])
+    }
+
+    fn text(text: &str) -> Message {
+        Message::Message { text: text.into() }
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let (alice, bob) = (SecretKey::generate(), SecretKey::generate());
+        let (ephemeral, sealed) = seal(&alice, topic(1), bob.public(), text("psst")).unwrap();
+        let ephemeral = ephemeral.secret_key().public();
+        assert_ne!(ephemeral, alice.public());
+        let (from, message, was_sealed) = unseal(&bob, topic(1), ephemeral, sealed)
+            .unwrap()
+            .expect("sealed for bob");
+        assert_eq!(from, alice.public());
+        assert!(was_sealed);
+        assert!(matches!(message, Message::Message { text } if text == "psst"));
+    }
+
+    #[test]
+    fn unsealed_messages_pass_through() {
+        let (alice, bob) = (SecretKey::generate(), SecretKey::generate());
+        let (from, _, was_sealed) = unseal(&bob, topic(1), alice.public(), text("hi"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, alice.public());
+        assert!(!was_sealed);
+    }
+
+    #[test]
+    fn wrong_recipient_can_not_open() {
+        let (alice, bob, carol) = (
+            SecretKey::generate(),
+            SecretKey::generate(),
+            SecretKey::generate(),
+        );
+        let (ephemeral, sealed) = seal(&alice, topic(1), bob.public(), text("psst")).unwrap();
+        let ephemeral = ephemeral.secret_key().public();
+        assert!(unseal(&carol, topic(1), ephemeral, sealed)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn wrong_topic_is_rejected() {
+        let (alice, bob) = (SecretKey::generate(), SecretKey::generate());
+        let (ephemeral, sealed) = seal(&alice, topic(1), bob.public(), text("psst")).unwrap();
+        let ephemeral = ephemeral.secret_key().public();
+        let err = unseal(&bob, topic(2), ephemeral, sealed).unwrap_err();
+        assert!(err.to_string().contains("invalid signature"), "{err}");
+    }
+
+    #[test]
+    fn forwarded_to_someone_else_is_rejected() {
+        let (alice, bob, carol) = (
+            SecretKey::generate(),
+            SecretKey::generate(),
+            SecretKey::generate(),
+        );
+        let (ephemeral, sealed) = seal(&alice, topic(1), bob.public(), text("psst")).unwrap();
+        let Message::Sealed { ciphertext } = sealed else {
+            panic!("expected a sealed message");
+        };
+        // bob opens the box and seals the same content for carol, with the same one-time key.
+        let mut content = ciphertext;
+        bob.shared(&ephemeral.secret_key().public())
+            .open(&mut content)
+            .unwrap();
+        ephemeral
+            .secret_key()
+            .shared(&carol.public())
+            .seal(&mut content);
+        let forwarded = Message::Sealed {
+            ciphertext: content,
+        };
+        let err = unseal(&carol, topic(1), ephemeral.secret_key().public(), forwarded).unwrap_err();
+        assert!(err.to_string().contains("invalid signature"), "{err}");
+    }
+
+    #[test]
+    fn changed_sender_is_rejected() {
+        let (alice, bob, mallory) = (
+            SecretKey::generate(),
+            SecretKey::generate(),
+            SecretKey::generate(),
+        );
+        let (ephemeral, sealed) = seal(&alice, topic(1), bob.public(), text("psst")).unwrap();
+        let Message::Sealed { ciphertext } = sealed else {
+            panic!("expected a sealed message");
+        };
+        let ephemeral = ephemeral.secret_key().clone();
+        let mut content = ciphertext;
+        bob.shared(&ephemeral.public()).open(&mut content).unwrap();
+        let mut content: SealedContent = postcard::from_bytes(&content).unwrap();
+        content.from = mallory.public();
+        let mut ciphertext = postcard::to_stdvec(&content).unwrap();
+        ephemeral.shared(&bob.public()).seal(&mut ciphertext);
+        let err = unseal(
+            &bob,
+            topic(1),
+            ephemeral.public(),
+            Message::Sealed { ciphertext },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid signature"), "{err}");
+    }
+
+    #[test]
+    fn nested_messages_are_rejected() {
+        let (alice, bob) = (SecretKey::generate(), SecretKey::generate());
+        let inner = Message::Sealed {
+            ciphertext: vec![1, 2, 3],
+        };
+        let (ephemeral, sealed) = seal(&alice, topic(1), bob.public(), inner).unwrap();
+        let ephemeral = ephemeral.secret_key().public();
+        assert!(unseal(&bob, topic(1), ephemeral, sealed).is_err());
+    }
+}
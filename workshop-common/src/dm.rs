@@ -0,0 +1,233 @@
+//! Private chats over a direct connection.
+//!
+//! Direct messages sent through a room are flooded to every member, even if
+//! only one of them can read them. `/dm` instead dials the recipient with
+//! [`DM_ALPN`], by node id, so the address is found via discovery. Each
+//! message is sent on its own stream, and the recipient acknowledges it by
+//! replying with its uid. The connection authenticates both sides, so the
+//! messages need no signature.
+//!
+//! Only members of a room we joined may send us direct messages, everyone
+//! else is rejected, see [`SyncAccess::shares_room`].
+//!
+//! If the recipient can not be reached, the caller gets a
+//! [`DmEvent::Failed`] back, and can send the message through a room instead.
+use std::time::Duration;
+
+use iroh_net::{
+    endpoint::{get_remote_node_id, Connection},
+    Endpoint, NodeId,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{acl::REJECTED_CODE, sync::SyncAccess};
+
+/// ALPN of the direct message protocol.
+pub const DM_ALPN: &[u8] = b"iroh-workshop/chat-dm/0";
+/// Maximum size of a message or an acknowledgement.
+const MAX_DM_SIZE: usize = 64 * 1024;
+/// Time to deliver a message, including dialing.
+const DM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A message sent over a direct connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DirectMessage {
+    uid: u128,
+    text: String,
+}
+
+/// Acknowledges a [`DirectMessage`] with the same uid.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DmAck {
+    uid: u128,
+}
+
+/// Something that happened to a direct message.
+#[derive(Debug)]
+pub enum DmEvent {
+    /// A peer sent us a message.
+    Received { from: NodeId, text: String },
+    /// The recipient acknowledged our message.
+    Delivered { to: NodeId },
+    /// Our message could not be delivered directly.
+    Failed {
+        to: NodeId,
+        text: String,
+        cause: anyhow::Error,
+    },
+}
+
+/// Parse a `/dm <node-id> <text>` command typed by the user.
+///
+/// Returns `None` if the line is not a dm command.
+pub fn parse_dm_command(line: &str) -> Option<anyhow::Result<(NodeId, String)>> {
+    let rest = line.strip_prefix("/dm ")?;
+    let parsed = match rest.trim_start().split_once(' ') {
+        Some((to, text)) if !text.trim().is_empty() => to
+            .parse()
+            .map(|to| (to, text.to_string()))
+            .map_err(|_| anyhow::anyhow!("invalid node id {to}")),
+        _ => Err(anyhow::anyhow!("usage: /dm <node-id> <text>")),
+    };
+    Some(parsed)
+}
+
+/// Send a message to `to` over a direct connection, in the background.
+///
+/// The outcome is reported to `tx` as [`DmEvent::Delivered`] or [`DmEvent::Failed`].
+pub fn spawn_send_dm(endpoint: Endpoint, to: NodeId, text: String, tx: mpsc::Sender<DmEvent>) {
+    tokio::spawn(async move {
+        let uid = rand::thread_rng().gen();
+        let message = DirectMessage {
+            uid,
+            text: text.clone(),
+        };
+        let res = tokio::time::timeout(DM_TIMEOUT, send_dm(&endpoint, to, &message))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("timeout")));
+        let event = match res {
+            Ok(()) => DmEvent::Delivered { to },
+            Err(cause) => DmEvent::Failed { to, text, cause },
+        };
+        tx.send(event).await.ok();
+    });
+}
+
+async fn send_dm(endpoint: &Endpoint, to: NodeId, message: &DirectMessage) -> anyhow::Result<()> {
+    let connection = endpoint.connect_by_node_id(to, DM_ALPN).await?;
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.write_all(&postcard::to_stdvec(message)?).await?;
+    send.finish()?;
+    let ack = recv.read_to_end(MAX_DM_SIZE).await?;
+    let ack: DmAck = postcard::from_bytes(&ack)?;
+    connection.close(0u32.into(), b"done");
+    anyhow::ensure!(ack.uid == message.uid, "acknowledged the wrong message");
+    Ok(())
+}
+
+/// Receive the messages of a node that connected with [`DM_ALPN`].
+///
+/// The node must share a room with us according to `access`. Every message
+/// is acknowledged once it was passed on to `tx`.
+pub async fn serve_dm(
+    connection: Connection,
+    access: &SyncAccess,
+    tx: mpsc::Sender<DmEvent>,
+) -> anyhow::Result<()> {
+    let from = get_remote_node_id(&connection)?;
+    if !access.wait_shares_room(&from).await {
+        connection.close(REJECTED_CODE.into(), b"not a member");
+        anyhow::bail!("{from} is not a member of any of our rooms");
+    }
+    // the sender closes the connection when it is done
+    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+        let message = recv.read_to_end(MAX_DM_SIZE).await?;
+        let DirectMessage { uid, text } = postcard::from_bytes(&message)?;
+        tx.send(DmEvent::Received { from, text }).await?;
+        send.write_all(&postcard::to_stdvec(&DmAck { uid })?)
+            .await?;
+        send.finish()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use iroh_gossip::proto::TopicId;
+    use iroh_net::{key::SecretKey, relay::RelayMode, NodeAddr};
+
+    use super::*;
+
+    #[test]
+    fn parse_dm_commands() {
+        let to = SecretKey::generate().public();
+        let (node_id, text) = parse_dm_command(&format!("/dm {to} hello  there"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(node_id, to);
+        assert_eq!(text, "hello  there");
+        // not a dm command
+        assert!(parse_dm_command("hello").is_none());
+        assert!(parse_dm_command("/dmx").is_none());
+        // missing text
+        assert!(parse_dm_command(&format!("/dm {to}")).unwrap().is_err());
+        assert!(parse_dm_command(&format!("/dm {to}  ")).unwrap().is_err());
+        // invalid node id
+        assert!(parse_dm_command("/dm bob hello").unwrap().is_err());
+    }
+
+    async fn bind() -> anyhow::Result<Endpoint> {
+        Endpoint::builder()
+            .secret_key(SecretKey::generate())
+            .alpns(vec![DM_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+    }
+
+    fn local_addr(endpoint: &Endpoint) -> NodeAddr {
+        let port = endpoint.bound_sockets().0.port();
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        NodeAddr::new(endpoint.node_id()).with_direct_addresses([addr])
+    }
+
+    /// Send `text` from `sender` to `receiver`, which serves a single connection.
+    ///
+    /// Returns the outcome for both sides, and what the receiver passed on.
+    async fn send_to(
+        sender: &Endpoint,
+        receiver: &Endpoint,
+        access: SyncAccess,
+        text: &str,
+    ) -> (anyhow::Result<()>, anyhow::Result<()>, Option<DmEvent>) {
+        sender.add_node_addr(local_addr(receiver)).unwrap();
+        let (tx, mut rx) = mpsc::channel(1);
+        let serve = tokio::spawn({
+            let receiver = receiver.clone();
+            async move {
+                let connection = receiver.accept().await.expect("open").await?;
+                serve_dm(connection, &access, tx).await
+            }
+        });
+        let message = DirectMessage {
+            uid: 1,
+            text: text.to_string(),
+        };
+        let sent = send_dm(sender, receiver.node_id(), &message).await;
+        let served = serve.await.expect("no panic");
+        (sent, served, rx.try_recv().ok())
+    }
+
+    #[tokio::test]
+    async fn members_can_send_direct_messages() -> anyhow::Result<()> {
+        let (alice, bob) = (bind().await?, bind().await?);
+        let access = SyncAccess::default();
+        access.set_members(TopicId::from_bytes([1; 32]), [alice.node_id()]);
+        let (sent, served, event) = send_to(&alice, &bob, access, "hi bob").await;
+        sent?;
+        served?;
+        let Some(DmEvent::Received { from, text }) = event else {
+            panic!("expected a received message, got {event:?}");
+        };
+        assert_eq!(from, alice.node_id());
+        assert_eq!(text, "hi bob");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn strangers_are_rejected() -> anyhow::Result<()> {
+        let (mallory, bob) = (bind().await?, bind().await?);
+        let access = SyncAccess::default();
+        let someone = SecretKey::generate().public();
+        access.set_members(TopicId::from_bytes([1; 32]), [someone]);
+        let (sent, served, event) = send_to(&mallory, &bob, access, "hi bob").await;
+        assert!(sent.is_err());
+        assert!(served.is_err());
+        assert!(event.is_none());
+        Ok(())
+    }
+}
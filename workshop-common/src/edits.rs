@@ -0,0 +1,488 @@
+//! Edits, deletes and reactions.
+//!
+//! [`Message::Edit`], [`Message::Delete`] and [`Message::React`] refer to an
+//! earlier chat message of the room by its uid. Edits and deletes are only
+//! honored if they are signed by the author of that message, reactions may
+//! come from anyone. The message must be in the local history: a delete
+//! removes it from there for good, edits and reactions are stored next to it
+//! and applied when the history is shown.
+//!
+//! Users refer to a message by its [`short_ref`], which is shown in front of
+//! it, or leave it out to mean the last message.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use iroh_gossip::proto::TopicId;
+use iroh_net::key::{PublicKey, SecretKey};
+
+use crate::{
+    chat::{Message, SignedMessage},
+    history::History,
+    receipts::preview,
+    room_key::RoomKeys,
+};
+
+/// Number of leading bits of the uid in a [`short_ref`].
+const REF_BITS: u32 = 24;
+/// Number of messages searched for the last message.
+const SEARCH_COUNT: usize = 100;
+/// Maximum length of a reaction in bytes.
+const MAX_EMOJI_LEN: usize = 32;
+
+/// A short reference to a message, e.g. `#3f2a1c`.
+pub fn short_ref(uid: u128) -> String {
+    format!("#{:06x}", uid >> (128 - REF_BITS))
+}
+
+/// Parse a reference typed by the user, returning the range of matching uids.
+pub(crate) fn parse_ref(text: &str) -> Option<std::ops::RangeInclusive<u128>> {
+    let digits = text.strip_prefix('#')?;
+    if digits.len() != 6 {
+        return None;
+    }
+    let prefix = u128::from_str_radix(digits, 16).ok()?;
+    let start = prefix << (128 - REF_BITS);
+    Some(start..=start | (u128::MAX >> REF_BITS))
+}
+
+/// Check a reaction claimed by a peer or typed by the user, and trim it.
+pub fn check_emoji(emoji: &str) -> anyhow::Result<String> {
+    let emoji = emoji.trim();
+    anyhow::ensure!(!emoji.is_empty(), "reaction must not be empty");
+    anyhow::ensure!(
+        emoji.len() <= MAX_EMOJI_LEN,
+        "reaction must be at most {MAX_EMOJI_LEN} bytes"
+    );
+    anyhow::ensure!(
+        !emoji.chars().any(|c| c.is_control() || c.is_whitespace()),
+        "reaction must be a single word"
+    );
+    Ok(emoji.to_string())
+}
+
+/// An edit, delete or reaction typed by the user.
+#[derive(Debug, Clone)]
+pub enum EditCommand {
+    Edit {
+        target: Option<String>,
+        text: String,
+    },
+    Delete {
+        target: Option<String>,
+    },
+    React {
+        target: Option<String>,
+        emoji: String,
+    },
+}
+
+/// Parse a `/edit [#ref] <text>`, `/delete [#ref]` or `/react [#ref] <emoji>` command.
+///
+/// Returns `None` if the line is not one of these commands.
+pub fn parse_edit_command(line: &str) -> Option<anyhow::Result<EditCommand>> {
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+    // the reference is optional, so only take the first word if it looks like one
+    let (target, rest) = match rest.split_once(' ').unwrap_or((rest, "")) {
+        (first, rest) if parse_ref(first).is_some() => (Some(first.to_string()), rest.trim()),
+        _ => (None, rest),
+    };
+    let parsed = match command {
+        "/edit" if !rest.is_empty() => Ok(EditCommand::Edit {
+            target,
+            text: rest.to_string(),
+        }),
+        "/edit" => Err(anyhow::anyhow!("usage: /edit [#ref] <text>")),
+        "/delete" if rest.is_empty() => Ok(EditCommand::Delete { target }),
+        "/delete" => Err(anyhow::anyhow!("usage: /delete [#ref]")),
+        "/react" => check_emoji(rest)
+            .map(|emoji| EditCommand::React { target, emoji })
+            .map_err(|cause| anyhow::anyhow!("usage: /react [#ref] <emoji> ({cause})")),
+        _ => return None,
+    };
+    Some(parsed)
+}
+
+/// A chat message from the history that can be edited or reacted to.
+#[derive(Debug, Clone)]
+pub(crate) struct Original {
+    pub(crate) from: PublicKey,
+    pub(crate) text: String,
+}
+
+/// Read a chat message or reply of a room from the history.
+///
+/// Direct messages and other kinds of messages are not found.
+pub(crate) fn load(
+    history: &History,
+    topic: TopicId,
+    keys: &mut RoomKeys,
+    secret_key: &SecretKey,
+    uid: u128,
+) -> anyhow::Result<Option<Original>> {
+    let Some(bytes) = history.get(topic, uid)? else {
+        return Ok(None);
+    };
+    let message = SignedMessage::verify(&bytes, topic)?;
+    let original = match keys.open(
+        topic,
+        secret_key,
+        message.from,
+        (message.timestamp, message.uid),
+        message.message,
+        false,
+    )? {
+        Message::Message { text } | Message::Reply { text, .. } => Some(Original {
+            from: message.from,
+            text,
+        }),
+        _ => None,
+    };
+    Ok(original)
+}
+
+/// Find the message a command refers to.
+///
+/// Without a reference, this is the last message of the room by us if `mine`
+/// is true, or by somebody else otherwise.
+pub(crate) fn find(
+    history: &History,
+    topic: TopicId,
+    keys: &mut RoomKeys,
+    secret_key: &SecretKey,
+    target: Option<&str>,
+    mine: bool,
+) -> anyhow::Result<u128> {
+    if let Some(target) = target {
+        let range = parse_ref(target).ok_or_else(|| anyhow::anyhow!("invalid ref {target}"))?;
+        let mut found = Vec::new();
+        for uid in history.uids(topic, range)? {
+            if load(history, topic, keys, secret_key, uid)?.is_some() {
+                found.push(uid);
+            }
+        }
+        return match found[..] {
+            [uid] => Ok(uid),
+            [] => Err(anyhow::anyhow!("no message {target} in this room")),
+            _ => Err(anyhow::anyhow!("{target} matches several messages")),
+        };
+    }
+    for bytes in history.last(topic, SEARCH_COUNT)?.iter().rev() {
+        let Ok(message) = SignedMessage::verify(bytes, topic) else {
+            continue;
+        };
+        if (message.from == secret_key.public()) != mine {
+            continue;
+        }
+        if let Ok(Message::Message { .. } | Message::Reply { .. }) = keys.open(
+            topic,
+            secret_key,
+            message.from,
+            (message.timestamp, message.uid),
+            message.message,
+            false,
+        ) {
+            return Ok(message.uid);
+        }
+    }
+    if mine {
+        anyhow::bail!("you have not written anything in this room yet");
+    }
+    anyhow::bail!("nobody has written anything in this room yet")
+}
+
+/// Turn a command into the message to send to the room.
+pub fn prepare(
+    command: EditCommand,
+    history: &History,
+    topic: TopicId,
+    keys: &mut RoomKeys,
+    secret_key: &SecretKey,
+) -> anyhow::Result<Message> {
+    let message = match command {
+        EditCommand::Edit { target, text } => {
+            let target_uid = find(history, topic, keys, secret_key, target.as_deref(), true)?;
+            own(history, topic, keys, secret_key, target_uid)?;
+            Message::Edit { target_uid, text }
+        }
+        EditCommand::Delete { target } => {
+            let target_uid = find(history, topic, keys, secret_key, target.as_deref(), true)?;
+            own(history, topic, keys, secret_key, target_uid)?;
+            Message::Delete { target_uid }
+        }
+        EditCommand::React { target, emoji } => {
+            let target_uid = find(history, topic, keys, secret_key, target.as_deref(), false)?;
+            Message::React { target_uid, emoji }
+        }
+    };
+    Ok(message)
+}
+
+/// Make sure we wrote a message, since others would ignore our edit.
+fn own(
+    history: &History,
+    topic: TopicId,
+    keys: &mut RoomKeys,
+    secret_key: &SecretKey,
+    uid: u128,
+) -> anyhow::Result<()> {
+    let original = load(history, topic, keys, secret_key, uid)?;
+    anyhow::ensure!(
+        original.is_some_and(|original| original.from == secret_key.public()),
+        "you can only change your own messages"
+    );
+    Ok(())
+}
+
+/// What an edit, delete or reaction did.
+#[derive(Debug, Clone)]
+pub enum Applied {
+    Edited {
+        target_uid: u128,
+        text: String,
+    },
+    Deleted {
+        target_uid: u128,
+        /// The text of the deleted message.
+        text: String,
+    },
+    Reacted {
+        target_uid: u128,
+        emoji: String,
+        /// The text of the message reacted to.
+        text: String,
+    },
+}
+
+impl Applied {
+    /// Describe what `author` did, e.g. `alice (abcd) reacted 👍 to #3f2a1c "hi"`.
+    pub fn describe(&self, author: &str) -> String {
+        match self {
+            Applied::Edited { target_uid, text } => {
+                format!("{author} edited {}: {text}", short_ref(*target_uid))
+            }
+            Applied::Deleted { target_uid, text } => format!(
+                "{author} deleted {} \"{}\"",
+                short_ref(*target_uid),
+                preview(text)
+            ),
+            Applied::Reacted {
+                target_uid,
+                emoji,
+                text,
+            } => format!(
+                "{author} reacted {emoji} to {} \"{}\"",
+                short_ref(*target_uid),
+                preview(text)
+            ),
+        }
+    }
+}
+
+/// Apply an edit, delete or reaction from `from` to the history of a room.
+///
+/// Returns `None` for other messages.
+pub fn apply(
+    history: &History,
+    topic: TopicId,
+    keys: &mut RoomKeys,
+    secret_key: &SecretKey,
+    from: PublicKey,
+    message: &Message,
+) -> anyhow::Result<Option<Applied>> {
+    let target_uid = match message {
+        Message::Edit { target_uid, .. }
+        | Message::Delete { target_uid }
+        | Message::React { target_uid, .. } => *target_uid,
+        _ => return Ok(None),
+    };
+    let original = load(history, topic, keys, secret_key, target_uid)?;
+    if original.is_none() && matches!(message, Message::Delete { .. }) {
+        // the message may still arrive, e.g. from the history of a neighbor
+        history.delete(topic, target_uid, from)?;
+    }
+    let Some(original) = original else {
+        anyhow::bail!("unknown message {}", short_ref(target_uid));
+    };
+    let author = original.from == from;
+    let applied = match message {
+        Message::Edit { text, .. } => {
+            anyhow::ensure!(author, "edit of a message by somebody else");
+            anyhow::ensure!(!text.trim().is_empty(), "empty edit");
+            Applied::Edited {
+                target_uid,
+                text: text.clone(),
+            }
+        }
+        Message::Delete { .. } => {
+            anyhow::ensure!(author, "delete of a message by somebody else");
+            history.delete(topic, target_uid, from)?;
+            Applied::Deleted {
+                target_uid,
+                text: original.text,
+            }
+        }
+        Message::React { emoji, .. } => Applied::Reacted {
+            target_uid,
+            emoji: check_emoji(emoji)?,
+            text: original.text,
+        },
+        _ => unreachable!("checked above"),
+    };
+    Ok(Some(applied))
+}
+
+/// The edits and reactions of the messages shown from the history.
+///
+/// Add the messages oldest first, then [`Edits::show`] each chat message.
+#[derive(Debug, Default)]
+pub struct Edits {
+    authors: HashMap<u128, PublicKey>,
+    texts: HashMap<u128, String>,
+    reactions: HashMap<u128, BTreeMap<String, BTreeSet<PublicKey>>>,
+}
+
+impl Edits {
+    /// Add a message from the history.
+    pub fn add(&mut self, from: PublicKey, uid: u128, message: &Message) {
+        match message {
+            // the first message with a uid is the one in the history
+            Message::Message { .. } | Message::Reply { .. } => {
+                self.authors.entry(uid).or_insert(from);
+            }
+            // only the author may edit
+            Message::Edit { target_uid, text } if self.authors.get(target_uid) == Some(&from) => {
+                self.texts.insert(*target_uid, text.clone());
+            }
+            Message::React { target_uid, emoji } if self.authors.contains_key(target_uid) => {
+                if let Ok(emoji) = check_emoji(emoji) {
+                    let reactions = self.reactions.entry(*target_uid).or_default();
+                    reactions.entry(emoji).or_default().insert(from);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The text of a chat message with its edits and reactions applied.
+    pub fn show(&self, uid: u128, text: String) -> String {
+        let mut shown = match self.texts.get(&uid) {
+            Some(edited) => format!("{edited} (edited)"),
+            None => text,
+        };
+        if let Some(reactions) = self.reactions.get(&uid) {
+            let reactions: Vec<_> = reactions
+                .iter()
+                .map(|(emoji, from)| format!("{emoji} {}", from.len()))
+                .collect();
+            shown.push_str(&format!(" [{}]", reactions.join(", ")));
+        }
+        shown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{self, Author, VERSION};
+
+    fn text(text: &str) -> Message {
+        Message::Message { text: text.into() }
+    }
+
+    fn edit(target_uid: u128, text: &str) -> Message {
+        Message::Edit {
+            target_uid,
+            text: text.into(),
+        }
+    }
+
+    #[test]
+    fn only_the_author_edits() {
+        let (alice, bob) = (
+            SecretKey::generate().public(),
+            SecretKey::generate().public(),
+        );
+        let mut edits = Edits::default();
+        edits.add(alice, 1, &text("hi"));
+        edits.add(bob, 2, &edit(1, "bye"));
+        assert_eq!(edits.show(1, "hi".into()), "hi");
+        edits.add(alice, 3, &edit(1, "hello"));
+        assert_eq!(edits.show(1, "hi".into()), "hello (edited)");
+    }
+
+    #[test]
+    fn delete_before_the_message_arrives() {
+        let topic = TopicId::from_bytes([1; 32]);
+        let history = History::in_memory().unwrap();
+        let mut keys = RoomKeys::default();
+        let (me, alice) = (SecretKey::generate(), SecretKey::generate());
+        let mut author = Author::new(alice.clone());
+        let signed =
+            SignedMessage::sign_at(&mut author, topic, VERSION, 7, chat::now(), &text("oops"))
+                .unwrap();
+        let delete = Message::Delete { target_uid: 7 };
+        let err = apply(&history, topic, &mut keys, &me, alice.public(), &delete).unwrap_err();
+        assert!(err.to_string().contains("unknown message"), "{err}");
+        // the deleted message is not stored when it arrives late.
+        assert!(!history.insert(topic, &signed).unwrap());
+        assert!(history.get(topic, 7).unwrap().is_none());
+    }
+
+    #[test]
+    fn colliding_uid_does_not_take_over_message() {
+        let (alice, mallory) = (
+            SecretKey::generate().public(),
+            SecretKey::generate().public(),
+        );
+        let mut edits = Edits::default();
+        edits.add(alice, 1, &text("hi"));
+        edits.add(mallory, 1, &text("evil"));
+        edits.add(mallory, 2, &edit(1, "owned"));
+        assert_eq!(edits.show(1, "hi".into()), "hi");
+    }
+
+    #[test]
+    fn reactions_are_counted_per_emoji() {
+        let (alice, bob) = (
+            SecretKey::generate().public(),
+            SecretKey::generate().public(),
+        );
+        let mut edits = Edits::default();
+        edits.add(alice, 1, &text("hi"));
+        for from in [alice, bob, bob] {
+            let react = Message::React {
+                target_uid: 1,
+                emoji: "👍".into(),
+            };
+            edits.add(from, 2, &react);
+        }
+        // reactions to unknown messages are ignored
+        let react = Message::React {
+            target_uid: 9,
+            emoji: "👍".into(),
+        };
+        edits.add(bob, 3, &react);
+        assert_eq!(edits.show(1, "hi".into()), "hi [👍 2]");
+        assert_eq!(edits.show(9, "ho".into()), "ho");
+    }
+
+    #[test]
+    fn parse_commands() {
+        let command = parse_edit_command("/edit #3f2a1c new text")
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            command,
+            EditCommand::Edit { target: Some(target), text } if target == "#3f2a1c" && text == "new text"
+        ));
+        let command = parse_edit_command("/edit #nope text").unwrap().unwrap();
+        assert!(
+            matches!(command, EditCommand::Edit { target: None, text } if text == "#nope text")
+        );
+        assert!(parse_edit_command("/delete #3f2a1c extra")
+            .unwrap()
+            .is_err());
+        assert!(parse_edit_command("/react two words").unwrap().is_err());
+        assert!(parse_edit_command("/history").is_none());
+    }
+}
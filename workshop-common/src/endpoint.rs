@@ -0,0 +1,211 @@
+//! Endpoint setup shared by the examples.
+use iroh_net::{
+    discovery::{
+        dns::DnsDiscovery,
+        local_swarm_discovery::LocalSwarmDiscovery,
+        pkarr::{dht::DhtDiscovery, PkarrPublisher},
+        ConcurrentDiscovery,
+    },
+    key::SecretKey,
+    relay::RelayMode,
+    Endpoint,
+};
+
+use crate::{
+    network::{NetworkArgs, NetworkConfig},
+    secret::SecretSource,
+};
+
+/// Which node discovery mechanism an endpoint uses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Discovery {
+    /// No discovery. Tickets need to contain the full address information.
+    #[default]
+    None,
+    /// Resolve node ids using DNS, by default using the n0 DNS server.
+    ///
+    /// If `publish` is set, our own address is also published to the pkarr relay
+    /// of the DNS server.
+    Dns { publish: bool },
+    /// Resolve node ids using the Mainline DHT.
+    ///
+    /// If `publish` is set, our own address is also published to the DHT,
+    /// including our direct addresses if `direct_addresses` is set.
+    Dht {
+        publish: bool,
+        direct_addresses: bool,
+    },
+    /// Announce our direct addresses on the local network, and find other nodes there.
+    ///
+    /// This works without internet access, so relays are not used either.
+    Local,
+}
+
+impl Discovery {
+    /// Create the discovery service for this choice.
+    ///
+    /// The DNS server, pkarr relay and DHT bootstrap nodes are taken from `network`.
+    pub fn build(
+        &self,
+        secret_key: &SecretKey,
+        network: &NetworkConfig,
+    ) -> anyhow::Result<Option<Box<dyn iroh_net::discovery::Discovery>>> {
+        let discovery: Box<dyn iroh_net::discovery::Discovery> = match *self {
+            Self::None => return Ok(None),
+            Self::Dns { publish } => {
+                let dns = DnsDiscovery::new(network.dns_origin().to_string());
+                if publish {
+                    let publisher =
+                        PkarrPublisher::new(secret_key.clone(), network.dns_pkarr_relay()?);
+                    Box::new(ConcurrentDiscovery::from_services(vec![
+                        Box::new(dns),
+                        Box::new(publisher),
+                    ]))
+                } else {
+                    Box::new(dns)
+                }
+            }
+            Self::Dht {
+                publish,
+                direct_addresses,
+            } => {
+                let mut builder = DhtDiscovery::builder();
+                if let Some(client) = network.pkarr_client()? {
+                    builder = builder.client(client);
+                }
+                if let Some(url) = &network.pkarr_relay {
+                    builder = builder.pkarr_relay(url.clone());
+                }
+                if publish {
+                    builder = builder
+                        .secret_key(secret_key.clone())
+                        .include_direct_addresses(direct_addresses);
+                }
+                Box::new(builder.build()?)
+            }
+            Self::Local => Box::new(LocalSwarmDiscovery::new(secret_key.public())?),
+        };
+        Ok(Some(discovery))
+    }
+
+    /// True if nodes using this discovery need a relay to be reachable.
+    pub fn uses_relay(&self) -> bool {
+        *self != Self::Local
+    }
+}
+
+/// Choice of the discovery mechanism on the command line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DiscoveryMode {
+    /// The discovery mechanism of the example.
+    #[default]
+    Default,
+    /// Find nodes on the local network, without relays or internet access.
+    Local,
+}
+
+/// Command line options to select the discovery mechanism.
+///
+/// Flatten this into the arguments of a binary with `#[clap(flatten)]`.
+#[derive(Debug, Clone, clap::Args)]
+#[command(about = None, long_about = None)]
+pub struct DiscoveryArgs {
+    /// How to find other nodes.
+    #[clap(long, global = true, value_enum, default_value_t)]
+    pub discovery: DiscoveryMode,
+    #[clap(flatten)]
+    pub network: NetworkArgs,
+}
+
+impl DiscoveryArgs {
+    /// True if only the local network should be used.
+    pub fn is_local(&self) -> bool {
+        self.discovery == DiscoveryMode::Local
+    }
+
+    /// The discovery to use, given the default of the example.
+    pub fn resolve(&self, default: Discovery) -> Discovery {
+        match self.discovery {
+            DiscoveryMode::Default => default,
+            DiscoveryMode::Local => Discovery::Local,
+        }
+    }
+
+    /// The infrastructure to use, from the config file and the command line.
+    pub fn network(&self) -> anyhow::Result<NetworkConfig> {
+        self.network.load()
+    }
+}
+
+/// Builder for an [`Endpoint`] with a secret source, a discovery choice, the
+/// infrastructure to use and a set of ALPNs.
+#[derive(Debug, Default)]
+pub struct EndpointBuilder {
+    secret: SecretSource,
+    discovery: Discovery,
+    network: NetworkConfig,
+    alpns: Vec<Vec<u8>>,
+}
+
+impl EndpointBuilder {
+    /// Create a new builder with the default settings.
+    ///
+    /// The default is to use a fresh secret key, no discovery, the n0
+    /// infrastructure and no ALPNs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set where the secret key comes from.
+    pub fn secret(mut self, secret: SecretSource) -> Self {
+        self.secret = secret;
+        self
+    }
+
+    /// Set the node discovery mechanism.
+    pub fn discovery(mut self, discovery: Discovery) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Set the relays, discovery servers and DHT bootstrap nodes to use.
+    pub fn network(mut self, network: NetworkConfig) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Set the ALPNs we accept incoming connections for.
+    pub fn alpns(mut self, alpns: Vec<Vec<u8>>) -> Self {
+        self.alpns = alpns;
+        self
+    }
+
+    /// Create the endpoint.
+    ///
+    /// We bind to port 0 to let the OS choose a random port.
+    pub async fn bind(self) -> anyhow::Result<Endpoint> {
+        let secret_key = self.secret.load()?;
+        let mut builder = Endpoint::builder().alpns(self.alpns);
+        if !self.discovery.uses_relay() {
+            builder = builder.relay_mode(RelayMode::Disabled);
+        } else if let Some(relay_mode) = self.network.relay_mode()? {
+            builder = builder.relay_mode(relay_mode);
+        }
+        if let Some(resolver) = self.network.dns_resolver() {
+            builder = builder.dns_resolver(resolver);
+        }
+        if let Some(discovery) = self.discovery.build(&secret_key, &self.network)? {
+            builder = builder.discovery(discovery);
+        }
+        let endpoint = builder.secret_key(secret_key).bind().await?;
+        Ok(endpoint)
+    }
+}
+
+/// Wait for the endpoint to figure out its relay address.
+pub async fn wait_for_relay(endpoint: &Endpoint) -> anyhow::Result<()> {
+    while endpoint.home_relay().is_none() {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
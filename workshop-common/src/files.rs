@@ -0,0 +1,377 @@
+//! Sending files over a pipe connection.
+//!
+//! The sending side opens a stream and writes the
+//! [`HANDSHAKE`](crate::io::HANDSHAKE), a length prefixed postcard encoded
+//! [`FileHeader`] and the content of the file. The receiving side writes the
+//! content to a temporary file, checks the BLAKE3 hash and replies with a
+//! [`Receipt`]. Only if the hash matches is the file moved to its final name.
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use iroh_net::endpoint::{Connection, RecvStream};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::io::{recv_handshake, send_handshake};
+
+/// Maximum size of an encoded header or receipt.
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+/// Size of the chunks in which files are read and written.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Time the sender has to open the stream and send the header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time the sender has to pick up the receipt.
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Minimum time between two progress updates.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Sent before the content of a file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileHeader {
+    /// File name, without any directories.
+    pub name: String,
+    /// Size of the content in bytes.
+    pub len: u64,
+    /// BLAKE3 hash of the content.
+    pub hash: [u8; 32],
+}
+
+/// Sent by the receiving side once the file is stored, or failed to be stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Receipt {
+    /// The file was received completely and the hash matched.
+    Ok,
+    /// The file was not stored, for the given reason.
+    Failed(String),
+}
+
+/// Send the file at `path` over a new stream on `connection`.
+pub async fn send_file(connection: &Connection, path: &Path) -> anyhow::Result<()> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("{} has no valid file name", path.display()))?
+        .to_string();
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .with_context(|| format!("unable to read {}", path.display()))?;
+    anyhow::ensure!(metadata.is_file(), "{} is not a file", path.display());
+    let len = metadata.len();
+    // hash the file first, so the receiver can check the content as it comes in.
+    let mut progress = Progress::new(format!("hashing {name}"), len);
+    let mut file = tokio::fs::File::open(path).await?;
+    let hash = copy_and_hash(&mut file, &mut tokio::io::sink(), len, &mut progress).await?;
+    progress.finish();
+    let header = FileHeader {
+        name: name.clone(),
+        len,
+        hash: *hash.as_bytes(),
+    };
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send_handshake(&mut send).await?;
+    let encoded = postcard::to_stdvec(&header)?;
+    send.write_all(&(encoded.len() as u32).to_be_bytes())
+        .await?;
+    send.write_all(&encoded).await?;
+    let mut progress = Progress::new(format!("sending {name}"), len);
+    let mut file = tokio::fs::File::open(path).await?;
+    let sent_hash = match copy_and_hash(&mut file, &mut send, len, &mut progress).await {
+        Ok(hash) => hash,
+        Err(cause) => {
+            // the receiver stops the stream if it rejects the file, the receipt tells why.
+            progress.finish();
+            if let Ok(Receipt::Failed(reason)) = read_receipt(&mut recv).await {
+                anyhow::bail!("remote failed to receive {name}: {reason}");
+            }
+            return Err(cause);
+        }
+    };
+    progress.finish();
+    anyhow::ensure!(
+        sent_hash == hash,
+        "{} changed while sending",
+        path.display()
+    );
+    send.finish()?;
+    match read_receipt(&mut recv).await? {
+        Receipt::Ok => {
+            eprintln!("Sent {name} ({len} bytes, blake3 {hash})");
+            Ok(())
+        }
+        Receipt::Failed(reason) => anyhow::bail!("remote failed to receive {name}: {reason}"),
+    }
+}
+
+/// Receive a file sent with [`send_file`] on `connection` and store it in `dir`.
+///
+/// Returns the path of the stored file.
+pub async fn receive_file(connection: &Connection, dir: &Path) -> anyhow::Result<PathBuf> {
+    let accept = async {
+        let (send, mut recv) = connection.accept_bi().await?;
+        recv_handshake(&mut recv).await?;
+        let header = read_header(&mut recv).await?;
+        anyhow::Ok((send, recv, header))
+    };
+    let (mut send, mut recv, header) = tokio::time::timeout(HEADER_TIMEOUT, accept)
+        .await
+        .context("timeout waiting for the file header")??;
+    let res = store_file(&mut recv, dir, &header).await;
+    let receipt = match &res {
+        Ok(_) => Receipt::Ok,
+        Err(cause) => {
+            // no need for the sender to send the rest of the file.
+            recv.stop(0u32.into()).ok();
+            Receipt::Failed(format!("{cause:#}"))
+        }
+    };
+    send.write_all(&postcard::to_stdvec(&receipt)?).await?;
+    send.finish()?;
+    // the sender closes the connection once it has the receipt.
+    tokio::time::timeout(RECEIPT_TIMEOUT, connection.closed())
+        .await
+        .ok();
+    res
+}
+
+async fn read_receipt(recv: &mut RecvStream) -> anyhow::Result<Receipt> {
+    let receipt = recv.read_to_end(MAX_HEADER_SIZE).await?;
+    Ok(postcard::from_bytes(&receipt)?)
+}
+
+async fn read_header(recv: &mut RecvStream) -> anyhow::Result<FileHeader> {
+    let mut len = [0u8; 4];
+    recv.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    anyhow::ensure!(len <= MAX_HEADER_SIZE, "header too large: {len} bytes");
+    let mut buf = vec![0u8; len];
+    recv.read_exact(&mut buf).await?;
+    Ok(postcard::from_bytes(&buf)?)
+}
+
+/// Write the content to a temporary file in `dir`, and move it into place if the hash matches.
+///
+/// The target is created right away, so an existing file is never replaced,
+/// and removed again if the content does not arrive.
+async fn store_file(
+    recv: &mut (impl AsyncRead + Unpin),
+    dir: &Path,
+    header: &FileHeader,
+) -> anyhow::Result<PathBuf> {
+    // the name comes from the remote, so make sure it can not escape `dir`.
+    let name = Path::new(&header.name);
+    anyhow::ensure!(
+        name.file_name() == Some(name.as_os_str()) && !header.name.starts_with('.'),
+        "invalid file name {:?}",
+        header.name
+    );
+    let target = dir.join(name);
+    tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&target)
+        .await
+        .with_context(|| format!("unable to create {}", target.display()))?;
+    // never truncate a file we did not create, e.g. of a transfer that is still running.
+    let temp = dir.join(format!(".{}.part", header.name));
+    let created = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp)
+        .await;
+    let mut file = match created {
+        Ok(file) => file,
+        Err(cause) => {
+            tokio::fs::remove_file(&target).await.ok();
+            return Err(cause).with_context(|| format!("unable to create {}", temp.display()));
+        }
+    };
+    let mut progress = Progress::new(format!("receiving {}", header.name), header.len);
+    let res = async {
+        let hash = copy_and_hash(
+            &mut recv.take(header.len),
+            &mut file,
+            header.len,
+            &mut progress,
+        )
+        .await?;
+        anyhow::ensure!(
+            hash.as_bytes() == &header.hash,
+            "hash mismatch, expected {} but got {}",
+            blake3::Hash::from(header.hash),
+            hash
+        );
+        file.sync_all().await?;
+        Ok(hash)
+    };
+    match res.await {
+        Ok(hash) => {
+            progress.finish();
+            // only replaces the empty file we created above.
+            if let Err(cause) = tokio::fs::rename(&temp, &target).await {
+                tokio::fs::remove_file(&temp).await.ok();
+                tokio::fs::remove_file(&target).await.ok();
+                return Err(cause.into());
+            }
+            eprintln!(
+                "Received {} ({} bytes, blake3 {})",
+                target.display(),
+                header.len,
+                hash
+            );
+            Ok(target)
+        }
+        Err(cause) => {
+            progress.finish();
+            tokio::fs::remove_file(&temp).await.ok();
+            tokio::fs::remove_file(&target).await.ok();
+            Err(cause)
+        }
+    }
+}
+
+/// Copy exactly `len` bytes from `from` to `to`, and return their hash.
+async fn copy_and_hash(
+    from: &mut (impl AsyncRead + Unpin),
+    to: &mut (impl AsyncWrite + Unpin),
+    len: u64,
+    progress: &mut Progress,
+) -> anyhow::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut done = 0u64;
+    while done < len {
+        let n = from.read(&mut buf).await?;
+        anyhow::ensure!(n > 0, "unexpected end of data after {done} of {len} bytes");
+        let n = n.min((len - done) as usize);
+        hasher.update(&buf[..n]);
+        to.write_all(&buf[..n]).await?;
+        done += n as u64;
+        progress.set(done);
+    }
+    to.flush().await?;
+    Ok(hasher.finalize())
+}
+
+/// Progress of a long running operation, printed to stderr.
+struct Progress {
+    label: String,
+    total: u64,
+    done: u64,
+    last_update: Option<Instant>,
+}
+
+impl Progress {
+    fn new(label: String, total: u64) -> Self {
+        Self {
+            label,
+            total,
+            done: 0,
+            last_update: None,
+        }
+    }
+
+    fn set(&mut self, done: u64) {
+        self.done = done;
+        let due = match self.last_update {
+            Some(last) => last.elapsed() >= PROGRESS_INTERVAL,
+            None => true,
+        };
+        if due {
+            self.print();
+            self.last_update = Some(Instant::now());
+        }
+    }
+
+    fn print(&self) {
+        let percent = match self.total {
+            0 => 100,
+            total => self.done * 100 / total,
+        };
+        eprint!(
+            "\r{}: {}% ({}/{} bytes)",
+            self.label, percent, self.done, self.total
+        );
+    }
+
+    fn finish(&self) {
+        self.print();
+        eprintln!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory to receive files in.
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("files-test-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn header(name: &str, content: &[u8]) -> FileHeader {
+        FileHeader {
+            name: name.into(),
+            len: content.len() as u64,
+            hash: *blake3::hash(content).as_bytes(),
+        }
+    }
+
+    fn files_in(dir: &Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn store_file_checks_the_hash() {
+        let dir = temp_dir();
+        let target = store_file(&mut &b"hello"[..], &dir, &header("a.txt", b"hello"))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(target).unwrap(), b"hello");
+        let mut wrong = header("b.txt", b"hello");
+        wrong.hash = *blake3::hash(b"other").as_bytes();
+        let err = store_file(&mut &b"hello"[..], &dir, &wrong)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("hash mismatch"), "{err}");
+        // neither the file nor the temporary file is left behind.
+        assert_eq!(files_in(&dir), ["a.txt"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn store_file_rejects_invalid_names() {
+        let dir = temp_dir();
+        for name in ["../x", "a/b", "/x", ".x", "..", ""] {
+            let res = store_file(&mut &b"hi"[..], &dir, &header(name, b"hi")).await;
+            assert!(res.is_err(), "{name:?} was accepted");
+        }
+        assert!(files_in(&dir).is_empty());
+        assert!(!dir.parent().unwrap().join("x").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn store_file_keeps_existing_files() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("a.txt"), b"mine").unwrap();
+        std::fs::write(dir.join(".b.txt.part"), b"partial").unwrap();
+        for name in ["a.txt", "b.txt"] {
+            let res = store_file(&mut &b"hi"[..], &dir, &header(name, b"hi")).await;
+            assert!(res.is_err(), "{name:?} was accepted");
+        }
+        assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"mine");
+        assert_eq!(std::fs::read(dir.join(".b.txt.part")).unwrap(), b"partial");
+        assert_eq!(files_in(&dir), [".b.txt.part", "a.txt"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+//! Forwarding TCP connections over iroh.
+//!
+//! The connecting side listens on a local TCP port and opens a fresh
+//! bidirectional QUIC stream for every accepted TCP socket. The listening side
+//! accepts these streams and connects each of them to a TCP address.
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Context;
+use iroh_net::{
+    endpoint::{self, Connection, RecvStream, SendStream},
+    Endpoint, NodeAddr,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::info;
+
+use crate::{
+    acl::Acl,
+    io::{recv_handshake, send_handshake},
+};
+
+/// Accept connections on the endpoint and forward every stream to `addr`.
+///
+/// Connections from nodes rejected by `acl` are closed right away.
+/// This runs until the endpoint is closed.
+pub async fn forward_to_tcp(
+    endpoint: Endpoint,
+    alpn: &[u8],
+    addr: &str,
+    acl: Acl,
+) -> anyhow::Result<()> {
+    let addrs = tokio::net::lookup_host(addr)
+        .await
+        .with_context(|| format!("unable to resolve {addr}"))?
+        .collect::<Vec<_>>();
+    anyhow::ensure!(!addrs.is_empty(), "{addr} did not resolve to any address");
+    let acl = Arc::new(acl);
+    while let Some(incoming) = endpoint.accept().await {
+        let alpn = alpn.to_vec();
+        let addrs = addrs.clone();
+        let acl = acl.clone();
+        tokio::spawn(async move {
+            if let Err(cause) = handle_forward_connection(incoming, &alpn, addrs, &acl).await {
+                tracing::warn!("error handling connection: {:?}", cause);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Handle a single incoming connection, forwarding each of its streams.
+async fn handle_forward_connection(
+    incoming: endpoint::Incoming,
+    alpn: &[u8],
+    addrs: Vec<SocketAddr>,
+    acl: &Acl,
+) -> anyhow::Result<()> {
+    let mut connecting = incoming.accept()?;
+    let remote_alpn = connecting.alpn().await?;
+    let connection = connecting.await?;
+    let remote_node_id = endpoint::get_remote_node_id(&connection)?;
+    if acl.reject_if_denied(&connection, &remote_node_id) {
+        return Ok(());
+    }
+    if remote_alpn != alpn {
+        tracing::warn!("unexpected ALPN: {:?}", remote_alpn);
+        return Ok(());
+    }
+    info!("got forwarding connection from {}", remote_node_id);
+    loop {
+        let (send, mut recv) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            Err(cause) => {
+                info!("connection from {} closed: {}", remote_node_id, cause);
+                return Ok(());
+            }
+        };
+        let addrs = addrs.clone();
+        tokio::spawn(async move {
+            let res = async {
+                recv_handshake(&mut recv).await?;
+                let tcp = TcpStream::connect(addrs.as_slice()).await?;
+                info!("forwarding stream to {}", tcp.peer_addr()?);
+                let (tcp_recv, tcp_send) = tcp.into_split();
+                copy_both_ways(tcp_recv, tcp_send, send, recv).await
+            };
+            if let Err(cause) = res.await {
+                tracing::warn!("error forwarding stream: {:?}", cause);
+            }
+        });
+    }
+}
+
+/// Forward every TCP connection accepted by `listener` to the remote node.
+///
+/// The caller binds the listener, so it knows the local address to tell the user.
+/// This runs until the TCP listener fails.
+pub async fn forward_from_tcp(
+    endpoint: Endpoint,
+    alpn: &[u8],
+    addr: NodeAddr,
+    listener: TcpListener,
+) -> anyhow::Result<()> {
+    // connect once up front, so we find out about problems before the first TCP client shows up.
+    let mut connection = endpoint.connect(addr.clone(), alpn).await?;
+    info!("connected to {}", addr.node_id);
+    loop {
+        let (tcp, peer) = listener.accept().await?;
+        info!("got TCP connection from {}", peer);
+        // reconnect if the connection was lost in the meantime.
+        if connection.close_reason().is_some() {
+            info!("connection lost, reconnecting");
+            connection = match endpoint.connect(addr.clone(), alpn).await {
+                Ok(connection) => connection,
+                Err(cause) => {
+                    tracing::warn!("unable to reconnect: {:?}", cause);
+                    continue;
+                }
+            };
+        }
+        let connection = connection.clone();
+        tokio::spawn(async move {
+            if let Err(cause) = forward_tcp_stream(connection, tcp).await {
+                tracing::warn!("error forwarding connection from {}: {:?}", peer, cause);
+            }
+        });
+    }
+}
+
+/// Forward a single TCP connection over a fresh stream on `connection`.
+async fn forward_tcp_stream(connection: Connection, tcp: TcpStream) -> anyhow::Result<()> {
+    let (mut send, recv) = connection.open_bi().await?;
+    // for protocols like SSH the server talks first, so we need to announce the stream.
+    send_handshake(&mut send).await?;
+    let (tcp_recv, tcp_send) = tcp.into_split();
+    copy_both_ways(tcp_recv, tcp_send, send, recv).await
+}
+
+/// Copy data between a TCP connection and a QUIC stream until both directions are done.
+///
+/// When one side stops sending, this is passed on to the other side as a half-close.
+async fn copy_both_ways(
+    mut tcp_recv: impl AsyncRead + Unpin,
+    mut tcp_send: impl AsyncWrite + Unpin,
+    mut send: SendStream,
+    mut recv: RecvStream,
+) -> anyhow::Result<()> {
+    let to_remote = async {
+        tokio::io::copy(&mut tcp_recv, &mut send).await?;
+        send.finish()?;
+        anyhow::Ok(())
+    };
+    let from_remote = async {
+        tokio::io::copy(&mut recv, &mut tcp_send).await?;
+        tcp_send.shutdown().await?;
+        anyhow::Ok(())
+    };
+    tokio::try_join!(to_remote, from_remote)?;
+    Ok(())
+}
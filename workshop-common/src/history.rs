@@ -0,0 +1,363 @@
+//! Local history of the chat rooms.
+//!
+//! Every verified message, including our own, is stored as it was received,
+//! so its signature can be checked again when it is read back. The history is
+//! kept per topic in a [redb](https://docs.rs/redb) database, ordered by the
+//! time the messages were sent. A uid is only stored once per topic, so a
+//! message that reuses the uid of another one is refused. Deleted messages
+//! leave a tombstone behind for their author, so they are not fetched again
+//! from the history of a neighbor.
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use iroh_gossip::proto::TopicId;
+use iroh_net::key::PublicKey;
+use redb::{backends::InMemoryBackend, Database, ReadableTable, TableDefinition};
+
+use crate::{
+    chat::{self, SignedMessage},
+    secret::{create_private_dir, CONFIG_DIR_NAME},
+};
+
+/// Messages by topic, timestamp and uid.
+const MESSAGES: TableDefinition<([u8; 32], u64, u128), &[u8]> = TableDefinition::new("messages");
+/// Timestamps of the messages by topic and uid.
+const UIDS: TableDefinition<([u8; 32], u128), u64> = TableDefinition::new("uids");
+/// Deleted messages by topic, uid and author.
+const DELETED: TableDefinition<([u8; 32], u128, [u8; 32]), ()> = TableDefinition::new("deleted");
+/// Number of messages shown by `/history` without a count.
+pub const DEFAULT_COUNT: usize = 20;
+
+/// The stored messages of all rooms.
+#[derive(Debug)]
+pub struct History {
+    db: Database,
+}
+
+impl History {
+    /// Open the history in a file, creating it if it does not exist.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent() {
+            create_private_dir(parent)?;
+        }
+        let db = Database::create(path)
+            .with_context(|| format!("failed to open history {}", path.display()))?;
+        Self::init(db)
+    }
+
+    /// A history that is only kept until the program exits.
+    pub fn in_memory() -> anyhow::Result<Self> {
+        let db = Database::builder().create_with_backend(InMemoryBackend::new())?;
+        Self::init(db)
+    }
+
+    fn init(db: Database) -> anyhow::Result<Self> {
+        let tx = db.begin_write()?;
+        tx.open_table(MESSAGES)?;
+        tx.open_table(UIDS)?;
+        tx.open_table(DELETED)?;
+        tx.commit()?;
+        Ok(Self { db })
+    }
+
+    /// Store a signed message sent to `topic`.
+    ///
+    /// The signature is checked, so the history only contains valid messages.
+    /// Returns false if a message with the same uid was already stored, or the
+    /// author deleted the message.
+    pub fn insert(&self, topic: TopicId, bytes: &[u8]) -> anyhow::Result<bool> {
+        let message = SignedMessage::verify(bytes, topic)?;
+        chat::check_not_future(&message.from, message.timestamp)?;
+        let topic = *topic.as_bytes();
+        let tx = self.db.begin_write()?;
+        let is_new = {
+            let mut uids = tx.open_table(UIDS)?;
+            let tombstone = (topic, message.uid, *message.from.as_bytes());
+            let is_new = uids.get((topic, message.uid))?.is_none()
+                && tx.open_table(DELETED)?.get(tombstone)?.is_none();
+            if is_new {
+                uids.insert((topic, message.uid), message.timestamp)?;
+                tx.open_table(MESSAGES)?
+                    .insert((topic, message.timestamp, message.uid), bytes)?;
+            }
+            is_new
+        };
+        tx.commit()?;
+        Ok(is_new)
+    }
+
+    /// The message of a topic with the given uid, if it is stored.
+    pub fn get(&self, topic: TopicId, uid: u128) -> anyhow::Result<Option<Vec<u8>>> {
+        let tx = self.db.begin_read()?;
+        let topic = *topic.as_bytes();
+        let Some(timestamp) = tx.open_table(UIDS)?.get((topic, uid))? else {
+            return Ok(None);
+        };
+        let message = tx
+            .open_table(MESSAGES)?
+            .get((topic, timestamp.value(), uid))?
+            .map(|bytes| bytes.value().to_vec());
+        Ok(message)
+    }
+
+    /// The uids of a topic in the given range, in the order of the uids.
+    pub fn uids(
+        &self,
+        topic: TopicId,
+        range: std::ops::RangeInclusive<u128>,
+    ) -> anyhow::Result<Vec<u128>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(UIDS)?;
+        let topic = *topic.as_bytes();
+        table
+            .range((topic, *range.start())..=(topic, *range.end()))?
+            .map(|entry| Ok(entry?.0.value().1))
+            .collect()
+    }
+
+    /// Delete a message of `author` for good.
+    ///
+    /// Returns false if it was not stored, e.g. because the uid belongs to a
+    /// message of somebody else.
+    pub fn delete(&self, topic: TopicId, uid: u128, author: PublicKey) -> anyhow::Result<bool> {
+        let topic_bytes = *topic.as_bytes();
+        let tx = self.db.begin_write()?;
+        let deleted = {
+            tx.open_table(DELETED)?
+                .insert((topic_bytes, uid, *author.as_bytes()), ())?;
+            let mut uids = tx.open_table(UIDS)?;
+            let mut messages = tx.open_table(MESSAGES)?;
+            let timestamp = uids.get((topic_bytes, uid))?.map(|t| t.value());
+            let stored = match timestamp {
+                Some(timestamp) => messages
+                    .get((topic_bytes, timestamp, uid))?
+                    .map(|bytes| SignedMessage::verify(bytes.value(), topic))
+                    .transpose()?
+                    .filter(|message| message.from == author)
+                    .map(|_| timestamp),
+                None => None,
+            };
+            if let Some(timestamp) = stored {
+                uids.remove((topic_bytes, uid))?;
+                messages.remove((topic_bytes, timestamp, uid))?;
+            }
+            stored.is_some()
+        };
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    /// The newest `count` messages of a topic that were sent after `since`, oldest first.
+    pub fn since(&self, topic: TopicId, since: u64, count: usize) -> anyhow::Result<Vec<Vec<u8>>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(MESSAGES)?;
+        let topic = *topic.as_bytes();
+        let mut messages = table
+            .range((topic, since.saturating_add(1), 0)..=(topic, u64::MAX, u128::MAX))?
+            .rev()
+            .take(count)
+            .map(|entry| Ok(entry?.1.value().to_vec()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// The time the newest stored message of a topic was sent, if there is one.
+    pub fn latest(&self, topic: TopicId) -> anyhow::Result<Option<u64>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(MESSAGES)?;
+        let topic = *topic.as_bytes();
+        let latest = table
+            .range((topic, 0, 0)..=(topic, u64::MAX, u128::MAX))?
+            .next_back()
+            .transpose()?
+            .map(|(key, _)| key.value().1);
+        Ok(latest)
+    }
+
+    /// The last `count` messages of a topic, oldest first.
+    pub fn last(&self, topic: TopicId, count: usize) -> anyhow::Result<Vec<Vec<u8>>> {
+        self.since(topic, 0, count)
+    }
+}
+
+/// Parse a `/history [count]` command typed by the user.
+///
+/// Returns `None` if the line is not a history command.
+pub fn parse_history_command(line: &str) -> Option<anyhow::Result<usize>> {
+    let mut parts = line.split_whitespace();
+    if parts.next() != Some("/history") {
+        return None;
+    }
+    let count = match parts.next() {
+        Some(count) => count
+            .parse()
+            .map_err(|_| anyhow::anyhow!("usage: /history [count]")),
+        None => Ok(DEFAULT_COUNT),
+    };
+    Some(count)
+}
+
+/// Command line options for the chat history.
+///
+/// Flatten this into the arguments of a binary with `#[clap(flatten)]`.
+#[derive(Debug, Clone, clap::Args)]
+#[command(about = None, long_about = None)]
+pub struct HistoryArgs {
+    /// File to store the chat history in.
+    ///
+    /// Defaults to a file per node id in the user's data directory. Nodes with
+    /// an ephemeral key keep no history unless a file is given.
+    #[clap(long, global = true, conflicts_with = "no_history")]
+    pub history_file: Option<PathBuf>,
+    /// Do not store the chat history.
+    #[clap(long, global = true)]
+    pub no_history: bool,
+}
+
+impl HistoryArgs {
+    /// Open the history of the node with the given id.
+    pub fn open(&self, node_id: &PublicKey, ephemeral: bool) -> anyhow::Result<History> {
+        match &self.history_file {
+            _ if self.no_history => History::in_memory(),
+            Some(path) => History::open(path),
+            None if ephemeral => History::in_memory(),
+            None => History::open(&default_history_file(node_id)?),
+        }
+    }
+}
+
+/// The default history file for a node.
+///
+/// This is `$XDG_DATA_HOME/iroh-workshop/history/<node-id>.redb` on Linux, and
+/// the equivalent data directory on other platforms.
+pub fn default_history_file(node_id: &PublicKey) -> anyhow::Result<PathBuf> {
+    let data_dir = dirs::data_dir().context("unable to determine the data directory")?;
+    Ok(data_dir
+        .join(CONFIG_DIR_NAME)
+        .join("history")
+        .join(format!("{node_id}.redb")))
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_net::key::SecretKey;
+
+    use super::*;
+    use crate::chat::{self, Author, Message, VERSION};
+
+    fn topic() -> TopicId {
+        TopicId::from_bytes([1; 32])
+    }
+
+    fn signed(author: &mut Author, uid: u128, text: &str) -> Vec<u8> {
+        let message = Message::Message { text: text.into() };
+        SignedMessage::sign_at(author, topic(), VERSION, uid, chat::now(), &message).unwrap()
+    }
+
+    fn text_of(bytes: &[u8]) -> String {
+        match SignedMessage::verify(bytes, topic()).unwrap().message {
+            Message::Message { text } => text,
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+
+    #[test]
+    fn insert_and_get() {
+        let history = History::in_memory().unwrap();
+        let mut alice = Author::new(SecretKey::generate());
+        let bytes = signed(&mut alice, 7, "hi");
+        assert!(history.insert(topic(), &bytes).unwrap());
+        assert!(!history.insert(topic(), &bytes).unwrap());
+        assert_eq!(history.get(topic(), 7).unwrap(), Some(bytes));
+        assert_eq!(history.uids(topic(), 0..=u128::MAX).unwrap(), vec![7]);
+        assert!(history
+            .get(TopicId::from_bytes([2; 32]), 7)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn insert_refuses_message_from_the_future() {
+        let history = History::in_memory().unwrap();
+        let mut alice = Author::new(SecretKey::generate());
+        let message = Message::Message { text: "hi".into() };
+        let tomorrow = chat::now() + 24 * 60 * 60 * 1_000_000;
+        let bytes =
+            SignedMessage::sign_at(&mut alice, topic(), VERSION, 7, tomorrow, &message).unwrap();
+        let err = history.insert(topic(), &bytes).unwrap_err();
+        assert!(err.to_string().contains("future"), "{err}");
+        assert!(history.get(topic(), 7).unwrap().is_none());
+        assert_eq!(history.latest(topic()).unwrap(), None);
+    }
+
+    #[test]
+    fn insert_refuses_uid_collision() {
+        let history = History::in_memory().unwrap();
+        let mut alice = Author::new(SecretKey::generate());
+        let mut mallory = Author::new(SecretKey::generate());
+        assert!(history
+            .insert(topic(), &signed(&mut alice, 7, "hi"))
+            .unwrap());
+        assert!(!history
+            .insert(topic(), &signed(&mut mallory, 7, "evil"))
+            .unwrap());
+        // not even the same author may reuse a uid
+        assert!(!history
+            .insert(topic(), &signed(&mut alice, 7, "again"))
+            .unwrap());
+        let stored = history.get(topic(), 7).unwrap().unwrap();
+        assert_eq!(text_of(&stored), "hi");
+        assert_eq!(history.last(topic(), 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn delete_leaves_tombstone() {
+        let history = History::in_memory().unwrap();
+        let alice_key = SecretKey::generate();
+        let mut alice = Author::new(alice_key.clone());
+        let bytes = signed(&mut alice, 7, "hi");
+        history.insert(topic(), &bytes).unwrap();
+        assert!(history.delete(topic(), 7, alice_key.public()).unwrap());
+        assert!(history.get(topic(), 7).unwrap().is_none());
+        assert!(history.last(topic(), 10).unwrap().is_empty());
+        // not fetched again from a neighbor
+        assert!(!history.insert(topic(), &bytes).unwrap());
+    }
+
+    #[test]
+    fn delete_of_somebody_else_keeps_message() {
+        let history = History::in_memory().unwrap();
+        let mut alice = Author::new(SecretKey::generate());
+        let mallory = SecretKey::generate();
+        history
+            .insert(topic(), &signed(&mut alice, 7, "hi"))
+            .unwrap();
+        assert!(!history.delete(topic(), 7, mallory.public()).unwrap());
+        let stored = history.get(topic(), 7).unwrap().unwrap();
+        assert_eq!(text_of(&stored), "hi");
+    }
+
+    #[test]
+    fn tombstone_does_not_block_message_of_somebody_else() {
+        let history = History::in_memory().unwrap();
+        let mut alice = Author::new(SecretKey::generate());
+        let mallory_key = SecretKey::generate();
+        let mut mallory = Author::new(mallory_key.clone());
+        // mallory takes the uid of alice's message first, then deletes it
+        let evil = signed(&mut mallory, 7, "evil");
+        assert!(history.insert(topic(), &evil).unwrap());
+        assert!(history.delete(topic(), 7, mallory_key.public()).unwrap());
+        // a tombstone sent before the message does not block it either
+        assert!(!history.delete(topic(), 8, mallory_key.public()).unwrap());
+        assert!(history
+            .insert(topic(), &signed(&mut alice, 7, "hi"))
+            .unwrap());
+        assert!(history
+            .insert(topic(), &signed(&mut alice, 8, "ho"))
+            .unwrap());
+        let stored = history.get(topic(), 7).unwrap().unwrap();
+        assert_eq!(text_of(&stored), "hi");
+        assert!(!history.insert(topic(), &evil).unwrap());
+    }
+}
@@ -0,0 +1,76 @@
+//! Copying data between QUIC streams and the console.
+use std::io::Read;
+
+use iroh_net::endpoint::{RecvStream, SendStream};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::mpsc,
+};
+
+/// Sent by the side that opens a stream, before any other data.
+///
+/// A QUIC stream is only announced to the remote once data is written to it,
+/// so without this the remote would not see the stream until the opening side
+/// has something to say.
+pub const HANDSHAKE: [u8; 5] = *b"hello";
+
+/// Write the [`HANDSHAKE`] to a freshly opened stream.
+pub async fn send_handshake(send: &mut SendStream) -> anyhow::Result<()> {
+    send.write_all(&HANDSHAKE).await?;
+    Ok(())
+}
+
+/// Read the [`HANDSHAKE`] from a freshly accepted stream.
+pub async fn recv_handshake(recv: &mut RecvStream) -> anyhow::Result<()> {
+    let mut handshake = [0u8; HANDSHAKE.len()];
+    recv.read_exact(&mut handshake).await?;
+    anyhow::ensure!(handshake == HANDSHAKE, "invalid handshake");
+    Ok(())
+}
+
+/// Copy from the remote to stdout, prepending the author's name.
+pub async fn copy_to_stdout(author: String, from: RecvStream) -> anyhow::Result<()> {
+    let mut lines = BufReader::new(from).lines();
+    while let Some(line) = lines.next_line().await? {
+        tracing::info!("read line: {}", line);
+        println!("{}> {}", author, line);
+    }
+    Ok(())
+}
+
+/// Copy from stdin to the remote.
+pub async fn copy_stdin_to(mut to: SendStream) -> anyhow::Result<()> {
+    let from = tokio::io::stdin();
+    let mut lines = BufReader::new(from).lines();
+    while let Some(line) = lines.next_line().await? {
+        tracing::info!("read line: {}", line);
+        to.write_all(format!("{}\n", line).as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Read stdin in chunks on a dedicated thread.
+///
+/// Reading from [`tokio::io::stdin`] can not be cancelled, so a pending read
+/// from a terminal would keep the runtime from shutting down until the user
+/// presses enter. A plain thread does not keep the process alive.
+pub(crate) fn stdin_chunks() -> mpsc::Receiver<std::io::Result<Vec<u8>>> {
+    let (tx, rx) = mpsc::channel(16);
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let res = match stdin.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => Ok(buf[..n].to_vec()),
+                Err(cause) if cause.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(cause) => Err(cause),
+            };
+            let failed = res.is_err();
+            if tx.blocking_send(res).is_err() || failed {
+                break;
+            }
+        }
+    });
+    rx
+}
@@ -0,0 +1,47 @@
+//! Shared helpers for the pipe and chat examples of the iroh workshop.
+//!
+//! Every example used to carry its own copy of these functions in a `util.rs`.
+//! They now live here, so binaries built on top of the examples can depend on
+//! a single implementation.
+use iroh_net::key::PublicKey;
+
+pub mod acl;
+pub mod chat;
+pub mod direct;
+pub mod dm;
+pub mod edits;
+pub mod endpoint;
+pub mod files;
+pub mod forward;
+pub mod history;
+pub mod io;
+pub mod names;
+pub mod network;
+pub mod presence;
+pub mod receipts;
+pub mod resume;
+pub mod room_key;
+pub mod rooms;
+pub mod secret;
+pub mod sessions;
+pub mod sync;
+pub mod threads;
+pub mod ticket;
+
+pub use acl::{Acl, AclArgs};
+pub use endpoint::{wait_for_relay, Discovery, DiscoveryArgs, EndpointBuilder};
+pub use files::{receive_file, send_file};
+pub use history::{History, HistoryArgs};
+pub use io::{copy_stdin_to, copy_to_stdout, recv_handshake, send_handshake};
+pub use names::{Names, Profile, ProfileArgs};
+pub use network::{NetworkArgs, NetworkConfig};
+pub use receipts::{ReceiptArgs, Receipts};
+pub use resume::{connect_resumable, serve_resumable};
+pub use rooms::{RoomArgs, RoomCommand, Rooms};
+pub use secret::{KeyArgs, SecretSource};
+pub use ticket::ChatTicket;
+
+/// Print public key (aka node id) as a z32 string, compatible with https://pkarr.org/
+pub fn z32_node_id(node_id: &PublicKey) -> String {
+    zbase32::encode_full_bytes(node_id.as_bytes().as_slice())
+}
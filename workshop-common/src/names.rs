@@ -0,0 +1,111 @@
+//! Nicknames of chat peers.
+//!
+//! Peers announce their name with a signed `AboutMe` message. Anyone can claim
+//! any name, so a name is always shown together with a short prefix of the
+//! node id, and marked with a `!` if several nodes claim the same name.
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::Context;
+use iroh_net::key::PublicKey;
+
+/// Longest name that is accepted, in characters.
+pub const MAX_NAME_LEN: usize = 32;
+
+/// What a peer announced about itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    /// BLAKE3 hash of the avatar image, if the peer has one.
+    pub avatar_hash: Option<[u8; 32]>,
+}
+
+/// The names of the peers we have heard of, including our own.
+#[derive(Debug, Default)]
+pub struct Names {
+    profiles: HashMap<PublicKey, Profile>,
+}
+
+impl Names {
+    /// Set the profile of a node.
+    ///
+    /// Returns true if the name changed.
+    pub fn set(&mut self, node_id: PublicKey, profile: Profile) -> bool {
+        let changed = self.name(&node_id) != Some(profile.name.as_str());
+        self.profiles.insert(node_id, profile);
+        changed
+    }
+
+    /// The profile of a node, if it announced one.
+    pub fn get(&self, node_id: &PublicKey) -> Option<&Profile> {
+        self.profiles.get(node_id)
+    }
+
+    /// The name of a node, if it announced one.
+    pub fn name(&self, node_id: &PublicKey) -> Option<&str> {
+        self.get(node_id).map(|profile| profile.name.as_str())
+    }
+
+    /// True if another node claims the same name as `node_id`.
+    ///
+    /// Names are compared case insensitively, so `Alice` collides with `alice`.
+    pub fn is_ambiguous(&self, node_id: &PublicKey) -> bool {
+        let Some(name) = self.name(node_id) else {
+            return false;
+        };
+        let name = name.to_lowercase();
+        self.profiles
+            .iter()
+            .any(|(id, profile)| id != node_id && profile.name.to_lowercase() == name)
+    }
+
+    /// Render a node as `name (short id)`, or just the short id if it has no name.
+    pub fn display(&self, node_id: &PublicKey) -> String {
+        let short = node_id.fmt_short();
+        match self.name(node_id) {
+            Some(name) if self.is_ambiguous(node_id) => format!("{name}! ({short})"),
+            Some(name) => format!("{name} ({short})"),
+            None => short,
+        }
+    }
+}
+
+/// Check a name claimed by a peer or typed by the user, and trim it.
+pub fn check_name(name: &str) -> anyhow::Result<String> {
+    let name = name.trim();
+    anyhow::ensure!(!name.is_empty(), "name must not be empty");
+    anyhow::ensure!(
+        name.chars().count() <= MAX_NAME_LEN,
+        "name must be at most {MAX_NAME_LEN} characters"
+    );
+    anyhow::ensure!(
+        !name.chars().any(char::is_control),
+        "name must not contain control characters"
+    );
+    Ok(name.to_string())
+}
+
+/// Command line options to set the profile announced in chat rooms.
+///
+/// Flatten this into the arguments of a binary with `#[clap(flatten)]`.
+#[derive(Debug, Clone, clap::Args)]
+#[command(about = None, long_about = None)]
+pub struct ProfileArgs {
+    /// Name to show to the other peers. Can be changed with /nick.
+    #[clap(long, global = true)]
+    pub nick: Option<String>,
+    /// Image file to use as avatar. Only its hash is announced.
+    #[clap(long, global = true)]
+    pub avatar: Option<PathBuf>,
+}
+
+impl ProfileArgs {
+    /// The hash of the avatar image, if one was given.
+    pub fn avatar_hash(&self) -> anyhow::Result<Option<[u8; 32]>> {
+        let Some(path) = &self.avatar else {
+            return Ok(None);
+        };
+        let data = std::fs::read(path)
+            .with_context(|| format!("failed to read avatar {}", path.display()))?;
+        Ok(Some(*blake3::hash(&data).as_bytes()))
+    }
+}
@@ -0,0 +1,220 @@
+//! Self-hosted infrastructure for discovery and relaying.
+//!
+//! By default the examples use the relays and the DNS server run by n0, and
+//! the public Mainline DHT. A [`NetworkConfig`] points them at our own relays,
+//! pkarr relay, DNS server and DHT bootstrap nodes instead, so a deployment
+//! does not depend on any hosted infrastructure.
+//!
+//! The config is read from a TOML file, e.g.
+//!
+//! ```toml
+//! relays = ["https://relay.example.com"]
+//! pkarr_relay = "https://dns.example.com/pkarr"
+//! dns_origin = "dns.example.com"
+//! dns_server = "10.0.0.53:53"
+//! dht_bootstrap = ["dht.example.com:6881"]
+//! ```
+//!
+//! and can be overridden on the command line with [`NetworkArgs`].
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use hickory_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    TokioAsyncResolver,
+};
+use iroh_net::{
+    discovery::{dns::N0_DNS_NODE_ORIGIN_PROD, pkarr::N0_DNS_PKARR_RELAY_PROD},
+    dns::DnsResolver,
+    key::PublicKey,
+    relay::{RelayMap, RelayMode, RelayNode, RelayUrl},
+};
+use pkarr::{mainline::dht::DhtSettings, PkarrClient};
+use serde::Deserialize;
+use url::Url;
+
+use crate::{secret::CONFIG_DIR_NAME, z32_node_id};
+
+/// Name of the network config file in the user's config directory.
+const CONFIG_FILE_NAME: &str = "network.toml";
+
+/// Relays, discovery servers and DHT bootstrap nodes to use.
+///
+/// Everything that is not set falls back to the n0 defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Relays to use instead of the n0 relays.
+    pub relays: Vec<RelayUrl>,
+    /// Pkarr relay to publish our address to.
+    pub pkarr_relay: Option<Url>,
+    /// Domain below which the DNS server serves the published addresses.
+    pub dns_origin: Option<String>,
+    /// DNS server to use for all lookups, instead of the system's resolver.
+    pub dns_server: Option<SocketAddr>,
+    /// Mainline DHT nodes to bootstrap from, as `host:port`.
+    ///
+    /// Set this to use a private DHT instead of the public one.
+    pub dht_bootstrap: Vec<String>,
+}
+
+impl NetworkConfig {
+    /// Parse a config file.
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Read a config file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read network config {}", path.display()))?;
+        Self::from_toml(&text).with_context(|| format!("invalid network config {}", path.display()))
+    }
+
+    /// The relay mode, if our own relays are configured.
+    pub fn relay_mode(&self) -> anyhow::Result<Option<RelayMode>> {
+        if self.relays.is_empty() {
+            return Ok(None);
+        }
+        let nodes = self.relays.iter().map(|url| RelayNode {
+            url: url.clone(),
+            stun_only: false,
+            stun_port: 0,
+        });
+        Ok(Some(RelayMode::Custom(RelayMap::from_nodes(nodes)?)))
+    }
+
+    /// The DNS resolver, if our own DNS server is configured.
+    pub fn dns_resolver(&self) -> Option<DnsResolver> {
+        let server = self.dns_server?;
+        let name_servers =
+            NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true);
+        let config = ResolverConfig::from_parts(None, vec![], name_servers);
+        Some(TokioAsyncResolver::tokio(config, ResolverOpts::default()))
+    }
+
+    /// The domain to resolve node ids below.
+    pub fn dns_origin(&self) -> &str {
+        self.dns_origin
+            .as_deref()
+            .unwrap_or(N0_DNS_NODE_ORIGIN_PROD)
+    }
+
+    /// The pkarr relay to publish to for DNS discovery.
+    pub fn dns_pkarr_relay(&self) -> anyhow::Result<Url> {
+        match &self.pkarr_relay {
+            Some(url) => Ok(url.clone()),
+            None => Ok(N0_DNS_PKARR_RELAY_PROD.parse()?),
+        }
+    }
+
+    /// True if DNS discovery uses our own DNS server or pkarr relay.
+    pub fn has_custom_dns(&self) -> bool {
+        self.pkarr_relay.is_some() || self.dns_origin.is_some()
+    }
+
+    /// True if the public Mainline DHT is used.
+    pub fn uses_public_dht(&self) -> bool {
+        self.dht_bootstrap.is_empty()
+    }
+
+    /// A pkarr client for the DHT, if our own bootstrap nodes are configured.
+    ///
+    /// A private DHT does not know about the public pkarr resolvers, so they are not used.
+    pub fn pkarr_client(&self) -> anyhow::Result<Option<PkarrClient>> {
+        if self.uses_public_dht() {
+            return Ok(None);
+        }
+        let client = PkarrClient::builder()
+            .dht_settings(DhtSettings {
+                bootstrap: Some(self.dht_bootstrap.clone()),
+                ..Default::default()
+            })
+            .resolvers(None)
+            .build()?;
+        Ok(Some(client))
+    }
+
+    /// Command to look up the address a node published via DNS discovery.
+    pub fn dns_lookup_command(&self, node_id: &PublicKey) -> String {
+        let name = format!("_iroh.{}.{}", z32_node_id(node_id), self.dns_origin());
+        match (self.dns_server, &self.dns_origin) {
+            (Some(server), _) => format!("dig TXT @{} -p {} {name}", server.ip(), server.port()),
+            (None, Some(_)) => format!("dig TXT {name}"),
+            (None, None) => format!("dig TXT @{N0_DNS_NODE_ORIGIN_PROD} {name}"),
+        }
+    }
+}
+
+/// Command line options to use our own infrastructure.
+///
+/// Without `--network-config`, the config file in the user's config directory
+/// is used if it exists. The other options override the values in the file.
+#[derive(Debug, Clone, Default, clap::Args)]
+#[command(about = None, long_about = None)]
+pub struct NetworkArgs {
+    /// TOML file with the relays, pkarr relay, DNS server and DHT bootstrap nodes to use.
+    ///
+    /// Defaults to network.toml in the user's config directory, if it exists.
+    #[clap(long, global = true)]
+    pub network_config: Option<PathBuf>,
+    /// Use this relay instead of the n0 relays. Can be given multiple times.
+    #[clap(long = "relay", value_name = "URL", global = true)]
+    pub relays: Vec<RelayUrl>,
+    /// Publish our address to this pkarr relay.
+    #[clap(long, value_name = "URL", global = true)]
+    pub pkarr_relay: Option<Url>,
+    /// Resolve node ids below this domain.
+    #[clap(long, value_name = "DOMAIN", global = true)]
+    pub dns_origin: Option<String>,
+    /// Use this DNS server for all lookups.
+    #[clap(long, value_name = "ADDR", global = true)]
+    pub dns_server: Option<SocketAddr>,
+    /// Bootstrap the Mainline DHT from this node. Can be given multiple times.
+    #[clap(long, value_name = "HOST:PORT", global = true)]
+    pub dht_bootstrap: Vec<String>,
+}
+
+impl NetworkArgs {
+    /// Build the [`NetworkConfig`] from the config file and the command line options.
+    pub fn load(&self) -> anyhow::Result<NetworkConfig> {
+        let mut config = match &self.network_config {
+            Some(path) => NetworkConfig::load(path)?,
+            None => match default_config_file() {
+                Some(path) if path.exists() => NetworkConfig::load(&path)?,
+                _ => NetworkConfig::default(),
+            },
+        };
+        if !self.relays.is_empty() {
+            config.relays = self.relays.clone();
+        }
+        if let Some(url) = &self.pkarr_relay {
+            config.pkarr_relay = Some(url.clone());
+        }
+        if let Some(origin) = &self.dns_origin {
+            config.dns_origin = Some(origin.clone());
+        }
+        if let Some(server) = self.dns_server {
+            config.dns_server = Some(server);
+        }
+        if !self.dht_bootstrap.is_empty() {
+            config.dht_bootstrap = self.dht_bootstrap.clone();
+        }
+        Ok(config)
+    }
+}
+
+/// The default network config file.
+///
+/// This is `$XDG_CONFIG_HOME/iroh-workshop/network.toml` on Linux, and the
+/// equivalent config directory on other platforms.
+pub fn default_config_file() -> Option<PathBuf> {
+    Some(
+        dirs::config_dir()?
+            .join(CONFIG_DIR_NAME)
+            .join(CONFIG_FILE_NAME),
+    )
+}
@@ -0,0 +1,135 @@
+//! Who is in a chat room.
+//!
+//! Gossip only tells a node about its direct neighbors. Members further away
+//! announce themselves with a signed [`Message::Presence`] every
+//! [`HEARTBEAT_INTERVAL`], and any other message counts as a sign of life as
+//! well. Members that are no neighbor and stay silent for
+//! [`PRESENCE_TIMEOUT`] are considered gone.
+//!
+//! The roster also knows which versions of the message format its members
+//! understand, and picks the one to send with.
+//!
+//! [`Message::Presence`]: crate::chat::Message::Presence
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use iroh_net::NodeId;
+
+use crate::chat::{MIN_VERSION, VERSION};
+
+/// How often to tell a room we are still there.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+/// How long a member that is not a neighbor may stay silent.
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A member joined or left a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Joined(NodeId),
+    Left(NodeId),
+}
+
+/// What we know about a member of a room.
+#[derive(Debug, Clone, Copy)]
+pub struct Member {
+    /// True if we are directly connected to the member.
+    pub neighbor: bool,
+    /// When we last heard of the member.
+    pub last_seen: Instant,
+    /// The highest version of the message format the member understands, once we got a message.
+    pub max_version: Option<u8>,
+}
+
+/// The members of a room, except ourselves.
+#[derive(Debug, Default)]
+pub struct Roster {
+    members: BTreeMap<NodeId, Member>,
+    /// Changes not yet shown to the user.
+    changes: Vec<Change>,
+}
+
+impl Roster {
+    /// A node became our neighbor in the room.
+    pub fn neighbor_up(&mut self, node_id: NodeId) {
+        self.touch(node_id).neighbor = true;
+    }
+
+    /// A node is no longer our neighbor.
+    ///
+    /// It may still be in the room through other nodes, so it is only
+    /// removed if it stays silent.
+    pub fn neighbor_down(&mut self, node_id: NodeId) {
+        if let Some(member) = self.members.get_mut(&node_id) {
+            member.neighbor = false;
+        }
+    }
+
+    /// We got a message from a node, which understands versions up to `max_version`.
+    pub fn seen(&mut self, node_id: NodeId, max_version: u8) {
+        self.touch(node_id).max_version = Some(max_version);
+    }
+
+    fn touch(&mut self, node_id: NodeId) -> &mut Member {
+        let changes = &mut self.changes;
+        let member = self.members.entry(node_id).or_insert_with(|| {
+            changes.push(Change::Joined(node_id));
+            Member {
+                neighbor: false,
+                last_seen: Instant::now(),
+                max_version: None,
+            }
+        });
+        member.last_seen = Instant::now();
+        member
+    }
+
+    /// Remove the members that stayed silent for too long.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        let gone: Vec<_> = self
+            .members
+            .iter()
+            .filter(|(_, member)| {
+                !member.neighbor && now.duration_since(member.last_seen) > PRESENCE_TIMEOUT
+            })
+            .map(|(node_id, _)| *node_id)
+            .collect();
+        for node_id in gone {
+            self.members.remove(&node_id);
+            self.changes.push(Change::Left(node_id));
+        }
+    }
+
+    /// The changes since the last call, oldest first.
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// All known members, ordered by node id.
+    pub fn members(&self) -> impl Iterator<Item = (&NodeId, &Member)> {
+        self.members.iter()
+    }
+
+    /// The version of the message format to send with, the highest one all members understand.
+    ///
+    /// Members we have not heard from yet don't count, and we never send with
+    /// a version below [`MIN_VERSION`].
+    pub fn version(&self) -> u8 {
+        self.members
+            .values()
+            .filter_map(|member| member.max_version)
+            .min()
+            .unwrap_or(VERSION)
+            .clamp(MIN_VERSION, VERSION)
+    }
+
+    /// Our direct neighbors.
+    pub fn neighbors(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.members
+            .iter()
+            .filter(|(_, member)| member.neighbor)
+            .map(|(node_id, _)| *node_id)
+    }
+}
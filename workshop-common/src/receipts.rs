@@ -0,0 +1,255 @@
+//! Delivery receipts and read markers.
+//!
+//! Receipts are opt-in, since every member answers every message. When they
+//! are enabled and a node receives a chat message, it answers with a signed
+//! [`Message::Ack`] for the uid of the message. Once the user writes something
+//! in the room, everything shown so far counts as read, and the node sends a
+//! [`Message::Read`] for the newest message of each author. A read marker
+//! covers the earlier messages of the author as well.
+//!
+//! Receipts for direct messages are sealed for the sender of the message, so
+//! they don't reveal more than the message itself. The sender collects the
+//! receipts of its last [`MAX_SENT`] messages in each room.
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use iroh_net::key::PublicKey;
+
+use crate::{chat::Message, names::Names};
+
+/// Number of our own messages whose receipts are tracked.
+pub const MAX_SENT: usize = 256;
+/// Number of received messages waiting to be acknowledged.
+const MAX_ACKS: usize = 256;
+/// Characters of a message shown next to its receipts.
+const PREVIEW_LEN: usize = 32;
+
+/// A message we sent, and who received and read it.
+#[derive(Debug, Clone)]
+pub struct Sent {
+    pub uid: u128,
+    pub text: String,
+    /// The recipient, for direct messages.
+    pub to: Option<PublicKey>,
+    pub received: BTreeSet<PublicKey>,
+    pub read: BTreeSet<PublicKey>,
+}
+
+impl Sent {
+    /// Describe the receipts of the message, e.g. `"hi": received by 2, read by alice (abcd)`.
+    pub fn status(&self, names: &Names) -> String {
+        let preview = preview(&self.text);
+        if let Some(to) = &self.to {
+            let state = if self.read.contains(to) {
+                "read"
+            } else if self.received.contains(to) {
+                "delivered"
+            } else {
+                "sent"
+            };
+            return format!("\"{preview}\" to {}: {state}", names.display(to));
+        }
+        let mut status = format!("\"{preview}\": received by {}", self.received.len());
+        if !self.read.is_empty() {
+            let readers: Vec<_> = self.read.iter().map(|id| names.display(id)).collect();
+            status.push_str(&format!(", read by {}", readers.join(", ")));
+        }
+        status
+    }
+}
+
+/// The start of a message, to show which one is meant.
+pub fn preview(text: &str) -> String {
+    let mut preview: String = text.chars().take(PREVIEW_LEN).collect();
+    if preview.len() < text.len() {
+        preview.push('…');
+    }
+    preview
+}
+
+/// A message we received, but did not acknowledge or mark as read yet.
+#[derive(Debug, Clone, Copy)]
+pub struct Unread {
+    pub uid: u128,
+    /// True if it was a direct message, so the read marker must be sealed.
+    pub direct: bool,
+}
+
+/// Receipts for our own messages in a room, and the ones we owe to others.
+#[derive(Debug, Default)]
+pub struct Receipts {
+    /// Our own messages, oldest first.
+    sent: VecDeque<Sent>,
+    /// The newest unread message of each author.
+    unread: BTreeMap<PublicKey, Unread>,
+    /// Messages to acknowledge, oldest first.
+    acks: VecDeque<(PublicKey, Unread)>,
+}
+
+impl Receipts {
+    /// Remember a message we sent, to collect its receipts.
+    pub fn sent(&mut self, uid: u128, text: String, to: Option<PublicKey>) {
+        if self.sent.len() >= MAX_SENT {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(Sent {
+            uid,
+            text,
+            to,
+            received: BTreeSet::new(),
+            read: BTreeSet::new(),
+        });
+    }
+
+    /// Remember a message we received, to acknowledge it and mark it as read later.
+    pub fn received(&mut self, from: PublicKey, uid: u128, direct: bool) {
+        let unread = Unread { uid, direct };
+        self.unread.insert(from, unread);
+        if self.acks.len() >= MAX_ACKS {
+            self.acks.pop_front();
+        }
+        self.acks.push_back((from, unread));
+    }
+
+    /// The messages to send a [`Message::Ack`] for, since the last call.
+    pub fn take_acks(&mut self) -> Vec<(PublicKey, Unread)> {
+        std::mem::take(&mut self.acks).into()
+    }
+
+    /// The messages to send a [`Message::Read`] for, since the last call.
+    pub fn take_unread(&mut self) -> Vec<(PublicKey, Unread)> {
+        std::mem::take(&mut self.unread).into_iter().collect()
+    }
+
+    /// Apply a receipt that `from` sent for one of our messages.
+    ///
+    /// Returns the message if its receipts changed.
+    pub fn apply(&mut self, from: PublicKey, receipt: &Message) -> Option<&Sent> {
+        let (uid, read) = match receipt {
+            Message::Ack { uid } => (*uid, false),
+            Message::Read { uid } => (*uid, true),
+            _ => return None,
+        };
+        let index = self.sent.iter().position(|sent| sent.uid == uid)?;
+        let to = self.sent[index].to;
+        // only the recipient can acknowledge a direct message
+        if to.is_some_and(|to| to != from) {
+            return None;
+        }
+        let mut changed = false;
+        for (i, sent) in self.sent.iter_mut().enumerate() {
+            // a read marker covers the earlier messages to the same recipients
+            let covered = i == index || (read && i < index && sent.to == to);
+            if covered {
+                changed |= sent.received.insert(from) && i == index;
+                if read {
+                    changed |= sent.read.insert(from) && i == index;
+                }
+            }
+        }
+        changed.then(|| &self.sent[index])
+    }
+}
+
+/// Command line options for receipts.
+///
+/// Flatten this into the arguments of a binary with `#[clap(flatten)]`.
+#[derive(Debug, Clone, clap::Args)]
+#[command(about = None, long_about = None)]
+pub struct ReceiptArgs {
+    /// Tell others that we received and read their messages.
+    #[clap(long = "receipts", global = true)]
+    pub enabled: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_net::key::SecretKey;
+
+    use super::*;
+
+    fn peer() -> PublicKey {
+        SecretKey::generate().public()
+    }
+
+    #[test]
+    fn ack_marks_received() {
+        let (bob, carol) = (peer(), peer());
+        let mut receipts = Receipts::default();
+        receipts.sent(1, "hello".into(), None);
+        let sent = receipts.apply(bob, &Message::Ack { uid: 1 }).unwrap();
+        assert!(sent.received.contains(&bob));
+        assert!(sent.read.is_empty());
+        // the same ack again changes nothing
+        assert!(receipts.apply(bob, &Message::Ack { uid: 1 }).is_none());
+        let sent = receipts.apply(carol, &Message::Ack { uid: 1 }).unwrap();
+        assert_eq!(sent.received.len(), 2);
+    }
+
+    #[test]
+    fn read_covers_earlier_messages() {
+        let bob = peer();
+        let mut receipts = Receipts::default();
+        receipts.sent(1, "one".into(), None);
+        receipts.sent(2, "two".into(), None);
+        receipts.sent(3, "three".into(), None);
+        let sent = receipts.apply(bob, &Message::Read { uid: 2 }).unwrap();
+        assert_eq!(sent.uid, 2);
+        assert!(sent.read.contains(&bob) && sent.received.contains(&bob));
+        let sent = &receipts.sent;
+        assert!(sent[0].read.contains(&bob) && sent[0].received.contains(&bob));
+        assert!(sent[2].read.is_empty() && sent[2].received.is_empty());
+        // reading an earlier message that is already covered changes nothing
+        assert!(receipts.apply(bob, &Message::Read { uid: 1 }).is_none());
+    }
+
+    #[test]
+    fn read_does_not_cover_other_recipients() {
+        let (bob, carol) = (peer(), peer());
+        let mut receipts = Receipts::default();
+        receipts.sent(1, "for carol".into(), Some(carol));
+        receipts.sent(2, "for bob".into(), Some(bob));
+        receipts.apply(bob, &Message::Read { uid: 2 }).unwrap();
+        assert!(receipts.sent[0].read.is_empty());
+        assert!(receipts.sent[0].received.is_empty());
+    }
+
+    #[test]
+    fn only_the_recipient_acknowledges_direct_messages() {
+        let (bob, carol) = (peer(), peer());
+        let mut receipts = Receipts::default();
+        receipts.sent(1, "psst".into(), Some(bob));
+        assert!(receipts.apply(carol, &Message::Ack { uid: 1 }).is_none());
+        assert!(receipts.apply(carol, &Message::Read { uid: 1 }).is_none());
+        let sent = receipts.apply(bob, &Message::Read { uid: 1 }).unwrap();
+        assert!(sent.read.contains(&bob));
+        assert!(!sent.received.contains(&carol));
+    }
+
+    #[test]
+    fn ignores_unknown_uids_and_other_messages() {
+        let bob = peer();
+        let mut receipts = Receipts::default();
+        receipts.sent(1, "hello".into(), None);
+        assert!(receipts.apply(bob, &Message::Ack { uid: 2 }).is_none());
+        let text = Message::Message {
+            text: "hello".into(),
+        };
+        assert!(receipts.apply(bob, &text).is_none());
+        assert!(receipts.sent[0].received.is_empty());
+    }
+
+    #[test]
+    fn forgets_old_messages_and_acks() {
+        let bob = peer();
+        let mut receipts = Receipts::default();
+        for uid in 0..=MAX_SENT as u128 {
+            receipts.sent(uid, "hello".into(), None);
+            receipts.received(bob, uid, false);
+        }
+        assert!(receipts.apply(bob, &Message::Ack { uid: 0 }).is_none());
+        assert!(receipts.apply(bob, &Message::Ack { uid: 1 }).is_some());
+        assert_eq!(receipts.take_acks().len(), MAX_ACKS);
+        assert!(receipts.take_acks().is_empty());
+        assert_eq!(receipts.take_unread().len(), 1);
+    }
+}
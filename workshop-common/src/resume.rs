@@ -0,0 +1,739 @@
+//! Raw byte transfers that survive the loss of the connection.
+//!
+//! Both sides keep the data they read from stdin until the remote acknowledges
+//! it. If the connection is lost, e.g. because the network changed, the
+//! connecting side reconnects and both sides continue from the last byte the
+//! other side has received.
+//!
+//! After the [`HANDSHAKE`](crate::io::HANDSHAKE), the connecting side sends a
+//! random session id and the number of bytes it has received so far, and the
+//! listening side replies with the number of bytes it has received. After that,
+//! both sides send frames starting with a one byte tag: data with a 4 byte
+//! length, or an acknowledgement or the end of the data with an 8 byte offset.
+//!
+//! A session belongs to the node that started it. The listening side only
+//! switches to a new connection once its handshake is complete, and only if
+//! it comes from the same node for the same session.
+use std::{collections::VecDeque, future::Future, io::IsTerminal, pin::Pin, time::Duration};
+
+use anyhow::Context;
+use iroh_net::{
+    endpoint::{self, Connection, ConnectionError, RecvStream, SendStream},
+    key::PublicKey,
+    Endpoint, NodeAddr,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinHandle,
+    time::error::Elapsed,
+};
+use tracing::info;
+
+use crate::io::{recv_handshake, send_handshake, stdin_chunks};
+
+/// Application error code used to close connections for a different transfer.
+pub const BUSY_CODE: u32 = 409;
+
+/// Maximum payload of a single data frame.
+const MAX_FRAME: usize = 64 * 1024;
+/// Maximum amount of data kept for retransmission before we stop reading stdin.
+const MAX_UNACKED: usize = 16 * 1024 * 1024;
+/// Acknowledge received data whenever this many bytes came in.
+const ACK_INTERVAL: u64 = 256 * 1024;
+/// Time to wait before the first reconnect attempt. Doubled on every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+/// Maximum time to wait between reconnect attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Number of failed reconnect attempts in a row before giving up.
+const MAX_ATTEMPTS: u32 = 20;
+/// Time the remote has to complete the session handshake, and to finish after we are done.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TAG_DATA: u8 = 0;
+const TAG_ACK: u8 = 1;
+const TAG_FIN: u8 = 2;
+
+/// A frame exchanged after the session handshake.
+#[derive(Debug, PartialEq)]
+enum Frame {
+    /// The next bytes of the data.
+    Data(Vec<u8>),
+    /// The sender has received all bytes up to this offset.
+    Ack(u64),
+    /// The data ends at this offset.
+    Fin(u64),
+}
+
+impl Frame {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Data(data) => {
+                let mut buf = Vec::with_capacity(5 + data.len());
+                buf.push(TAG_DATA);
+                buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                buf.extend_from_slice(data);
+                buf
+            }
+            Self::Ack(offset) => [&[TAG_ACK][..], &offset.to_be_bytes()].concat(),
+            Self::Fin(offset) => [&[TAG_FIN][..], &offset.to_be_bytes()].concat(),
+        }
+    }
+
+    /// Read the next frame, or `None` if the remote finished the stream.
+    async fn read(recv: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Option<Self>> {
+        let mut tag = [0u8; 1];
+        if recv.read(&mut tag).await? == 0 {
+            return Ok(None);
+        }
+        let frame = match tag[0] {
+            TAG_DATA => {
+                let len = read_u32(recv).await? as usize;
+                anyhow::ensure!(len <= MAX_FRAME, "data frame too large: {len}");
+                let mut data = vec![0u8; len];
+                recv.read_exact(&mut data).await?;
+                Self::Data(data)
+            }
+            TAG_ACK => Self::Ack(read_u64(recv).await?),
+            TAG_FIN => Self::Fin(read_u64(recv).await?),
+            tag => anyhow::bail!("unknown frame tag {tag}"),
+        };
+        Ok(Some(frame))
+    }
+}
+
+async fn read_u32(recv: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<u32> {
+    let mut buf = [0u8; 4];
+    recv.read_exact(&mut buf).await?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+async fn read_u64(recv: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<u64> {
+    let mut buf = [0u8; 8];
+    recv.read_exact(&mut buf).await?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// The next frame to send on a link.
+enum Next {
+    Ack(u64),
+    Data { offset: u64, len: usize },
+    Fin(u64),
+}
+
+/// How a link ended.
+enum LinkEnd {
+    /// The transfer is complete.
+    Done,
+    /// The connection was lost.
+    Lost,
+    /// The remote connected again, the old connection is abandoned.
+    Replaced(Session),
+}
+
+/// A connection for a transfer whose session handshake was received, but not answered yet.
+///
+/// The streams keep the connection open.
+struct Session {
+    send: SendStream,
+    recv: RecvStream,
+    id: u64,
+    remote: PublicKey,
+    /// Number of bytes the remote has received.
+    remote_received: u64,
+}
+
+/// The session handshake of a new connection, limited to [`HANDSHAKE_TIMEOUT`].
+type Handshake = Pin<Box<dyn Future<Output = Result<anyhow::Result<Session>, Elapsed>> + Send>>;
+
+/// A stream carrying the transfer, with tasks reading and writing its frames.
+struct Link {
+    frames: mpsc::Receiver<anyhow::Result<Option<Frame>>>,
+    writer: mpsc::Sender<Vec<u8>>,
+    tasks: [JoinHandle<()>; 2],
+    progress: Progress,
+}
+
+/// What was sent on a link so far.
+struct Progress {
+    /// Offset of the next data byte to send.
+    next_send: u64,
+    /// Offset of the last acknowledgement we sent.
+    ack_sent: u64,
+    fin_sent: bool,
+}
+
+impl Link {
+    /// Start reading and writing frames.
+    ///
+    /// `remote_received` and `received` are the offsets exchanged in the session handshake.
+    fn new(
+        mut send: impl AsyncWrite + Unpin + Send + 'static,
+        mut recv: impl AsyncRead + Unpin + Send + 'static,
+        remote_received: u64,
+        received: u64,
+    ) -> Self {
+        // reading and writing happens in tasks, so that the link can be
+        // abandoned at any time without losing track of what was received.
+        let (frames_tx, frames) = mpsc::channel(16);
+        let reader = tokio::spawn(async move {
+            loop {
+                let res = Frame::read(&mut recv).await;
+                let end = !matches!(res, Ok(Some(_)));
+                if frames_tx.send(res).await.is_err() || end {
+                    break;
+                }
+            }
+        });
+        let (writer, mut writer_rx) = mpsc::channel::<Vec<u8>>(4);
+        let writer_task = tokio::spawn(async move {
+            while let Some(frame) = writer_rx.recv().await {
+                if let Err(cause) = send.write_all(&frame).await {
+                    info!("error writing frame: {}", cause);
+                    return;
+                }
+            }
+            // we are done, let the remote know.
+            send.shutdown().await.ok();
+        });
+        Self {
+            frames,
+            writer,
+            tasks: [reader, writer_task],
+            progress: Progress {
+                next_send: remote_received,
+                ack_sent: received,
+                fin_sent: false,
+            },
+        }
+    }
+
+    /// Finish our side of the stream and wait for the remote to finish its side.
+    ///
+    /// Errors are ignored, since both sides already have all the data.
+    async fn finish(mut self) {
+        let (tx, _) = mpsc::channel(1);
+        drop(std::mem::replace(&mut self.writer, tx));
+        (&mut self.tasks[1]).await.ok();
+        let wait_for_remote = async { while let Some(Ok(Some(_))) = self.frames.recv().await {} };
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, wait_for_remote)
+            .await
+            .ok();
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// State of a transfer, which outlives the connections carrying it.
+struct Transfer {
+    session: u64,
+    /// The node on the other side, the only one that may continue the transfer.
+    remote: PublicKey,
+    /// Data read from stdin that the remote has not acknowledged yet.
+    unacked: VecDeque<u8>,
+    /// Offset of the first byte in `unacked`.
+    acked: u64,
+    /// Chunks read from stdin, until stdin is done.
+    stdin: Option<mpsc::Receiver<std::io::Result<Vec<u8>>>>,
+    stdin_is_terminal: bool,
+    /// A terminal we stopped reading, kept for the next transfer.
+    paused_stdin: Option<mpsc::Receiver<std::io::Result<Vec<u8>>>>,
+    /// Number of bytes received from the remote and written to stdout.
+    received: u64,
+    /// Total number of bytes the remote sends, once the remote knows.
+    remote_total: Option<u64>,
+    stdout: Pin<Box<dyn AsyncWrite + Send>>,
+}
+
+impl Transfer {
+    fn new(
+        session: u64,
+        remote: PublicKey,
+        stdin: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    ) -> Self {
+        let is_terminal = std::io::stdin().is_terminal();
+        Self::with_io(session, remote, stdin, is_terminal, tokio::io::stdout())
+    }
+
+    /// A transfer of `stdin` to `stdout`, which need not be the ones of the process.
+    fn with_io(
+        session: u64,
+        remote: PublicKey,
+        stdin: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+        stdin_is_terminal: bool,
+        stdout: impl AsyncWrite + Send + 'static,
+    ) -> Self {
+        Self {
+            session,
+            remote,
+            unacked: VecDeque::new(),
+            acked: 0,
+            stdin: Some(stdin),
+            stdin_is_terminal,
+            paused_stdin: None,
+            received: 0,
+            remote_total: None,
+            stdout: Box::pin(stdout),
+        }
+    }
+
+    /// Total number of bytes read from stdin so far.
+    fn written(&self) -> u64 {
+        self.acked + self.unacked.len() as u64
+    }
+
+    fn remote_done(&self) -> bool {
+        self.remote_total == Some(self.received)
+    }
+
+    /// Drop the data the remote has received.
+    fn ack(&mut self, offset: u64) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            offset <= self.written(),
+            "remote acknowledged data we never sent"
+        );
+        if offset > self.acked {
+            self.unacked.drain(..(offset - self.acked) as usize);
+            self.acked = offset;
+        }
+        Ok(())
+    }
+
+    /// The transfer is complete once both sides have all the data, and know about it.
+    fn is_done(&self, link: &Link) -> bool {
+        self.stdin.is_none()
+            && self.unacked.is_empty()
+            && self.remote_done()
+            && link.progress.fin_sent
+            && link.progress.ack_sent == self.received
+    }
+
+    fn next_frame(&self, link: &Link) -> Option<Next> {
+        let unacked_received = self.received - link.progress.ack_sent;
+        if unacked_received >= ACK_INTERVAL || (self.remote_done() && unacked_received > 0) {
+            return Some(Next::Ack(self.received));
+        }
+        if link.progress.next_send < self.written() {
+            let len = (self.written() - link.progress.next_send).min(MAX_FRAME as u64) as usize;
+            return Some(Next::Data {
+                offset: link.progress.next_send,
+                len,
+            });
+        }
+        if self.stdin.is_none() && !link.progress.fin_sent {
+            return Some(Next::Fin(self.written()));
+        }
+        None
+    }
+
+    /// Turn `next` into a frame, and record that it was sent.
+    fn take_frame(&self, progress: &mut Progress, next: Next) -> Frame {
+        match next {
+            Next::Ack(offset) => {
+                progress.ack_sent = offset;
+                Frame::Ack(offset)
+            }
+            Next::Data { offset, len } => {
+                let start = (offset - self.acked) as usize;
+                progress.next_send = offset + len as u64;
+                Frame::Data(self.unacked.range(start..start + len).copied().collect())
+            }
+            Next::Fin(offset) => {
+                progress.fin_sent = true;
+                Frame::Fin(offset)
+            }
+        }
+    }
+
+    async fn handle_frame(&mut self, link: &Link, frame: Frame) -> anyhow::Result<()> {
+        match frame {
+            Frame::Data(data) => {
+                let received = self.received + data.len() as u64;
+                if let Some(total) = self.remote_total {
+                    anyhow::ensure!(received <= total, "remote sent data after the end");
+                }
+                self.stdout.write_all(&data).await?;
+                self.received = received;
+            }
+            Frame::Ack(offset) => {
+                anyhow::ensure!(
+                    offset <= link.progress.next_send,
+                    "remote acknowledged unsent data"
+                );
+                self.ack(offset)?;
+            }
+            Frame::Fin(total) => {
+                anyhow::ensure!(
+                    total >= self.received,
+                    "remote sent more data than announced"
+                );
+                self.remote_total = Some(total);
+            }
+        }
+        if self.remote_done() {
+            self.stdout.flush().await?;
+            info!("remote done after {} bytes", self.received);
+        }
+        Ok(())
+    }
+
+    /// Run the transfer over `link` until it is done or the link ends.
+    ///
+    /// If `connections` is given, a new connection from the remote replaces the
+    /// link, once its session handshake shows that it continues this transfer.
+    async fn run_link(
+        &mut self,
+        mut link: Link,
+        mut connections: Option<&mut mpsc::Receiver<Connection>>,
+    ) -> anyhow::Result<LinkEnd> {
+        // everything before the offset the remote has received is acknowledged.
+        anyhow::ensure!(
+            link.progress.next_send >= self.acked,
+            "remote lost data it already acknowledged"
+        );
+        self.ack(link.progress.next_send)?;
+        // the handshake of a new connection, which runs while we keep using the link.
+        let mut handshake: Option<Handshake> = None;
+        loop {
+            // like the plain pipe, stop reading from a terminal once the remote is done.
+            if self.stdin_is_terminal && self.remote_done() && self.stdin.is_some() {
+                self.paused_stdin = self.stdin.take();
+            }
+            if self.is_done(&link) {
+                link.finish().await;
+                return Ok(LinkEnd::Done);
+            }
+            let next = self.next_frame(&link);
+            let can_read_stdin = self.unacked.len() < MAX_UNACKED;
+            tokio::select! {
+                biased;
+                Some(connection) = async { connections.as_mut()?.recv().await }, if connections.is_some() && handshake.is_none() => {
+                    let expected = Some((self.session, self.remote));
+                    handshake = Some(Box::pin(tokio::time::timeout(HANDSHAKE_TIMEOUT, accept_session(connection, expected))));
+                }
+                res = async { handshake.as_mut()?.await.ok() }, if handshake.is_some() => {
+                    handshake = None;
+                    match res {
+                        Some(Ok(session)) => return Ok(LinkEnd::Replaced(session)),
+                        Some(Err(cause)) => tracing::warn!("error accepting transfer: {:?}", cause),
+                        None => tracing::warn!("timeout accepting transfer"),
+                    }
+                }
+                frame = link.frames.recv() => match frame {
+                    Some(Ok(Some(frame))) => self.handle_frame(&link, frame).await?,
+                    Some(Err(cause)) => {
+                        info!("error reading frame: {:?}", cause);
+                        return Ok(LinkEnd::Lost);
+                    }
+                    // the remote only finishes its stream once it is done.
+                    Some(Ok(None)) | None => return Ok(LinkEnd::Lost),
+                },
+                permit = link.writer.reserve(), if next.is_some() => match permit {
+                    Ok(permit) => {
+                        let frame = self.take_frame(&mut link.progress, next.expect("checked above"));
+                        permit.send(frame.encode());
+                    }
+                    Err(_) => return Ok(LinkEnd::Lost),
+                },
+                chunk = async { self.stdin.as_mut()?.recv().await }, if self.stdin.is_some() && can_read_stdin => match chunk {
+                    Some(chunk) => self.unacked.extend(chunk?),
+                    None => {
+                        info!("stdin done after {} bytes", self.written());
+                        self.stdin = None;
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Fail if the remote closed the connection on purpose, e.g. because we are not allowed to connect.
+fn ensure_not_closed_by_remote(connection: &Connection) -> anyhow::Result<()> {
+    if let Some(ConnectionError::ApplicationClosed(close)) = connection.close_reason() {
+        anyhow::bail!("connection closed by remote: {}", close);
+    }
+    Ok(())
+}
+
+/// Open the stream for a transfer and do the session handshake.
+async fn open_link(connection: Connection, transfer: &Transfer) -> anyhow::Result<Link> {
+    let handshake = async {
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send_handshake(&mut send).await?;
+        send.write_all(&transfer.session.to_be_bytes()).await?;
+        send.write_all(&transfer.received.to_be_bytes()).await?;
+        let remote_received = read_u64(&mut recv).await?;
+        anyhow::Ok((send, recv, remote_received))
+    };
+    let (send, recv, remote_received) = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .context("timeout during session handshake")??;
+    info!("remote has received {} bytes", remote_received);
+    Ok(Link::new(send, recv, remote_received, transfer.received))
+}
+
+/// Copy stdin to the remote and the remote to stdout, reconnecting if the connection is lost.
+///
+/// Connecting again goes through the discovery configured on the endpoint, so
+/// the remote is found even if its addresses changed.
+pub async fn connect_resumable(
+    endpoint: &Endpoint,
+    addr: NodeAddr,
+    alpn: &[u8],
+) -> anyhow::Result<()> {
+    let mut transfer = Transfer::new(rand::random(), addr.node_id, stdin_chunks());
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempts = 0;
+    loop {
+        let res = async {
+            let connection = endpoint.connect(addr.clone(), alpn).await?;
+            match open_link(connection.clone(), &transfer).await {
+                Ok(link) => Ok((connection, link)),
+                Err(cause) => {
+                    ensure_not_closed_by_remote(&connection)?;
+                    Err(cause)
+                }
+            }
+        };
+        match res.await {
+            Ok((connection, link)) => {
+                backoff = INITIAL_BACKOFF;
+                attempts = 0;
+                match transfer.run_link(link, None).await? {
+                    LinkEnd::Done => return Ok(()),
+                    LinkEnd::Lost | LinkEnd::Replaced(_) => {
+                        ensure_not_closed_by_remote(&connection)?;
+                        eprintln!("Connection lost, reconnecting");
+                        continue;
+                    }
+                }
+            }
+            Err(cause) => {
+                attempts += 1;
+                if attempts >= MAX_ATTEMPTS {
+                    return Err(cause).context("giving up reconnecting");
+                }
+                tracing::warn!("unable to connect: {:?}", cause);
+            }
+        }
+        eprintln!("Retrying in {:?}", backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Accept the stream of a transfer and read the session handshake.
+///
+/// If `expected` is given, connections for other sessions or from other nodes
+/// than the `(session, node id)` are rejected.
+async fn accept_session(
+    connection: Connection,
+    expected: Option<(u64, PublicKey)>,
+) -> anyhow::Result<Session> {
+    let remote = endpoint::get_remote_node_id(&connection)?;
+    let (send, mut recv) = connection.accept_bi().await?;
+    recv_handshake(&mut recv).await?;
+    let id = read_u64(&mut recv).await?;
+    let remote_received = read_u64(&mut recv).await?;
+    if let Some((session, node_id)) = expected {
+        if id != session || remote != node_id {
+            connection.close(BUSY_CODE.into(), b"busy with another transfer");
+            anyhow::bail!("connection from {remote} for a different transfer");
+        }
+    }
+    Ok(Session {
+        send,
+        recv,
+        id,
+        remote,
+        remote_received,
+    })
+}
+
+/// Answer the session handshake, and continue the transfer on the session's connection.
+async fn accept_link(session: Session, transfer: &Transfer) -> anyhow::Result<Link> {
+    let Session {
+        mut send,
+        recv,
+        remote_received,
+        ..
+    } = session;
+    send.write_all(&transfer.received.to_be_bytes()).await?;
+    info!("remote has received {} bytes", remote_received);
+    Ok(Link::new(send, recv, remote_received, transfer.received))
+}
+
+/// Copy stdin to the remote and the remote to stdout, over the given connections.
+///
+/// Transfers are served one after another, until the endpoint is closed. The
+/// remote may connect again to resume a transfer if the connection is lost.
+pub async fn serve_resumable(mut connections: mpsc::Receiver<Connection>) -> anyhow::Result<()> {
+    let mut stdin = None;
+    while let Some(connection) = connections.recv().await {
+        let session =
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, accept_session(connection, None)).await {
+                Ok(Ok(session)) => session,
+                Ok(Err(cause)) => {
+                    tracing::warn!("error accepting transfer: {:?}", cause);
+                    continue;
+                }
+                Err(_) => {
+                    tracing::warn!("timeout accepting transfer");
+                    continue;
+                }
+            };
+        // stdin of the previous transfer, e.g. a terminal, or a pipe that is done.
+        let chunks = stdin.take().unwrap_or_else(stdin_chunks);
+        let mut transfer = Transfer::new(session.id, session.remote, chunks);
+        if let Err(cause) = serve_transfer(&mut connections, &mut transfer, session).await {
+            tracing::warn!("transfer failed: {:?}", cause);
+        }
+        stdin = transfer.stdin.take().or(transfer.paused_stdin.take());
+    }
+    Ok(())
+}
+
+/// Serve a transfer, starting with the connection of `session`.
+async fn serve_transfer(
+    connections: &mut mpsc::Receiver<Connection>,
+    transfer: &mut Transfer,
+    mut session: Session,
+) -> anyhow::Result<()> {
+    loop {
+        let link = match accept_link(session, transfer).await {
+            Ok(link) => transfer.run_link(link, Some(connections)).await?,
+            Err(cause) => {
+                info!("error answering handshake: {:?}", cause);
+                LinkEnd::Lost
+            }
+        };
+        session = match link {
+            LinkEnd::Done => return Ok(()),
+            LinkEnd::Replaced(session) => session,
+            LinkEnd::Lost => {
+                eprintln!("Connection lost, waiting for the remote to reconnect");
+                wait_for_session(connections, transfer).await?
+            }
+        };
+    }
+}
+
+/// Wait for the remote to connect again and continue the transfer.
+async fn wait_for_session(
+    connections: &mut mpsc::Receiver<Connection>,
+    transfer: &Transfer,
+) -> anyhow::Result<Session> {
+    let expected = Some((transfer.session, transfer.remote));
+    while let Some(connection) = connections.recv().await {
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, accept_session(connection, expected)).await {
+            Ok(Ok(session)) => return Ok(session),
+            Ok(Err(cause)) => tracing::warn!("error accepting transfer: {:?}", cause),
+            Err(_) => tracing::warn!("timeout accepting transfer"),
+        }
+    }
+    anyhow::bail!("endpoint closed before the transfer was complete")
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_net::key::SecretKey;
+    use tokio::io::{duplex, split, DuplexStream};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let frames = [
+            Frame::Data(b"hello".to_vec()),
+            Frame::Data(Vec::new()),
+            Frame::Ack(7),
+            Frame::Fin(u64::MAX),
+        ];
+        let bytes: Vec<u8> = frames.iter().flat_map(Frame::encode).collect();
+        let mut recv = bytes.as_slice();
+        for frame in frames {
+            assert_eq!(Frame::read(&mut recv).await.unwrap(), Some(frame));
+        }
+        assert_eq!(Frame::read(&mut recv).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn read_rejects_invalid_frames() {
+        let too_large = [&[TAG_DATA][..], &(MAX_FRAME as u32 + 1).to_be_bytes()].concat();
+        let truncated = Frame::Ack(7).encode()[..5].to_vec();
+        for bytes in [vec![3], too_large, truncated] {
+            assert!(Frame::read(&mut bytes.as_slice()).await.is_err());
+        }
+    }
+
+    /// Stdin of a transfer, which is already complete.
+    fn input(data: &[u8]) -> mpsc::Receiver<std::io::Result<Vec<u8>>> {
+        let (tx, rx) = mpsc::channel(data.len() / MAX_FRAME + 1);
+        for chunk in data.chunks(MAX_FRAME) {
+            tx.try_send(Ok(chunk.to_vec())).unwrap();
+        }
+        rx
+    }
+
+    /// Links between two transfers over in-memory streams, after the session handshake.
+    ///
+    /// The connection is lost once `limit` bytes went from `a` to `b`.
+    fn connect(a: &Transfer, b: &Transfer, limit: u64) -> (Link, Link) {
+        let (a_stream, a_relay) = duplex(4 * MAX_FRAME);
+        let (b_stream, b_relay) = duplex(4 * MAX_FRAME);
+        tokio::spawn(relay(a_relay, b_relay, limit));
+        let (a_recv, a_send) = split(a_stream);
+        let (b_recv, b_send) = split(b_stream);
+        (
+            Link::new(a_send, a_recv, b.received, a.received),
+            Link::new(b_send, b_recv, a.received, b.received),
+        )
+    }
+
+    async fn relay(a: DuplexStream, b: DuplexStream, limit: u64) {
+        let (a_recv, mut a_send) = split(a);
+        let (mut b_recv, mut b_send) = split(b);
+        let mut a_recv = a_recv.take(limit);
+        let forward = tokio::io::copy(&mut a_recv, &mut b_send);
+        let backward = tokio::io::copy(&mut b_recv, &mut a_send);
+        // dropping the streams loses the connection.
+        tokio::select! {
+            _ = forward => {}
+            _ = backward => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_from_acked_offset_after_lost_connection() {
+        let data: Vec<u8> = (0..3 * ACK_INTERVAL).map(|i| (i % 251) as u8).collect();
+        let remote = SecretKey::generate().public();
+        let mut sender = Transfer::with_io(1, remote, input(&data), false, tokio::io::sink());
+        let (output, mut received) = duplex(2 * data.len());
+        let mut receiver = Transfer::with_io(1, remote, input(&[]), false, output);
+        // the connection is lost in the middle of the transfer.
+        let (a, b) = connect(&sender, &receiver, ACK_INTERVAL + ACK_INTERVAL / 2);
+        let (a, b) = tokio::join!(sender.run_link(a, None), receiver.run_link(b, None));
+        assert!(matches!(a.unwrap(), LinkEnd::Lost));
+        assert!(matches!(b.unwrap(), LinkEnd::Lost));
+        assert!(receiver.received > 0 && receiver.received < data.len() as u64);
+        // the sender kept everything the receiver did not acknowledge.
+        assert!(sender.acked <= receiver.received);
+        // after reconnecting, the sender continues where the receiver stopped.
+        let (a, b) = connect(&sender, &receiver, u64::MAX);
+        assert_eq!(a.progress.next_send, receiver.received);
+        let (a, b) = tokio::join!(sender.run_link(a, None), receiver.run_link(b, None));
+        assert!(matches!(a.unwrap(), LinkEnd::Done));
+        assert!(matches!(b.unwrap(), LinkEnd::Done));
+        assert_eq!(sender.acked, data.len() as u64);
+        drop(receiver);
+        let mut output = Vec::new();
+        received.read_to_end(&mut output).await.unwrap();
+        assert!(output == data, "output differs from the input");
+    }
+}
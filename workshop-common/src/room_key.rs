@@ -0,0 +1,581 @@
+//! End-to-end encryption of chat rooms.
+//!
+//! Anyone who knows or guesses the topic id of a room can subscribe to it and
+//! read along. In an encrypted room every message is sealed with a symmetric
+//! room key before it is signed, and sent as [`Message::Encrypted`]. Nodes
+//! without the key only see who is writing, not what.
+//!
+//! The key is handed out with the chat ticket of the room. To remove a member,
+//! a new key is generated and wrapped for each remaining member with the
+//! shared secret of the two node ids, like a direct message. The resulting
+//! [`Message::RoomKey`] is itself sealed with the old key, so only members can
+//! change the key. Once the key changed, messages sealed with the old key are
+//! rejected, so the removed member can neither read nor write any more.
+//!
+//! Members that were offline during a key change pick up the new key from the
+//! history of their neighbors when they come back, since the key change is
+//! sealed with the key they still have.
+//!
+//! A key change is only accepted from a node that was a member while the old
+//! key was current. If two members change the same key, e.g. a removed member
+//! that still has the old key, the change with the lower (timestamp, uid) of
+//! its signed message wins, so all members end up with the same key no matter
+//! in which order the changes arrive.
+use std::collections::{BTreeSet, HashMap};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use iroh_gossip::proto::TopicId;
+use iroh_net::key::{PublicKey, SecretKey};
+use rand::Rng;
+
+use crate::chat::Message;
+
+/// Context for deriving key ids from room keys.
+const KEY_ID_CONTEXT: &str = "iroh-workshop 2024-10 chat room key id";
+
+/// Short id of a room key, so receivers know which key a message is sealed with.
+pub type KeyId = [u8; 8];
+
+/// The id of a room key.
+pub fn key_id(key: &[u8; 32]) -> KeyId {
+    let hash = blake3::derive_key(KEY_ID_CONTEXT, key);
+    hash[..8].try_into().expect("8 bytes")
+}
+
+/// Timestamp and uid of a signed message, to order competing key changes.
+pub type Sent = (u64, u128);
+
+/// The keys of a room and the members that are known to have them.
+#[derive(Debug, Default)]
+pub struct RoomKeys {
+    /// The key new messages are sealed with. `None` for unencrypted rooms.
+    current: Option<[u8; 32]>,
+    /// Previous keys, to read old messages from the history.
+    old: HashMap<KeyId, [u8; 32]>,
+    /// Nodes that sent messages sealed with the current key, or got it wrapped.
+    members: BTreeSet<PublicKey>,
+    /// The change that replaced each previous key.
+    rotations: HashMap<KeyId, Rotation>,
+}
+
+/// A key change we followed.
+#[derive(Debug)]
+struct Rotation {
+    /// The message that changed the key.
+    sent: Sent,
+    /// The key it changed to.
+    next: KeyId,
+    /// The members while the old key was current.
+    members: BTreeSet<PublicKey>,
+}
+
+impl RoomKeys {
+    /// Keys of a room, encrypted if `key` is set.
+    pub fn new(key: Option<[u8; 32]>) -> Self {
+        Self {
+            current: key,
+            ..Default::default()
+        }
+    }
+
+    /// True if the room is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// The current key, to put into tickets.
+    pub fn current(&self) -> Option<[u8; 32]> {
+        self.current
+    }
+
+    /// The nodes known to have the current key.
+    pub fn members(&self) -> &BTreeSet<PublicKey> {
+        &self.members
+    }
+
+    /// Encrypt the room with a fresh key.
+    pub fn encrypt(&mut self) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.is_encrypted(), "room is already encrypted");
+        self.current = Some(rand::thread_rng().gen());
+        Ok(())
+    }
+
+    /// Seal a message for a room, if the room is encrypted.
+    pub fn seal(&self, topic: TopicId, message: Message) -> anyhow::Result<Message> {
+        match &self.current {
+            Some(key) => seal(key, topic, &message),
+            None => Ok(message),
+        }
+    }
+
+    /// Open a message received in the room.
+    ///
+    /// `live` is false for messages from the history, which may be sealed
+    /// with an old key. A [`Message::RoomKey`] switches to the new key, also
+    /// from the history, and is returned so it can be shown. `sent` is the
+    /// timestamp and uid of the signed message.
+    pub fn open(
+        &mut self,
+        topic: TopicId,
+        secret_key: &SecretKey,
+        from: PublicKey,
+        sent: Sent,
+        message: Message,
+        live: bool,
+    ) -> anyhow::Result<Message> {
+        let Message::Encrypted {
+            key_id: id,
+            nonce,
+            ciphertext,
+        } = message
+        else {
+            anyhow::ensure!(
+                !self.is_encrypted(),
+                "unencrypted message in encrypted room"
+            );
+            return Ok(message);
+        };
+        let Some(current) = self.current else {
+            anyhow::bail!("encrypted message, but the room has no key");
+        };
+        let is_current = id == key_id(&current);
+        let key = if is_current {
+            current
+        } else {
+            match self.old.get(&id) {
+                Some(key) => *key,
+                None => anyhow::bail!("message sealed with an unknown room key"),
+            }
+        };
+        let message = open(&key, topic, &id, &nonce, &ciphertext)?;
+        match &message {
+            // a competing key change may arrive after the one we followed
+            Message::RoomKey { wrapped, .. } => {
+                self.accept_key(secret_key, from, sent, &id, wrapped)?
+            }
+            _ if !is_current && live => anyhow::bail!("message sealed with an old room key"),
+            // sealed direct messages are signed with a one-time key
+            Message::Sealed { .. } => {}
+            _ if is_current => {
+                self.members.insert(from);
+            }
+            _ => {}
+        }
+        Ok(message)
+    }
+
+    /// Switch to a new key sent by `from`, replacing the key `old`.
+    ///
+    /// Nothing changes if we were left out, if `old` was already replaced by
+    /// an earlier change, or if we moved on since.
+    fn accept_key(
+        &mut self,
+        secret_key: &SecretKey,
+        from: PublicKey,
+        sent: Sent,
+        old: &KeyId,
+        wrapped: &[(PublicKey, Vec<u8>)],
+    ) -> anyhow::Result<()> {
+        let me = secret_key.public();
+        let Some((_, wrapped_key)) = wrapped.iter().find(|(member, _)| *member == me) else {
+            // we were removed, nothing we can do.
+            return Ok(());
+        };
+        let current = self.current.as_ref().map(key_id);
+        let members = if current == Some(*old) {
+            &self.members
+        } else {
+            match self.rotations.get(old) {
+                Some(rotation) if sent < rotation.sent && current == Some(rotation.next) => {
+                    &rotation.members
+                }
+                _ => return Ok(()),
+            }
+        };
+        anyhow::ensure!(
+            members.contains(&from),
+            "key change by {from}, who is not a member"
+        );
+        let members = members.clone();
+        let mut buffer = wrapped_key.clone();
+        secret_key.shared(&from).open(&mut buffer)?;
+        let key: [u8; 32] = buffer
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("invalid room key"))?;
+        self.rotations.insert(
+            *old,
+            Rotation {
+                sent,
+                next: key_id(&key),
+                members,
+            },
+        );
+        self.replace_key(key);
+        self.members = wrapped
+            .iter()
+            .map(|(member, _)| *member)
+            .filter(|member| *member != me)
+            .chain(Some(from))
+            .collect();
+        Ok(())
+    }
+
+    /// Replace the key with a new one, for all members except `removed`.
+    ///
+    /// The new key is wrapped for every node known to have the current key:
+    /// the ones it was wrapped for, and the ones that sent a message with it,
+    /// live or in the history. Members that only got the key with a ticket and
+    /// never wrote need a new ticket.
+    ///
+    /// Returns the message to send to the room, sealed with the old key. It
+    /// must be signed with the timestamp and uid in `sent`.
+    pub fn rotate(
+        &mut self,
+        topic: TopicId,
+        secret_key: &SecretKey,
+        removed: &PublicKey,
+        sent: Sent,
+    ) -> anyhow::Result<Message> {
+        let Some(old) = self.current else {
+            anyhow::bail!("room is not encrypted");
+        };
+        let key: [u8; 32] = rand::thread_rng().gen();
+        self.rotations.insert(
+            key_id(&old),
+            Rotation {
+                sent,
+                next: key_id(&key),
+                members: self.members.clone(),
+            },
+        );
+        self.members.remove(removed);
+        let wrapped = self
+            .members
+            .iter()
+            .map(|member| {
+                let mut buffer = key.to_vec();
+                secret_key.shared(member).seal(&mut buffer);
+                (*member, buffer)
+            })
+            .collect();
+        let message = Message::RoomKey {
+            key_id: key_id(&key),
+            wrapped,
+        };
+        let sealed = seal(&old, topic, &message)?;
+        self.replace_key(key);
+        Ok(sealed)
+    }
+
+    fn replace_key(&mut self, key: [u8; 32]) {
+        if let Some(old) = self.current.replace(key) {
+            self.old.insert(key_id(&old), old);
+        }
+    }
+}
+
+/// Seal a message with a room key.
+///
+/// The topic and the key id are authenticated, so a sealed message can not be
+/// moved to another room.
+fn seal(key: &[u8; 32], topic: TopicId, message: &Message) -> anyhow::Result<Message> {
+    let id = key_id(key);
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let plaintext = postcard::to_stdvec(message)?;
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &plaintext,
+                aad: &associated_data(topic, &id),
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("encryption failed"))?;
+    Ok(Message::Encrypted {
+        key_id: id,
+        nonce: nonce.into(),
+        ciphertext,
+    })
+}
+
+fn open(
+    key: &[u8; 32],
+    topic: TopicId,
+    id: &KeyId,
+    nonce: &[u8; 24],
+    ciphertext: &[u8],
+) -> anyhow::Result<Message> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let plaintext = cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &associated_data(topic, id),
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("decryption failed"))?;
+    let message: Message = postcard::from_bytes(&plaintext)?;
+    anyhow::ensure!(
+        !matches!(message, Message::Encrypted { .. }),
+        "nested encrypted message"
+    );
+    Ok(message)
+}
+
+fn associated_data(topic: TopicId, id: &KeyId) -> Vec<u8> {
+    let mut data = topic.as_bytes().to_vec();
+    data.extend_from_slice(id);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOPIC: [u8; 32] = [7; 32];
+
+    fn topic() -> TopicId {
+        TopicId::from_bytes(TOPIC)
+    }
+
+    /// Timestamp and uid of the signed messages.
+    const SENT: Sent = (1, 1);
+
+    fn text(text: &str) -> Message {
+        Message::Message { text: text.into() }
+    }
+
+    fn open_text(keys: &mut RoomKeys, me: &SecretKey, from: PublicKey, msg: Message) -> String {
+        match keys.open(topic(), me, from, SENT, msg, true).unwrap() {
+            Message::Message { text } => text,
+            other => panic!("expected a text, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn open_sealed_message() {
+        let (alice, bob) = (SecretKey::generate(), SecretKey::generate());
+        let mut keys = RoomKeys::default();
+        keys.encrypt().unwrap();
+        let mut bob_keys = RoomKeys::new(keys.current());
+        let sealed = keys.seal(topic(), text("hi")).unwrap();
+        assert!(matches!(sealed, Message::Encrypted { .. }));
+        assert_eq!(open_text(&mut bob_keys, &bob, alice.public(), sealed), "hi");
+        assert!(bob_keys.members().contains(&alice.public()));
+    }
+
+    #[test]
+    fn open_rejects_wrong_room_and_plain_text() {
+        let (alice, bob) = (SecretKey::generate(), SecretKey::generate());
+        let mut keys = RoomKeys::default();
+        keys.encrypt().unwrap();
+        let sealed = keys.seal(topic(), text("hi")).unwrap();
+        let other_topic = TopicId::from_bytes([8; 32]);
+        let mut bob_keys = RoomKeys::new(keys.current());
+        assert!(bob_keys
+            .open(other_topic, &bob, alice.public(), SENT, sealed, true)
+            .is_err());
+        assert!(bob_keys
+            .open(topic(), &bob, alice.public(), SENT, text("hi"), true)
+            .is_err());
+        // without a key, nothing can be read.
+        let sealed = keys.seal(topic(), text("hi")).unwrap();
+        let mut plain = RoomKeys::default();
+        assert!(plain
+            .open(topic(), &bob, alice.public(), SENT, sealed, true)
+            .is_err());
+    }
+
+    #[test]
+    fn rotate_removes_member() {
+        let (alice, bob, carol) = (
+            SecretKey::generate(),
+            SecretKey::generate(),
+            SecretKey::generate(),
+        );
+        let mut alice_keys = RoomKeys::default();
+        alice_keys.encrypt().unwrap();
+        let mut bob_keys = RoomKeys::new(alice_keys.current());
+        let mut carol_keys = RoomKeys::new(alice_keys.current());
+        // alice learns who has the key from their messages.
+        for (from, keys) in [(&bob, &bob_keys), (&carol, &carol_keys)] {
+            let msg = keys.seal(topic(), text("hello")).unwrap();
+            open_text(&mut alice_keys, &alice, from.public(), msg);
+        }
+        let old_message = alice_keys.seal(topic(), text("before")).unwrap();
+        // and they learn about alice from hers.
+        for (me, keys) in [(&bob, &mut bob_keys), (&carol, &mut carol_keys)] {
+            open_text(keys, me, alice.public(), old_message.clone());
+        }
+        let rotation = alice_keys
+            .rotate(topic(), &alice, &carol.public(), SENT)
+            .unwrap();
+        assert_eq!(
+            alice_keys.members(),
+            &BTreeSet::from([bob.public()]),
+            "carol is removed"
+        );
+        for (me, keys) in [(&bob, &mut bob_keys), (&carol, &mut carol_keys)] {
+            let msg = keys
+                .open(topic(), me, alice.public(), SENT, rotation.clone(), true)
+                .unwrap();
+            assert!(matches!(msg, Message::RoomKey { .. }));
+        }
+        assert_eq!(bob_keys.current(), alice_keys.current());
+        assert_ne!(carol_keys.current(), alice_keys.current());
+        let after = alice_keys.seal(topic(), text("after")).unwrap();
+        assert_eq!(
+            open_text(&mut bob_keys, &bob, alice.public(), after.clone()),
+            "after"
+        );
+        assert!(carol_keys
+            .open(topic(), &carol, alice.public(), SENT, after, true)
+            .is_err());
+        // old messages can still be read from the history, but no longer live.
+        assert!(bob_keys
+            .open(
+                topic(),
+                &bob,
+                alice.public(),
+                SENT,
+                old_message.clone(),
+                false
+            )
+            .is_ok());
+        assert!(bob_keys
+            .open(topic(), &bob, alice.public(), SENT, old_message, true)
+            .is_err());
+    }
+
+    #[test]
+    fn rotate_includes_offline_members() {
+        let (alice, bob, dave) = (
+            SecretKey::generate(),
+            SecretKey::generate(),
+            SecretKey::generate(),
+        );
+        let mut alice_keys = RoomKeys::default();
+        alice_keys.encrypt().unwrap();
+        let mut dave_keys = RoomKeys::new(alice_keys.current());
+        // alice only knows of dave from the history, e.g. synced after a restart.
+        let from_history = dave_keys.seal(topic(), text("earlier")).unwrap();
+        alice_keys
+            .open(topic(), &alice, dave.public(), SENT, from_history, false)
+            .unwrap();
+        let hello = alice_keys.seal(topic(), text("hello")).unwrap();
+        open_text(&mut dave_keys, &dave, alice.public(), hello);
+        let rotation = alice_keys
+            .rotate(topic(), &alice, &bob.public(), SENT)
+            .unwrap();
+        assert!(alice_keys.members().contains(&dave.public()));
+        // dave was offline, and catches up on the key change from the history.
+        dave_keys
+            .open(topic(), &dave, alice.public(), SENT, rotation, false)
+            .unwrap();
+        assert_eq!(dave_keys.current(), alice_keys.current());
+    }
+
+    #[test]
+    fn accept_key_requires_valid_wrapping() {
+        let (alice, bob, mallory) = (
+            SecretKey::generate(),
+            SecretKey::generate(),
+            SecretKey::generate(),
+        );
+        let mut bob_keys = RoomKeys::default();
+        bob_keys.encrypt().unwrap();
+        bob_keys.members.insert(alice.public());
+        let before = bob_keys.current();
+        let old = key_id(&before.unwrap());
+        // wrapped by mallory, but claiming to come from alice.
+        let mut wrapped = [1u8; 32].to_vec();
+        mallory.shared(&bob.public()).seal(&mut wrapped);
+        let res = bob_keys.accept_key(&bob, alice.public(), SENT, &old, &[(bob.public(), wrapped)]);
+        assert!(res.is_err());
+        assert_eq!(bob_keys.current(), before);
+        // a key that is not for us is ignored.
+        bob_keys
+            .accept_key(
+                &bob,
+                alice.public(),
+                SENT,
+                &old,
+                &[(mallory.public(), vec![0; 72])],
+            )
+            .unwrap();
+        assert_eq!(bob_keys.current(), before);
+        // mallory was never seen with the key.
+        let mut wrapped = [3u8; 32].to_vec();
+        mallory.shared(&bob.public()).seal(&mut wrapped);
+        let res = bob_keys.accept_key(
+            &bob,
+            mallory.public(),
+            SENT,
+            &old,
+            &[(bob.public(), wrapped)],
+        );
+        assert!(res.is_err());
+        assert_eq!(bob_keys.current(), before);
+        // a properly wrapped key is taken over.
+        let mut wrapped = [2u8; 32].to_vec();
+        alice.shared(&bob.public()).seal(&mut wrapped);
+        bob_keys
+            .accept_key(&bob, alice.public(), SENT, &old, &[(bob.public(), wrapped)])
+            .unwrap();
+        assert_eq!(bob_keys.current(), Some([2; 32]));
+        assert_eq!(bob_keys.members(), &BTreeSet::from([alice.public()]));
+    }
+
+    #[test]
+    fn removed_member_can_not_take_over_the_room() {
+        let nodes = [(); 4].map(|_| SecretKey::generate());
+        let [alice, bob, carol, mallory] = &nodes;
+        let mut alice_keys = RoomKeys::default();
+        alice_keys.encrypt().unwrap();
+        let mut keys = [(); 4].map(|_| RoomKeys::new(alice_keys.current()));
+        keys[0] = alice_keys;
+        // everybody learns about everybody else from their messages.
+        let hellos = keys
+            .iter()
+            .map(|keys| keys.seal(topic(), text("hello")).unwrap())
+            .collect::<Vec<_>>();
+        for (me, my_keys) in nodes.iter().zip(&mut keys) {
+            for (from, hello) in nodes.iter().zip(&hellos) {
+                if from.public() != me.public() {
+                    open_text(my_keys, me, from.public(), hello.clone());
+                }
+            }
+        }
+        let [alice_keys, bob_keys, carol_keys, mallory_keys] = &mut keys;
+        let removal = alice_keys
+            .rotate(topic(), alice, &mallory.public(), (10, 1))
+            .unwrap();
+        // mallory still has the old key, and removes alice in turn.
+        let takeover = mallory_keys
+            .rotate(topic(), mallory, &alice.public(), (20, 1))
+            .unwrap();
+        // bob gets the changes live, in order.
+        for (from, sent, msg) in [
+            (alice, (10, 1), removal.clone()),
+            (mallory, (20, 1), takeover.clone()),
+        ] {
+            bob_keys
+                .open(topic(), bob, from.public(), sent, msg, true)
+                .unwrap();
+        }
+        // carol was offline, and gets them from the history the other way round.
+        for (from, sent, msg) in [(mallory, (20, 1), takeover), (alice, (10, 1), removal)] {
+            carol_keys
+                .open(topic(), carol, from.public(), sent, msg, false)
+                .unwrap();
+        }
+        assert_eq!(bob_keys.current(), alice_keys.current());
+        assert_eq!(carol_keys.current(), alice_keys.current());
+        assert!(!carol_keys.members().contains(&mallory.public()));
+    }
+}
@@ -0,0 +1,528 @@
+//! Chat rooms on top of gossip topics.
+//!
+//! A room is a gossip topic whose id is derived from the room name, so
+//! everyone who knows the name ends up in the same topic. A secret salt makes
+//! the topic id unguessable for unlisted rooms. [`Rooms`] keeps the rooms a
+//! node has joined and merges their events, so one node can take part in
+//! several rooms at once. Lines typed by the user go to the current room.
+use std::{collections::BTreeMap, future::Future};
+
+use futures::{Stream, StreamExt};
+use iroh_gossip::{
+    net::{Event, GossipEvent},
+    proto::TopicId,
+};
+use iroh_net::{Endpoint, NodeAddr, NodeId};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{
+    chat, presence::Roster, receipts::Receipts, room_key::RoomKeys, sync::SyncAccess,
+    ticket::ChatTicket,
+};
+
+/// Room that is joined if no room is given on the command line.
+pub const DEFAULT_ROOM: &str = "lobby";
+/// Context for deriving topic ids from room names.
+const TOPIC_CONTEXT: &str = "iroh-workshop 2024-10 chat room topic";
+/// Number of events buffered before reading from the gossip topics pauses.
+const EVENT_BUFFER: usize = 64;
+
+/// Derive the topic id of a room from its name and an optional secret salt.
+pub fn room_topic(name: &str, salt: Option<&str>) -> TopicId {
+    let mut hasher = blake3::Hasher::new_derive_key(TOPIC_CONTEXT);
+    // length prefix the name, so name and salt can not be shifted into each other.
+    hasher.update(&(name.len() as u64).to_be_bytes());
+    hasher.update(name.as_bytes());
+    if let Some(salt) = salt {
+        hasher.update(salt.as_bytes());
+    }
+    TopicId::from_bytes(*hasher.finalize().as_bytes())
+}
+
+/// Command line options to select the rooms to join.
+///
+/// Flatten this into the arguments of a binary with `#[clap(flatten)]`.
+#[derive(Debug, Clone, clap::Args)]
+#[command(about = None, long_about = None)]
+pub struct RoomArgs {
+    /// Room to join. Can be given multiple times, the last one is the current room.
+    ///
+    /// Defaults to the lobby.
+    #[clap(long = "room", value_name = "NAME", global = true)]
+    pub rooms: Vec<String>,
+    /// Secret mixed into the topic ids, for unlisted rooms. All members need the same salt.
+    #[clap(long, global = true)]
+    pub room_salt: Option<String>,
+}
+
+impl RoomArgs {
+    /// The names of the rooms to join on startup.
+    pub fn names(&self) -> Vec<String> {
+        if self.rooms.is_empty() {
+            vec![DEFAULT_ROOM.to_string()]
+        } else {
+            self.rooms.clone()
+        }
+    }
+}
+
+/// A command to manage the rooms, typed on stdin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomCommand {
+    /// `/join <room>`: join a room and make it the current one.
+    Join(String),
+    /// `/leave [room]`: leave the given room, or the current one.
+    Leave(Option<String>),
+    /// `/switch <room>`: send further lines to another joined room.
+    Switch(String),
+    /// `/rooms`: list the joined rooms.
+    List,
+    /// `/invite [room]`: print a ticket for the given room, or the current one.
+    Invite(Option<String>),
+    /// `/encrypt [room]`: encrypt the given room, or the current one, with a fresh key.
+    Encrypt(Option<String>),
+}
+
+impl RoomCommand {
+    /// Parse a line typed by the user.
+    ///
+    /// Returns `None` if the line is not a room command.
+    pub fn parse(line: &str) -> Option<anyhow::Result<Self>> {
+        let (command, arg) = match line.trim().split_once(' ') {
+            Some((command, arg)) => (command, Some(arg.trim().to_string())),
+            None => (line.trim(), None),
+        };
+        let arg = arg.filter(|arg| !arg.is_empty());
+        let command = match command {
+            "/join" => arg.map(Self::Join),
+            "/leave" => Some(Self::Leave(arg)),
+            "/switch" => arg.map(Self::Switch),
+            "/rooms" => Some(Self::List),
+            "/invite" => Some(Self::Invite(arg)),
+            "/encrypt" => Some(Self::Encrypt(arg)),
+            _ => return None,
+        };
+        Some(command.ok_or_else(|| anyhow::anyhow!("usage: {} <room>", line.trim())))
+    }
+}
+
+/// The rooms a node has joined.
+///
+/// `S` is whatever is used to send to a gossip topic, e.g. a `GossipSender`.
+#[derive(Debug)]
+pub struct Rooms<S> {
+    salt: Option<String>,
+    rooms: BTreeMap<String, Room<S>>,
+    current: Option<String>,
+    /// Endpoint to create tickets with, if invites are supported.
+    endpoint: Option<Endpoint>,
+    /// The members of the rooms, who may fetch their history.
+    access: SyncAccess,
+    events_tx: mpsc::Sender<(String, anyhow::Result<Event>)>,
+    events_rx: mpsc::Receiver<(String, anyhow::Result<Event>)>,
+}
+
+#[derive(Debug)]
+struct Room<S> {
+    topic: TopicId,
+    /// Keys of the room, if it is encrypted.
+    keys: RoomKeys,
+    /// The members of the room we know of, including our direct neighbors.
+    roster: Roster,
+    /// Receipts of our messages, and the ones we owe to others.
+    receipts: Receipts,
+    sender: S,
+    /// Task forwarding the events of the topic, aborted when the room is left.
+    forward: JoinHandle<()>,
+}
+
+impl<S> Drop for Room<S> {
+    fn drop(&mut self) {
+        self.forward.abort();
+    }
+}
+
+impl<S> Rooms<S> {
+    /// Create an empty set of rooms, deriving topic ids with the given salt.
+    pub fn new(salt: Option<String>) -> Self {
+        let (events_tx, events_rx) = mpsc::channel(EVENT_BUFFER);
+        Self {
+            salt,
+            rooms: BTreeMap::new(),
+            current: None,
+            endpoint: None,
+            access: SyncAccess::default(),
+            events_tx,
+            events_rx,
+        }
+    }
+
+    /// Support `/invite`, creating tickets with the address of `endpoint`.
+    pub fn with_invites(mut self, endpoint: Endpoint) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    /// Keep `access` up to date with the members of the joined rooms.
+    pub fn with_sync_access(mut self, access: SyncAccess) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// The topic id of the room with the given name.
+    pub fn topic(&self, name: &str) -> TopicId {
+        room_topic(name, self.salt.as_deref())
+    }
+
+    /// Join a room and make it the current one.
+    ///
+    /// `join` subscribes to the topic and returns the sender and the stream of events.
+    pub async fn join<F, Fut, E>(&mut self, name: String, join: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(TopicId) -> Fut,
+        Fut: Future<Output = anyhow::Result<(S, E)>>,
+        E: Stream<Item = anyhow::Result<Event>> + Send + 'static,
+    {
+        let topic = self.topic(&name);
+        self.insert(name, topic, None, join).await
+    }
+
+    /// Join the room of a ticket and make it the current one.
+    ///
+    /// The topic id and key are taken from the ticket, so no salt is needed.
+    pub async fn join_ticket<F, Fut, E>(
+        &mut self,
+        ticket: &ChatTicket,
+        join: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnOnce(TopicId) -> Fut,
+        Fut: Future<Output = anyhow::Result<(S, E)>>,
+        E: Stream<Item = anyhow::Result<Event>> + Send + 'static,
+    {
+        self.insert(ticket.room.clone(), ticket.topic, ticket.key, join)
+            .await
+    }
+
+    async fn insert<F, Fut, E>(
+        &mut self,
+        name: String,
+        topic: TopicId,
+        key: Option<[u8; 32]>,
+        join: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnOnce(TopicId) -> Fut,
+        Fut: Future<Output = anyhow::Result<(S, E)>>,
+        E: Stream<Item = anyhow::Result<Event>> + Send + 'static,
+    {
+        anyhow::ensure!(!self.rooms.contains_key(&name), "already in room {name}");
+        let (sender, events) = join(topic).await?;
+        let events_tx = self.events_tx.clone();
+        let room = name.clone();
+        let forward = tokio::spawn(async move {
+            let mut events = std::pin::pin!(events);
+            while let Some(event) = events.next().await {
+                if events_tx.send((room.clone(), event)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        self.rooms.insert(
+            name.clone(),
+            Room {
+                topic,
+                keys: RoomKeys::new(key),
+                roster: Roster::default(),
+                receipts: Receipts::default(),
+                sender,
+                forward,
+            },
+        );
+        self.current = Some(name);
+        Ok(())
+    }
+
+    /// Create a ticket for a room, or the current room if `name` is `None`.
+    ///
+    /// The ticket contains our own address and the addresses of our current
+    /// neighbors in the room, as far as the endpoint knows them.
+    pub async fn ticket(&self, name: Option<&str>) -> anyhow::Result<ChatTicket> {
+        let endpoint = self
+            .endpoint
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("invites are not supported"))?;
+        let name = match name.or(self.current_name()) {
+            Some(name) => name,
+            None => anyhow::bail!("not in any room"),
+        };
+        let room = self
+            .rooms
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("not in room {name}"))?;
+        let mut nodes = vec![endpoint.node_addr().await?];
+        for node_id in room.roster.neighbors() {
+            let addr = endpoint
+                .remote_info(node_id)
+                .map(NodeAddr::from)
+                .unwrap_or_else(|| NodeAddr::new(node_id));
+            nodes.push(addr);
+        }
+        Ok(ChatTicket {
+            topic: room.topic,
+            room: name.to_string(),
+            nodes,
+            key: room.keys.current(),
+        })
+    }
+
+    /// Print a ticket for a room, or the current room if `name` is `None`.
+    pub async fn invite(&self, name: Option<&str>) -> anyhow::Result<()> {
+        let ticket = self.ticket(name).await?;
+        println!(
+            "Invite others to {} using cargo run {}",
+            ticket.room, ticket
+        );
+        Ok(())
+    }
+
+    /// Leave a room. If it was the current one, another joined room becomes current.
+    pub fn leave(&mut self, name: &str) -> anyhow::Result<()> {
+        let Some(room) = self.rooms.remove(name) else {
+            anyhow::bail!("not in room {name}");
+        };
+        self.access.remove(&room.topic);
+        if self.current.as_deref() == Some(name) {
+            self.current = self.rooms.keys().next().cloned();
+        }
+        Ok(())
+    }
+
+    /// Make a joined room the current one.
+    pub fn switch(&mut self, name: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(self.rooms.contains_key(name), "not in room {name}");
+        self.current = Some(name.to_string());
+        Ok(())
+    }
+
+    /// The name of the current room, if any room is joined.
+    pub fn current_name(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    /// A joined room that `node_id` is a member of, preferring the current room.
+    pub fn room_with_member(&self, node_id: &NodeId) -> Option<&str> {
+        let is_member = |name: &&String| {
+            self.rooms
+                .get(name.as_str())
+                .is_some_and(|room| room.roster.members().any(|(id, _)| id == node_id))
+        };
+        let mut names = self.current.iter().chain(self.rooms.keys());
+        names.find(is_member).map(String::as_str)
+    }
+
+    /// The topic id of a joined room.
+    ///
+    /// Unlike [`Rooms::topic`], this is also right for rooms joined with a ticket.
+    pub fn joined_topic(&self, name: &str) -> Option<TopicId> {
+        self.rooms.get(name).map(|room| room.topic)
+    }
+
+    /// The topic id of the current room, if any room is joined.
+    pub fn current_topic(&self) -> Option<TopicId> {
+        self.joined_topic(self.current.as_ref()?)
+    }
+
+    /// The sender of the current room, if any room is joined.
+    pub fn current(&mut self) -> Option<&mut S> {
+        let name = self.current.as_ref()?;
+        self.rooms.get_mut(name).map(|room| &mut room.sender)
+    }
+
+    /// The keys of a joined room.
+    pub fn keys_mut(&mut self, name: &str) -> Option<&mut RoomKeys> {
+        self.rooms.get_mut(name).map(|room| &mut room.keys)
+    }
+
+    /// The members of a joined room.
+    pub fn roster_mut(&mut self, name: &str) -> Option<&mut Roster> {
+        self.rooms.get_mut(name).map(|room| &mut room.roster)
+    }
+
+    /// The version of the message format to send to a room with, see [`Roster::version`].
+    pub fn version(&self, name: &str) -> u8 {
+        self.rooms
+            .get(name)
+            .map_or(chat::VERSION, |room| room.roster.version())
+    }
+
+    /// The receipts of a joined room.
+    pub fn receipts_mut(&mut self, name: &str) -> Option<&mut Receipts> {
+        self.rooms.get_mut(name).map(|room| &mut room.receipts)
+    }
+
+    /// The sender of a joined room.
+    pub fn sender(&mut self, name: &str) -> Option<&mut S> {
+        self.rooms.get_mut(name).map(|room| &mut room.sender)
+    }
+
+    /// The topic ids and senders of all joined rooms.
+    pub fn senders(&mut self) -> impl Iterator<Item = (TopicId, &mut S)> {
+        self.rooms
+            .values_mut()
+            .map(|room| (room.topic, &mut room.sender))
+    }
+
+    /// List the joined rooms, with their topic id and a flag for the current one.
+    pub fn list(&self) -> Vec<(String, TopicId, bool)> {
+        self.rooms
+            .iter()
+            .map(|(name, room)| {
+                let current = self.current.as_ref() == Some(name);
+                (name.clone(), room.topic, current)
+            })
+            .collect()
+    }
+
+    /// Wait for the next event from any of the joined rooms.
+    pub async fn next(&mut self) -> (String, anyhow::Result<Event>) {
+        loop {
+            // we keep a sender ourselves, so the channel is never closed.
+            let (name, event) = self.events_rx.recv().await.expect("sender is kept");
+            // events of a room can still be queued after it was left.
+            let Some(room) = self.rooms.get_mut(&name) else {
+                continue;
+            };
+            match &event {
+                Ok(Event::Gossip(GossipEvent::Joined(neighbors))) => {
+                    for node_id in neighbors {
+                        room.roster.neighbor_up(*node_id);
+                    }
+                }
+                Ok(Event::Gossip(GossipEvent::NeighborUp(node_id))) => {
+                    room.roster.neighbor_up(*node_id);
+                }
+                Ok(Event::Gossip(GossipEvent::NeighborDown(node_id))) => {
+                    room.roster.neighbor_down(*node_id);
+                }
+                _ => {}
+            }
+            // also picks up the members we got messages from since the last event.
+            let members = room.roster.members().map(|(node_id, _)| *node_id);
+            self.access.set_members(room.topic, members);
+            return (name, event);
+        }
+    }
+
+    /// Handle a line typed by the user if it is a room command.
+    ///
+    /// Returns true if the line was handled, false if it should be sent to the
+    /// current room. Lines are also handled if there is no current room to send to.
+    pub async fn handle_line<F, Fut, E>(&mut self, line: &str, join: F) -> bool
+    where
+        F: FnOnce(TopicId) -> Fut,
+        Fut: Future<Output = anyhow::Result<(S, E)>>,
+        E: Stream<Item = anyhow::Result<Event>> + Send + 'static,
+    {
+        let res = match RoomCommand::parse(line) {
+            Some(Ok(command)) => self.execute(command, join).await,
+            Some(Err(cause)) => Err(cause),
+            None if self.current.is_none() => Err(anyhow::anyhow!(
+                "not in any room, use /join <room> to join one"
+            )),
+            None => return false,
+        };
+        if let Err(cause) = res {
+            eprintln!("{cause}");
+        }
+        true
+    }
+
+    /// Execute a room command typed by the user, printing the result to stderr.
+    ///
+    /// `join` subscribes to a topic, see [`Rooms::join`].
+    pub async fn execute<F, Fut, E>(&mut self, command: RoomCommand, join: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(TopicId) -> Fut,
+        Fut: Future<Output = anyhow::Result<(S, E)>>,
+        E: Stream<Item = anyhow::Result<Event>> + Send + 'static,
+    {
+        match command {
+            RoomCommand::Join(name) => {
+                self.join(name.clone(), join).await?;
+                eprintln!("joined room {name}");
+            }
+            RoomCommand::Leave(name) => {
+                let name = match name.or_else(|| self.current.clone()) {
+                    Some(name) => name,
+                    None => anyhow::bail!("not in any room"),
+                };
+                self.leave(&name)?;
+                match self.current_name() {
+                    Some(current) => eprintln!("left room {name}, now in room {current}"),
+                    None => eprintln!("left room {name}, use /join <room> to join another one"),
+                }
+            }
+            RoomCommand::Switch(name) => {
+                self.switch(&name)?;
+                eprintln!("now in room {name}");
+            }
+            RoomCommand::List => {
+                for (name, topic, current) in self.list() {
+                    let marker = if current { "*" } else { " " };
+                    eprintln!("{marker} {name} ({topic})");
+                }
+            }
+            RoomCommand::Invite(name) => self.invite(name.as_deref()).await?,
+            RoomCommand::Encrypt(name) => {
+                let name = match name.or_else(|| self.current.clone()) {
+                    Some(name) => name,
+                    None => anyhow::bail!("not in any room"),
+                };
+                self.keys_mut(&name)
+                    .ok_or_else(|| anyhow::anyhow!("not in room {name}"))?
+                    .encrypt()?;
+                eprintln!("room {name} is now encrypted, use /invite to share the key");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_net::key::SecretKey;
+
+    use super::*;
+
+    async fn join(rooms: &mut Rooms<()>, name: &str) {
+        let join = |_| async { Ok(((), futures::stream::empty::<anyhow::Result<Event>>())) };
+        rooms.join(name.to_string(), join).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn room_with_member_prefers_the_current_room() {
+        let alice = SecretKey::generate().public();
+        let bob = SecretKey::generate().public();
+        let mut rooms = Rooms::new(None);
+        assert_eq!(rooms.room_with_member(&alice), None);
+        join(&mut rooms, "a").await;
+        join(&mut rooms, "b").await;
+        join(&mut rooms, "c").await;
+        // not in any room with them, so there is nothing to fall back to
+        assert_eq!(rooms.room_with_member(&alice), None);
+        rooms.roster_mut("b").unwrap().neighbor_up(alice);
+        rooms.roster_mut("c").unwrap().neighbor_up(alice);
+        rooms.roster_mut("a").unwrap().neighbor_up(bob);
+        // c is current
+        assert_eq!(rooms.room_with_member(&alice), Some("c"));
+        assert_eq!(rooms.room_with_member(&bob), Some("a"));
+        // otherwise the first room they are in
+        rooms.switch("a").unwrap();
+        assert_eq!(rooms.room_with_member(&alice), Some("b"));
+        rooms.leave("b").unwrap();
+        assert_eq!(rooms.room_with_member(&alice), Some("c"));
+        rooms.leave("c").unwrap();
+        assert_eq!(rooms.room_with_member(&alice), None);
+    }
+}
@@ -0,0 +1,155 @@
+//! Where the node identity comes from.
+//!
+//! By default the secret key is stored in a file in the user's config
+//! directory, so the node id stays stable across restarts without the secret
+//! ever being printed or passed on the command line.
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use iroh_net::key::SecretKey;
+
+/// Name of the directory below the user's config dir where keys are stored.
+pub(crate) const CONFIG_DIR_NAME: &str = "iroh-workshop";
+
+/// Source of the secret key, and therefore the node id, of an endpoint.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Default)]
+pub enum SecretSource {
+    /// Generate a new key every time. The node id changes on each start.
+    #[default]
+    Ephemeral,
+    /// Load the key from a file, creating the file if it does not exist yet.
+    File(PathBuf),
+    /// Use the given key.
+    Key(SecretKey),
+}
+
+impl SecretSource {
+    /// Use the default key file for the given application name.
+    pub fn default_file(name: &str) -> anyhow::Result<Self> {
+        Ok(Self::File(default_key_file(name)?))
+    }
+
+    /// Get the secret key from this source.
+    pub fn load(&self) -> anyhow::Result<SecretKey> {
+        match self {
+            Self::Ephemeral => Ok(SecretKey::generate()),
+            Self::File(path) => load_or_create_key_file(path),
+            Self::Key(secret) => Ok(secret.clone()),
+        }
+    }
+}
+
+/// Command line options to select the secret key.
+///
+/// Flatten this into the arguments of a binary with `#[clap(flatten)]`.
+#[derive(Debug, Clone, clap::Args)]
+#[command(about = None, long_about = None)]
+pub struct KeyArgs {
+    /// File to load the secret key from. It is created if it does not exist.
+    ///
+    /// Defaults to a file in the user's config directory.
+    #[clap(long, global = true, conflicts_with = "ephemeral")]
+    pub key_file: Option<PathBuf>,
+    /// Use a fresh secret key instead of the stored one. The node id will change on every start.
+    #[clap(long, global = true)]
+    pub ephemeral: bool,
+}
+
+impl KeyArgs {
+    /// Secret source for a node that should keep its node id across restarts.
+    ///
+    /// Uses the default key file for `name` unless overridden on the command line.
+    pub fn persistent(&self, name: &str) -> anyhow::Result<SecretSource> {
+        if self.ephemeral {
+            return Ok(SecretSource::Ephemeral);
+        }
+        match &self.key_file {
+            Some(path) => Ok(SecretSource::File(path.clone())),
+            None => SecretSource::default_file(name),
+        }
+    }
+
+    /// Secret source for a node that only needs a stable node id if asked for one.
+    ///
+    /// Uses a fresh key unless a key file is given on the command line.
+    pub fn ephemeral_by_default(&self) -> SecretSource {
+        match &self.key_file {
+            Some(path) if !self.ephemeral => SecretSource::File(path.clone()),
+            _ => SecretSource::Ephemeral,
+        }
+    }
+}
+
+/// The default key file for the given application name.
+///
+/// This is `$XDG_CONFIG_HOME/iroh-workshop/<name>.key` on Linux, and the
+/// equivalent config directory on other platforms.
+pub fn default_key_file(name: &str) -> anyhow::Result<PathBuf> {
+    let config_dir = dirs::config_dir().context("unable to determine the config directory")?;
+    Ok(config_dir.join(CONFIG_DIR_NAME).join(format!("{name}.key")))
+}
+
+/// Load the secret key from a file, or generate a new one and store it there.
+///
+/// New key files are only readable by the current user.
+pub fn load_or_create_key_file(path: &Path) -> anyhow::Result<SecretKey> {
+    if path.exists() {
+        warn_if_accessible_by_others(path);
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read key file {}", path.display()))?;
+        let secret = SecretKey::from_str(text.trim())
+            .with_context(|| format!("invalid key file {}", path.display()))?;
+        tracing::info!("using secret key from {}", path.display());
+        return Ok(secret);
+    }
+    if let Some(parent) = path.parent() {
+        create_private_dir(parent)?;
+    }
+    let secret = SecretKey::generate();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to create key file {}", path.display()))?;
+    writeln!(file, "{secret}")?;
+    file.sync_all()?;
+    eprintln!("Created a new secret key in {}\n", path.display());
+    Ok(secret)
+}
+
+/// Create a directory and its parents, only accessible by the current user.
+pub(crate) fn create_private_dir(path: &Path) -> anyhow::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder
+        .create(path)
+        .with_context(|| format!("failed to create directory {}", path.display()))
+}
+
+#[cfg(unix)]
+fn warn_if_accessible_by_others(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        let mode = metadata.permissions().mode();
+        if mode & 0o077 != 0 {
+            tracing::warn!(
+                "key file {} is accessible by other users (mode {:o}), consider chmod 600",
+                path.display(),
+                mode & 0o777
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_accessible_by_others(_path: &Path) {}
@@ -0,0 +1,193 @@
+//! Serving several connected peers from one listener.
+//!
+//! There is only one stdin, so it must not be read by a task per connection.
+//! Instead a single task reads stdin and hands each line to the open sessions
+//! according to a [`FanOut`] policy. Lines received from the peers are printed
+//! with the session number, so it is clear which peer sent what.
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use iroh_net::{
+    endpoint::{RecvStream, SendStream},
+    key::PublicKey,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::mpsc,
+};
+
+use crate::io::copy_to_stdout;
+
+/// Number of lines buffered for a peer before lines get dropped.
+const SESSION_BUFFER: usize = 64;
+
+/// How lines from stdin are distributed when several peers are connected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FanOut {
+    /// Send every line to all connected peers.
+    #[default]
+    Broadcast,
+    /// Send lines only to the selected peer. Use `/select <session>` to switch.
+    Route,
+}
+
+/// Registry of the open sessions of a listener.
+#[derive(Debug, Clone)]
+pub struct Sessions {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    fan_out: FanOut,
+    next_id: u64,
+    sessions: BTreeMap<u64, Session>,
+    selected: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Session {
+    node_id: PublicKey,
+    lines: mpsc::Sender<String>,
+}
+
+impl Sessions {
+    /// Create an empty registry with the given fan-out policy.
+    pub fn new(fan_out: FanOut) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                fan_out,
+                next_id: 1,
+                sessions: BTreeMap::new(),
+                selected: None,
+            })),
+        }
+    }
+
+    /// Register a new session with the given peer.
+    ///
+    /// Returns the session number and the receiver for the lines to send to the peer.
+    pub fn add(&self, node_id: PublicKey) -> (u64, mpsc::Receiver<String>) {
+        let (tx, rx) = mpsc::channel(SESSION_BUFFER);
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.sessions.insert(id, Session { node_id, lines: tx });
+        if inner.fan_out == FanOut::Route && inner.selected.is_none() {
+            inner.selected = Some(id);
+        }
+        (id, rx)
+    }
+
+    /// Remove a session, e.g. because the peer disconnected.
+    pub fn remove(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sessions.remove(&id);
+        if inner.selected == Some(id) {
+            inner.selected = None;
+            if inner.fan_out == FanOut::Route && !inner.sessions.is_empty() {
+                eprintln!(
+                    "selected session {id} closed, use /select <session> to pick another one"
+                );
+            }
+        }
+    }
+
+    /// List the open sessions, with a flag for the selected one.
+    pub fn list(&self) -> Vec<(u64, PublicKey, bool)> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .sessions
+            .iter()
+            .map(|(id, session)| (*id, session.node_id, inner.selected == Some(*id)))
+            .collect()
+    }
+
+    /// Select the session that lines are routed to.
+    pub fn select(&self, id: u64) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        anyhow::ensure!(
+            inner.fan_out == FanOut::Route,
+            "only possible with --fan-out route"
+        );
+        anyhow::ensure!(inner.sessions.contains_key(&id), "no session {id}");
+        inner.selected = Some(id);
+        Ok(())
+    }
+
+    /// Hand a line to the sessions, according to the fan-out policy.
+    pub fn dispatch(&self, line: &str) {
+        let inner = self.inner.lock().unwrap();
+        let targets: Vec<u64> = match inner.fan_out {
+            FanOut::Broadcast => inner.sessions.keys().copied().collect(),
+            FanOut::Route => inner.selected.into_iter().collect(),
+        };
+        if targets.is_empty() {
+            eprintln!("no session to send to, line dropped");
+        }
+        for id in targets {
+            let session = &inner.sessions[&id];
+            if session.lines.try_send(line.to_string()).is_err() {
+                tracing::warn!("session {} is not keeping up, line dropped", id);
+            }
+        }
+    }
+}
+
+/// Read lines from stdin and hand them to the sessions.
+///
+/// Lines starting with `/sessions` and `/select` are commands to list the
+/// sessions and to select where lines are routed.
+pub async fn dispatch_stdin(sessions: Sessions) -> anyhow::Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line == "/sessions" {
+            for (id, node_id, selected) in sessions.list() {
+                let marker = if selected { "*" } else { " " };
+                eprintln!("{marker} {id}: {node_id}");
+            }
+        } else if let Some(id) = line.strip_prefix("/select ") {
+            let res = id
+                .trim()
+                .parse::<u64>()
+                .map_err(anyhow::Error::from)
+                .and_then(|id| sessions.select(id));
+            if let Err(cause) = res {
+                eprintln!("unable to select session: {cause}");
+            }
+        } else {
+            sessions.dispatch(&line);
+        }
+    }
+    Ok(())
+}
+
+/// Serve a session with a peer until the peer stops sending.
+///
+/// Lines dispatched to the session are written to `send`, lines from `recv`
+/// are printed to stdout, prefixed with the session number and node id.
+pub async fn run_session(
+    sessions: Sessions,
+    node_id: PublicKey,
+    mut send: SendStream,
+    recv: RecvStream,
+) -> anyhow::Result<()> {
+    let (id, mut lines) = sessions.add(node_id);
+    eprintln!("session {id}: {node_id} connected");
+    let to_remote = async {
+        while let Some(line) = lines.recv().await {
+            send.write_all(format!("{}\n", line).as_bytes()).await?;
+        }
+        anyhow::Ok(())
+    };
+    let from_remote = copy_to_stdout(format!("[{id}] {node_id}"), recv);
+    let res = tokio::select! {
+        res = to_remote => res,
+        res = from_remote => res,
+    };
+    sessions.remove(id);
+    eprintln!("session {id}: {node_id} closed");
+    res
+}
@@ -0,0 +1,384 @@
+//! Catching up on the history of a room.
+//!
+//! Gossip only delivers messages that are broadcast while a node is
+//! subscribed. When a node joins a room, it connects to its new neighbors with
+//! [`SYNC_ALPN`] and asks for the messages sent since the newest one in its
+//! own [`History`]. The requester writes a postcard encoded [`SyncRequest`]
+//! and finishes the stream, the other side replies with the signed messages,
+//! each length prefixed, oldest first. The same request can also ask for
+//! single messages by uid, e.g. the parent of a reply.
+//!
+//! Received messages are verified again and deduplicated by their uid, so a
+//! neighbor can neither forge messages nor show the same message twice.
+//!
+//! Only members of a room may fetch its history. [`SyncAccess`] knows the
+//! members of each joined room, and requests from anyone else are rejected.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use iroh_gossip::proto::TopicId;
+use iroh_net::{
+    endpoint::{get_remote_node_id, Connection, SendStream},
+    Endpoint, NodeId,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Notify};
+
+use crate::{
+    acl::REJECTED_CODE,
+    chat::{self, SignedMessage, VerifiedMessage},
+    history::History,
+};
+
+/// ALPN of the history sync protocol.
+pub const SYNC_ALPN: &[u8] = b"iroh-workshop/chat-sync/0";
+/// Most messages sent in reply to a single request.
+pub const MAX_MESSAGES: usize = 100;
+/// Maximum size of a request or of a single message.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+/// Maximum size of a reply.
+const MAX_REPLY_SIZE: usize = MAX_MESSAGES * (MAX_FRAME_SIZE + 4);
+/// Time a sync may take, including connecting.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for a new neighbor to show up in the members of a room.
+const MEMBER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Sent by a node to ask for the history of a room.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncRequest {
+    /// The topic of the room.
+    pub topic: TopicId,
+    /// Only send messages sent after this time, in microseconds since the unix epoch.
+    pub since: u64,
+    /// Send at most this many messages, the newest ones.
+    pub limit: u32,
+    /// If not empty, send the messages with these uids instead.
+    pub uids: Vec<u128>,
+}
+
+/// The members of each joined room, who may fetch its history.
+///
+/// Cloning gives another handle to the same members, so the rooms can update
+/// them while [`serve_sync`] checks them.
+#[derive(Debug, Clone, Default)]
+pub struct SyncAccess(Arc<AccessInner>);
+
+#[derive(Debug, Default)]
+struct AccessInner {
+    rooms: Mutex<BTreeMap<TopicId, BTreeSet<NodeId>>>,
+    /// Notified whenever the members change.
+    changed: Notify,
+}
+
+impl SyncAccess {
+    /// Replace the members of a room.
+    pub fn set_members(&self, topic: TopicId, members: impl IntoIterator<Item = NodeId>) {
+        let mut rooms = self.0.rooms.lock().expect("poisoned");
+        rooms.insert(topic, members.into_iter().collect());
+        self.0.changed.notify_waiters();
+    }
+
+    /// Forget a room that was left, nobody may fetch its history any more.
+    pub fn remove(&self, topic: &TopicId) {
+        self.0.rooms.lock().expect("poisoned").remove(topic);
+    }
+
+    /// Check if `node_id` is a member of the room.
+    pub fn is_allowed(&self, topic: &TopicId, node_id: &NodeId) -> bool {
+        let rooms = self.0.rooms.lock().expect("poisoned");
+        rooms
+            .get(topic)
+            .is_some_and(|members| members.contains(node_id))
+    }
+
+    /// Check if `node_id` is a member of any joined room.
+    pub fn shares_room(&self, node_id: &NodeId) -> bool {
+        let rooms = self.0.rooms.lock().expect("poisoned");
+        rooms.values().any(|members| members.contains(node_id))
+    }
+
+    /// Wait a moment for `node_id` to become a member of the room.
+    ///
+    /// A node that just became our neighbor may ask for the history before we
+    /// handled the event that adds it to the members.
+    async fn wait_allowed(&self, topic: &TopicId, node_id: &NodeId) -> bool {
+        self.wait_until(|| self.is_allowed(topic, node_id)).await
+    }
+
+    /// Wait a moment for `node_id` to become a member of any joined room.
+    pub(crate) async fn wait_shares_room(&self, node_id: &NodeId) -> bool {
+        self.wait_until(|| self.shares_room(node_id)).await
+    }
+
+    async fn wait_until(&self, check: impl Fn() -> bool) -> bool {
+        let wait = async {
+            loop {
+                let changed = self.0.changed.notified();
+                if check() {
+                    return;
+                }
+                changed.await;
+            }
+        };
+        tokio::time::timeout(MEMBER_TIMEOUT, wait).await.is_ok()
+    }
+}
+
+/// Messages of a room that were missed, fetched from a neighbor.
+#[derive(Debug)]
+pub struct Synced {
+    /// The name of the room.
+    pub room: String,
+    /// The neighbor the messages were fetched from.
+    pub from: NodeId,
+    /// The new messages, oldest first.
+    pub messages: Vec<VerifiedMessage>,
+}
+
+/// Catch up on the history of a room from the given neighbors, in the background.
+///
+/// The neighbors are asked one after the other, and new messages are sent to `tx`.
+pub fn spawn_sync(
+    endpoint: Endpoint,
+    history: Arc<History>,
+    room: String,
+    topic: TopicId,
+    neighbors: Vec<NodeId>,
+    tx: mpsc::Sender<Synced>,
+) {
+    tokio::spawn(async move {
+        for node_id in neighbors {
+            match sync_from(&endpoint, node_id, topic, &history).await {
+                Ok(messages) if messages.is_empty() => {}
+                Ok(messages) => {
+                    let synced = Synced {
+                        room: room.clone(),
+                        from: node_id,
+                        messages,
+                    };
+                    if tx.send(synced).await.is_err() {
+                        break;
+                    }
+                }
+                Err(cause) => tracing::warn!("error syncing {} from {}: {}", room, node_id, cause),
+            }
+        }
+    });
+}
+
+/// Fetch single messages of a room by uid, in the background.
+///
+/// The nodes are asked one after the other, until one of them has the
+/// messages, which are then sent to `tx`.
+pub fn spawn_fetch(
+    endpoint: Endpoint,
+    history: Arc<History>,
+    room: String,
+    topic: TopicId,
+    nodes: Vec<NodeId>,
+    uids: Vec<u128>,
+    tx: mpsc::Sender<Synced>,
+) {
+    tokio::spawn(async move {
+        let request = SyncRequest {
+            topic,
+            since: 0,
+            limit: MAX_MESSAGES as u32,
+            uids,
+        };
+        for node_id in nodes {
+            match request_from(&endpoint, node_id, &request, &history).await {
+                Ok(messages) if messages.is_empty() => {}
+                Ok(messages) => {
+                    let synced = Synced {
+                        room,
+                        from: node_id,
+                        messages,
+                    };
+                    tx.send(synced).await.ok();
+                    break;
+                }
+                Err(cause) => tracing::warn!("error fetching from {}: {}", node_id, cause),
+            }
+        }
+    });
+}
+
+/// Ask `node_id` for the messages of `topic` that are newer than our history.
+///
+/// New messages are stored in `history`, and the ones by other authors returned, oldest first.
+pub async fn sync_from(
+    endpoint: &Endpoint,
+    node_id: NodeId,
+    topic: TopicId,
+    history: &History,
+) -> anyhow::Result<Vec<VerifiedMessage>> {
+    let request = SyncRequest {
+        topic,
+        since: history.latest(topic)?.unwrap_or_default(),
+        limit: MAX_MESSAGES as u32,
+        uids: Vec::new(),
+    };
+    request_from(endpoint, node_id, &request, history).await
+}
+
+/// Send a request to `node_id`, and store the messages it replies with.
+async fn request_from(
+    endpoint: &Endpoint,
+    node_id: NodeId,
+    request: &SyncRequest,
+    history: &History,
+) -> anyhow::Result<Vec<VerifiedMessage>> {
+    let topic = request.topic;
+    let messages = tokio::time::timeout(SYNC_TIMEOUT, request_messages(endpoint, node_id, request))
+        .await
+        .map_err(|_| anyhow::anyhow!("timeout syncing from {node_id}"))??;
+    let mut new = Vec::new();
+    for bytes in messages {
+        let message = match SignedMessage::verify(&bytes, topic) {
+            Ok(message) => message,
+            Err(cause) => {
+                tracing::warn!("invalid message from {}: {}", node_id, cause);
+                continue;
+            }
+        };
+        // a message from the future would stop us from asking for anything older
+        if let Err(cause) = chat::check_not_future(&message.from, message.timestamp) {
+            tracing::warn!("invalid message from {}: {}", node_id, cause);
+            continue;
+        }
+        // our own messages were shown when we sent them
+        if history.insert(topic, &bytes)? && message.from != endpoint.node_id() {
+            new.push(message);
+        }
+    }
+    Ok(new)
+}
+
+async fn request_messages(
+    endpoint: &Endpoint,
+    node_id: NodeId,
+    request: &SyncRequest,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    let connection = endpoint.connect_by_node_id(node_id, SYNC_ALPN).await?;
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.write_all(&postcard::to_stdvec(request)?).await?;
+    send.finish()?;
+    let reply = recv.read_to_end(MAX_REPLY_SIZE).await?;
+    connection.close(0u32.into(), b"done");
+    let mut messages = Vec::new();
+    let mut rest = reply.as_slice();
+    while !rest.is_empty() {
+        anyhow::ensure!(messages.len() < MAX_MESSAGES, "too many messages");
+        anyhow::ensure!(rest.len() >= 4, "truncated reply");
+        let (len, tail) = rest.split_at(4);
+        let len = u32::from_be_bytes(len.try_into().expect("4 bytes")) as usize;
+        anyhow::ensure!(len <= tail.len(), "truncated reply");
+        let (bytes, tail) = tail.split_at(len);
+        messages.push(bytes.to_vec());
+        rest = tail;
+    }
+    Ok(messages)
+}
+
+/// Answer the sync requests of a node that connected with [`SYNC_ALPN`].
+///
+/// The node must be a member of the room according to `access`.
+pub async fn serve_sync(
+    connection: Connection,
+    history: &History,
+    access: &SyncAccess,
+) -> anyhow::Result<()> {
+    let remote = get_remote_node_id(&connection)?;
+    let (mut send, mut recv) = connection.accept_bi().await?;
+    let request = recv.read_to_end(MAX_FRAME_SIZE).await?;
+    let request: SyncRequest = postcard::from_bytes(&request)?;
+    if !access.wait_allowed(&request.topic, &remote).await {
+        connection.close(REJECTED_CODE.into(), b"not a member");
+        anyhow::bail!("{remote} is not a member of the room it asked for");
+    }
+    let limit = (request.limit as usize).min(MAX_MESSAGES);
+    let messages = if request.uids.is_empty() {
+        history.since(request.topic, request.since, limit)?
+    } else {
+        let mut messages = Vec::new();
+        for uid in request.uids.iter().take(limit) {
+            messages.extend(history.get(request.topic, *uid)?);
+        }
+        messages
+    };
+    for bytes in messages {
+        write_frame(&mut send, &bytes).await?;
+    }
+    send.finish()?;
+    // wait for the requester to close the connection once it has read everything.
+    tokio::time::timeout(SYNC_TIMEOUT, connection.closed())
+        .await
+        .ok();
+    Ok(())
+}
+
+/// Write a length prefixed message.
+async fn write_frame(send: &mut SendStream, bytes: &[u8]) -> anyhow::Result<()> {
+    send.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+    send.write_all(bytes).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_net::key::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn only_members_may_sync() {
+        let topic = TopicId::from_bytes([1; 32]);
+        let other_topic = TopicId::from_bytes([2; 32]);
+        let member = SecretKey::generate().public();
+        let stranger = SecretKey::generate().public();
+        let access = SyncAccess::default();
+        assert!(!access.is_allowed(&topic, &member));
+        access.clone().set_members(topic, [member]);
+        assert!(access.is_allowed(&topic, &member));
+        assert!(!access.is_allowed(&topic, &stranger));
+        assert!(!access.is_allowed(&other_topic, &member));
+        access.set_members(topic, []);
+        assert!(!access.is_allowed(&topic, &member));
+        access.set_members(topic, [member]);
+        access.remove(&topic);
+        assert!(!access.is_allowed(&topic, &member));
+    }
+
+    #[test]
+    fn shares_room_with_members_of_any_room() {
+        let member = SecretKey::generate().public();
+        let stranger = SecretKey::generate().public();
+        let access = SyncAccess::default();
+        assert!(!access.shares_room(&member));
+        access.set_members(TopicId::from_bytes([1; 32]), []);
+        access.set_members(TopicId::from_bytes([2; 32]), [member]);
+        assert!(access.shares_room(&member));
+        assert!(!access.shares_room(&stranger));
+        access.remove(&TopicId::from_bytes([2; 32]));
+        assert!(!access.shares_room(&member));
+    }
+
+    #[tokio::test]
+    async fn waits_for_new_member() {
+        let topic = TopicId::from_bytes([1; 32]);
+        let member = SecretKey::generate().public();
+        let access = SyncAccess::default();
+        let setter = access.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            setter.set_members(topic, [member]);
+        });
+        assert!(access.wait_allowed(&topic, &member).await);
+        let stranger = SecretKey::generate().public();
+        assert!(!access.wait_allowed(&topic, &stranger).await);
+    }
+}
@@ -0,0 +1,175 @@
+//! Threaded replies.
+//!
+//! A [`Message::Reply`] is a chat message that names the uid of its parent.
+//! Live replies are shown below a quote of their parent, and `/history`
+//! groups replies under their parent, so parallel discussions in one room
+//! don't interleave. If the parent is not in the local history, it is fetched
+//! from the author of the reply or the neighbors with
+//! [`spawn_fetch`](crate::sync::spawn_fetch).
+use std::collections::{HashMap, HashSet};
+
+use iroh_gossip::proto::TopicId;
+use iroh_net::key::SecretKey;
+
+use crate::{
+    chat::Message,
+    edits::{self, short_ref},
+    history::History,
+    names::Names,
+    receipts::preview,
+    room_key::RoomKeys,
+};
+
+/// Parse a `/reply [#ref] <text>` command typed by the user.
+///
+/// Returns `None` if the line is not a reply command.
+pub fn parse_reply_command(line: &str) -> Option<anyhow::Result<(Option<String>, String)>> {
+    let rest = match line.strip_prefix("/reply") {
+        Some(rest) if rest.is_empty() || rest.starts_with(' ') => rest.trim(),
+        _ => return None,
+    };
+    // the reference is optional, so only take the first word if it looks like one
+    let (target, text) = match rest.split_once(' ') {
+        Some((first, text)) if edits::parse_ref(first).is_some() => {
+            (Some(first.to_string()), text.trim())
+        }
+        _ => (None, rest),
+    };
+    if text.is_empty() || edits::parse_ref(text).is_some() {
+        return Some(Err(anyhow::anyhow!("usage: /reply [#ref] <text>")));
+    }
+    Some(Ok((target, text.to_string())))
+}
+
+/// Turn a reply command into the message to send to the room.
+///
+/// Without a reference, the reply goes to the last message of somebody else.
+pub fn prepare(
+    target: Option<&str>,
+    text: String,
+    history: &History,
+    topic: TopicId,
+    keys: &mut RoomKeys,
+    secret_key: &SecretKey,
+) -> anyhow::Result<Message> {
+    let parent_uid = edits::find(history, topic, keys, secret_key, target, false)?;
+    Ok(Message::Reply { parent_uid, text })
+}
+
+/// The parent of a message, if it is a reply.
+pub fn parent(message: &Message) -> Option<u128> {
+    match message {
+        Message::Reply { parent_uid, .. } => Some(*parent_uid),
+        _ => None,
+    }
+}
+
+/// A quote of the parent of a reply, e.g. `┌ #3f2a1c alice (abcd)> hello…`.
+///
+/// Returns `None` if the parent is not in the history.
+pub fn quote(
+    history: &History,
+    topic: TopicId,
+    keys: &mut RoomKeys,
+    secret_key: &SecretKey,
+    parent_uid: u128,
+    names: &Names,
+) -> anyhow::Result<Option<String>> {
+    let quote = edits::load(history, topic, keys, secret_key, parent_uid)?.map(|parent| {
+        let name = names.display(&parent.from);
+        format!(
+            "┌ {} {}> {}",
+            short_ref(parent_uid),
+            name,
+            preview(&parent.text)
+        )
+    });
+    Ok(quote)
+}
+
+/// The line shown instead of a quote if the parent is not in the history.
+pub fn missing(parent_uid: u128) -> String {
+    format!("┌ {} (not in the history)", short_ref(parent_uid))
+}
+
+/// The order to show messages from the history in, with replies below their parent.
+///
+/// Takes the uid and the parent of each message, oldest first. Returns the
+/// indices of the messages, and whether each one is shown below its parent.
+pub fn thread_order(messages: &[(u128, Option<u128>)]) -> Vec<(usize, bool)> {
+    let uids: HashSet<u128> = messages.iter().map(|(uid, _)| *uid).collect();
+    let shown_parent = |(uid, parent): &(u128, Option<u128>)| {
+        parent.filter(|parent| uids.contains(parent) && parent != uid)
+    };
+    let mut children: HashMap<u128, Vec<usize>> = HashMap::new();
+    for (index, message) in messages.iter().enumerate() {
+        if let Some(parent) = shown_parent(message) {
+            children.entry(parent).or_default().push(index);
+        }
+    }
+    let mut order = Vec::with_capacity(messages.len());
+    let mut shown = vec![false; messages.len()];
+    for (root, message) in messages.iter().enumerate() {
+        if shown_parent(message).is_some() {
+            continue;
+        }
+        // depth first, so a thread is shown in one piece
+        let mut stack = vec![(root, false)];
+        while let Some((index, grouped)) = stack.pop() {
+            if std::mem::replace(&mut shown[index], true) {
+                continue;
+            }
+            order.push((index, grouped));
+            if let Some(children) = children.get(&messages[index].0) {
+                stack.extend(children.iter().rev().map(|child| (*child, true)));
+            }
+        }
+    }
+    // replies in a cycle have no root, show them where they are
+    for (index, shown) in shown.into_iter().enumerate() {
+        if !shown {
+            order.push((index, false));
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_follow_their_parents() {
+        // 1 <- 3 <- 4 (nested), 2 stands alone, 5 replies to 1 again.
+        let messages = [
+            (1, None),
+            (2, None),
+            (3, Some(1)),
+            (4, Some(3)),
+            (5, Some(1)),
+        ];
+        assert_eq!(
+            thread_order(&messages),
+            [(0, false), (2, true), (3, true), (4, true), (1, false)]
+        );
+    }
+
+    #[test]
+    fn replies_without_shown_parent_stay_in_place() {
+        // the parent of 2 is not in the list, 3 replies to itself.
+        let messages = [(1, None), (2, Some(9)), (3, Some(3)), (4, Some(2))];
+        assert_eq!(
+            thread_order(&messages),
+            [(0, false), (1, false), (3, true), (2, false)]
+        );
+    }
+
+    #[test]
+    fn cycles_are_shown_once() {
+        let messages = [(1, Some(2)), (2, Some(1)), (3, None)];
+        assert_eq!(
+            thread_order(&messages),
+            [(2, false), (0, false), (1, false)]
+        );
+    }
+}
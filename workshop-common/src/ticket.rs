@@ -0,0 +1,115 @@
+//! Tickets to join a chat room.
+//!
+//! A [`ChatTicket`] contains everything needed to join a room: the topic id,
+//! the name of the room, the addresses of some members to bootstrap from, and
+//! the room key if the room is encrypted. It is a single string, like the
+//! node tickets of iroh, so one string is enough to invite someone.
+use std::str::FromStr;
+
+use iroh_base::ticket::{self, Ticket};
+use iroh_gossip::proto::TopicId;
+use iroh_net::NodeAddr;
+use serde::{Deserialize, Serialize};
+
+/// A ticket to join a chat room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatTicket {
+    /// The gossip topic of the room.
+    pub topic: TopicId,
+    /// The name of the room, as shown to the user.
+    pub room: String,
+    /// Members of the room to bootstrap from.
+    pub nodes: Vec<NodeAddr>,
+    /// Key of the room, if the room is encrypted.
+    pub key: Option<[u8; 32]>,
+}
+
+/// Wire format for [`ChatTicket`].
+///
+/// New versions of the ticket get a new variant, so old tickets keep working.
+#[derive(Serialize, Deserialize)]
+enum TicketWireFormat {
+    Variant0(ChatTicket),
+}
+
+impl Ticket for ChatTicket {
+    const KIND: &'static str = "chat";
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let data = TicketWireFormat::Variant0(self.clone());
+        postcard::to_stdvec(&data).expect("postcard serialization failed")
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ticket::Error> {
+        let TicketWireFormat::Variant0(ticket) = postcard::from_bytes(bytes)?;
+        Ok(ticket)
+    }
+}
+
+impl FromStr for ChatTicket {
+    type Err = ticket::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ticket::deserialize(s)
+    }
+}
+
+impl std::fmt::Display for ChatTicket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", Ticket::serialize(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::base32;
+    use iroh_net::key::SecretKey;
+
+    use super::*;
+
+    fn ticket(key: Option<[u8; 32]>) -> ChatTicket {
+        let addr = NodeAddr::new(SecretKey::generate().public())
+            .with_relay_url("https://relay.example.com".parse().unwrap())
+            .with_direct_addresses(["192.168.1.2:1234".parse().unwrap()]);
+        ChatTicket {
+            topic: TopicId::from_bytes([7; 32]),
+            room: "lobby".into(),
+            nodes: vec![addr, NodeAddr::new(SecretKey::generate().public())],
+            key,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for ticket in [ticket(None), ticket(Some([3; 32]))] {
+            let text = ticket.to_string();
+            assert!(text.starts_with("chat"), "{text}");
+            assert_eq!(ChatTicket::from_str(&text).unwrap(), ticket);
+        }
+    }
+
+    #[test]
+    fn rejects_corrupted_tickets() {
+        let ticket = ticket(Some([3; 32]));
+        let text = ticket.to_string();
+        let mut bytes = ticket.to_bytes();
+        bytes[0] = 1;
+        let corrupted = [
+            // wrong kind
+            text.replacen("chat", "node", 1),
+            // not base32
+            text.replacen("chat", "chat!", 1),
+            // truncated
+            text[..text.len() / 2].to_string(),
+            // unknown version of the wire format
+            format!("chat{}", base32::fmt(bytes)),
+            String::new(),
+        ];
+        for text in corrupted {
+            assert!(
+                ChatTicket::from_str(&text).is_err(),
+                "{text:?} was accepted"
+            );
+        }
+    }
+}